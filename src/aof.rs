@@ -0,0 +1,311 @@
+// append only file: every accepted write is logged as RESP and replayed at startup
+
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+
+use crate::{
+    cmd::Cmd,
+    error::DBError,
+    options::{AofOption, AppendFsync},
+    protocol::Protocol,
+    server::Server,
+    storage::{Value, ValueType},
+};
+
+const ITEMS_PER_CMD: usize = 64;
+
+pub struct Aof {
+    file: File,
+    path: PathBuf,
+    fsync: AppendFsync,
+    last_fsync: Instant,
+    // writes that arrive while a rewrite is running, appended to the new file when it is done
+    rewrite_buf: Option<Vec<u8>>,
+    // database of the last logged write, None forces a SELECT before the next one
+    selected_db: Option<usize>,
+}
+
+impl Aof {
+    pub fn open(dir: &str, option: &AofOption) -> Result<Self, DBError> {
+        let path = PathBuf::from(dir).join(&option.file_name);
+        Ok(Aof {
+            file: OpenOptions::new().create(true).append(true).open(&path)?,
+            path,
+            fsync: option.fsync,
+            last_fsync: Instant::now(),
+            rewrite_buf: None,
+            selected_db: None,
+        })
+    }
+
+    pub fn append(&mut self, db: usize, protocol: &Protocol) -> Result<(), DBError> {
+        let mut data = Vec::new();
+        if self.selected_db != Some(db) {
+            data.extend_from_slice(&Protocol::select(db).encode());
+            self.selected_db = Some(db);
+        }
+        data.extend_from_slice(&protocol.encode());
+        self.file.write_all(&data)?;
+        if let Some(buf) = self.rewrite_buf.as_mut() {
+            buf.extend_from_slice(&data);
+        }
+        if self.fsync == AppendFsync::Always {
+            self.file.sync_data()?;
+            self.last_fsync = Instant::now();
+        }
+        Ok(())
+    }
+
+    // called from the server cron, only does work under `appendfsync everysec`
+    pub fn fsync_if_due(&mut self) -> Result<(), DBError> {
+        if self.fsync == AppendFsync::EverySec
+            && self.last_fsync.elapsed() >= Duration::from_secs(1)
+        {
+            self.file.sync_data()?;
+            self.last_fsync = Instant::now();
+        }
+        Ok(())
+    }
+
+    pub fn is_rewriting(&self) -> bool {
+        self.rewrite_buf.is_some()
+    }
+}
+
+/// Replays the AOF through `Cmd::run`. A truncated last command, as left behind by a crash
+/// mid-write, is cut off when `load_truncated` is set and is an error otherwise.
+pub async fn load_aof(server: &Server, option: &AofOption) -> Result<(), DBError> {
+    let path = PathBuf::from(&server.option.dir).join(&option.file_name);
+    let data = match tokio::fs::read(&path).await {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+
+    // replay on a clone with no AOF attached, so the replayed commands are not logged again
+    let mut replayer = server.clone();
+    replayer.aof = None;
+    let mut offset = 0;
+    let mut queued_cmd = None;
+    let mut commands = 0;
+    while let Some((frame, len)) = Protocol::from(&data[offset..])? {
+        offset += len;
+        let (cmd, protocol) = Cmd::from(frame)?;
+        cmd.run(&mut replayer, protocol, true, &mut queued_cmd)
+            .await?;
+        commands += 1;
+    }
+    server.dirty.store(0, Ordering::Relaxed);
+
+    if offset < data.len() {
+        if !option.load_truncated {
+            return Err(DBError(format!(
+                "unexpected end of AOF {} at offset {}",
+                path.display(),
+                offset
+            )));
+        }
+        println!(
+            "AOF {} was truncated, dropping {} bytes after offset {}",
+            path.display(),
+            data.len() - offset,
+            offset
+        );
+        OpenOptions::new()
+            .write(true)
+            .open(&path)?
+            .set_len(offset as u64)?;
+    }
+    println!("loaded {} commands from AOF {}", commands, path.display());
+    Ok(())
+}
+
+// the smallest set of commands that rebuilds the current keyspace
+async fn rewrite_commands(server: &Server) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for (index, db) in server.dbs.iter().enumerate() {
+        let entries = db.lock().await.snapshot();
+        if !entries.is_empty() {
+            buf.extend_from_slice(&Protocol::select(index).encode());
+            rewrite_db(&mut buf, entries);
+        }
+    }
+    buf
+}
+
+fn rewrite_db(buf: &mut Vec<u8>, entries: Vec<(Bytes, ValueType)>) {
+    for (k, (v, expire_at)) in entries {
+        match v {
+            Value::String(v) => {
+                let mut args = vec![
+                    Protocol::bulk("SET"),
+                    Protocol::bulk(k.clone()),
+                    Protocol::BulkString(v),
+                ];
+                if let Some(expire_at) = expire_at {
+                    args.push(Protocol::bulk("PXAT"));
+                    args.push(Protocol::bulk(expire_at.to_string()));
+                }
+                buf.extend_from_slice(&Protocol::Array(args).encode());
+                continue;
+            }
+            Value::List(list) => write_batched(buf, "RPUSH", &k, list.into_iter().map(|v| vec![v])),
+            Value::Set(set) => write_batched(buf, "SADD", &k, set.iter().map(|v| vec![v])),
+            Value::ZSet(zset) => write_batched(
+                buf,
+                "ZADD",
+                &k,
+                zset.iter()
+                    .map(|(member, score)| vec![Bytes::from(score.to_string()), member.clone()]),
+            ),
+            Value::Hash(hash) => {
+                write_batched(
+                    buf,
+                    "HSET",
+                    &k,
+                    hash.iter()
+                        .map(|(field, value)| vec![field.clone(), value.clone()]),
+                );
+                for (field, _) in hash.iter() {
+                    if let Some(field_expire_at) = hash.expire_at(field) {
+                        let args = vec![
+                            Protocol::bulk("HPEXPIREAT"),
+                            Protocol::bulk(k.clone()),
+                            Protocol::bulk(field_expire_at.to_string()),
+                            Protocol::bulk("FIELDS"),
+                            Protocol::bulk("1"),
+                            Protocol::BulkString(field.clone()),
+                        ];
+                        buf.extend_from_slice(&Protocol::Array(args).encode());
+                    }
+                }
+            }
+            Value::Stream(stream) => {
+                for (id, fields) in stream.entries {
+                    let mut args = vec![
+                        Protocol::bulk("XADD"),
+                        Protocol::bulk(k.clone()),
+                        Protocol::bulk(id.to_string()),
+                    ];
+                    for (field, value) in fields {
+                        args.push(Protocol::BulkString(field));
+                        args.push(Protocol::BulkString(value));
+                    }
+                    buf.extend_from_slice(&Protocol::Array(args).encode());
+                }
+            }
+        }
+        if let Some(expire_at) = expire_at {
+            let args = vec![
+                Protocol::bulk("PEXPIREAT"),
+                Protocol::bulk(k),
+                Protocol::bulk(expire_at.to_string()),
+            ];
+            buf.extend_from_slice(&Protocol::Array(args).encode());
+        }
+    }
+}
+
+// collections are rebuilt with one command per batch of items, to keep each command small
+fn write_batched(buf: &mut Vec<u8>, cmd: &str, k: &Bytes, items: impl Iterator<Item = Vec<Bytes>>) {
+    let mut args = Vec::new();
+    for (i, item) in items.enumerate() {
+        if i % ITEMS_PER_CMD == 0 && !args.is_empty() {
+            buf.extend_from_slice(&Protocol::Array(std::mem::take(&mut args)).encode());
+        }
+        if args.is_empty() {
+            args.push(Protocol::bulk(cmd.to_string()));
+            args.push(Protocol::bulk(k.clone()));
+        }
+        args.extend(item.into_iter().map(Protocol::BulkString));
+    }
+    if !args.is_empty() {
+        buf.extend_from_slice(&Protocol::Array(args).encode());
+    }
+}
+
+/// BGREWRITEAOF: writes the compacted log in the background while new writes keep going to
+/// the old file and to a side buffer, then swaps the new file in. Returns false if a rewrite
+/// is already running or AOF is off.
+pub async fn bgrewrite_aof(server: &Server) -> bool {
+    let aof = match server.aof.clone() {
+        Some(aof) => aof,
+        None => return false,
+    };
+    let (path, commands) = {
+        // with the write lock no write is half done: each one is either in the snapshot and
+        // logged before the buffer starts, or comes after and goes to the buffer only
+        let _write_order = server.write_lock.lock().await;
+        let mut aof = aof.lock().await;
+        if aof.is_rewriting() {
+            return false;
+        }
+        // make the buffer open with a SELECT of its own
+        aof.rewrite_buf = Some(Vec::new());
+        aof.selected_db = None;
+        (aof.path.clone(), rewrite_commands(server).await)
+    };
+
+    tokio::spawn(async move {
+        let tmp_path = path.with_file_name(format!("temp-rewriteaof-{}.aof", std::process::id()));
+        let written = tokio::task::spawn_blocking({
+            let tmp_path = tmp_path.clone();
+            move || write_file(&tmp_path, &commands)
+        })
+        .await
+        .map_err(|e| DBError(e.to_string()))
+        .and_then(|r| r);
+
+        let mut aof = aof.lock().await;
+        let buffered = aof.rewrite_buf.take().unwrap_or_default();
+        let ret = written.and_then(|_| swap_in(&mut aof, &tmp_path, &path, &buffered));
+        match ret {
+            Ok(()) => println!("background AOF rewrite finished successfully"),
+            Err(e) => {
+                let _ = std::fs::remove_file(&tmp_path);
+                println!("background AOF rewrite error: {:?}", e);
+            }
+        }
+    });
+    true
+}
+
+fn write_file(path: &Path, data: &[u8]) -> Result<(), DBError> {
+    let mut f = File::create(path)?;
+    f.write_all(data)?;
+    Ok(())
+}
+
+// append what was written during the rewrite, then atomically replace the old log
+fn swap_in(aof: &mut Aof, tmp_path: &Path, path: &Path, buffered: &[u8]) -> Result<(), DBError> {
+    let mut f = OpenOptions::new().append(true).open(tmp_path)?;
+    f.write_all(buffered)?;
+    f.sync_all()?;
+    std::fs::rename(tmp_path, path)?;
+    aof.file = f;
+    aof.selected_db = None;
+    Ok(())
+}
+
+/// Writes a fresh AOF from the current keyspace, used when AOF is turned on for a dataset that
+/// was loaded from an RDB file.
+pub async fn create_aof(server: &Server) -> Result<(), DBError> {
+    if let Some(aof) = server.aof.clone() {
+        let mut aof = aof.lock().await;
+        let commands = rewrite_commands(server).await;
+        let tmp_path = aof
+            .path
+            .with_file_name(format!("temp-rewriteaof-{}.aof", std::process::id()));
+        let path = aof.path.clone();
+        write_file(&tmp_path, &commands)?;
+        swap_in(&mut aof, &tmp_path, &path, &[])?;
+    }
+    Ok(())
+}
@@ -0,0 +1,109 @@
+// clients blocked on list keys by BLPOP, BRPOP, BLMOVE and BRPOPLPUSH, and on sorted set keys by
+// BZPOPMIN and BZPOPMAX. Each key keeps its waiters in the order they blocked; a push hands its
+// elements to the oldest waiter first and sends the reply over the waiter's channel, so the pop
+// happens under the pusher's storage lock.
+
+use std::collections::{HashMap, VecDeque};
+
+use bytes::Bytes;
+use tokio::sync::oneshot;
+
+use crate::protocol::Protocol;
+
+#[derive(Debug, Clone)]
+pub enum BlockedOp {
+    // BLPOP, BRPOP
+    Pop {
+        left: bool,
+    },
+    // BLMOVE, BRPOPLPUSH
+    Move {
+        dst: Bytes,
+        from_left: bool,
+        to_left: bool,
+    },
+    // BZPOPMIN, BZPOPMAX
+    ZPop {
+        min: bool,
+    },
+}
+
+struct BlockedClient {
+    db: usize,
+    keys: Vec<Bytes>,
+    op: BlockedOp,
+    sender: oneshot::Sender<Protocol>,
+}
+
+#[derive(Default)]
+pub struct BlockingKeys {
+    next_id: u64,
+    clients: HashMap<u64, BlockedClient>,
+    // (database, key) -> ids of the clients blocked on it, oldest first
+    queues: HashMap<(usize, Bytes), VecDeque<u64>>,
+}
+
+impl BlockingKeys {
+    // register a client waiting on any of `keys`, the reply arrives on the returned receiver
+    pub fn block(
+        &mut self,
+        db: usize,
+        keys: &[Bytes],
+        op: BlockedOp,
+    ) -> (u64, oneshot::Receiver<Protocol>) {
+        let id = self.next_id;
+        self.next_id += 1;
+        let (sender, receiver) = oneshot::channel();
+        for k in keys {
+            self.queues
+                .entry((db, k.clone()))
+                .or_default()
+                .push_back(id);
+        }
+        self.clients.insert(
+            id,
+            BlockedClient {
+                db,
+                keys: keys.to_vec(),
+                op,
+                sender,
+            },
+        );
+        (id, receiver)
+    }
+
+    // drop a client that gave up waiting, false if it was served in the meantime
+    pub fn unblock(&mut self, id: u64) -> bool {
+        self.remove(id).is_some()
+    }
+
+    // take the client that has been waiting on `key` the longest among those whose operation
+    // `accepts` holds for, off every key it waits on
+    pub fn pop_waiter(
+        &mut self,
+        db: usize,
+        key: &Bytes,
+        accepts: impl Fn(&BlockedOp) -> bool,
+    ) -> Option<(BlockedOp, oneshot::Sender<Protocol>)> {
+        let id = *self
+            .queues
+            .get(&(db, key.clone()))?
+            .iter()
+            .find(|id| accepts(&self.clients[id].op))?;
+        self.remove(id).map(|c| (c.op, c.sender))
+    }
+
+    fn remove(&mut self, id: u64) -> Option<BlockedClient> {
+        let client = self.clients.remove(&id)?;
+        for k in &client.keys {
+            let queue_key = (client.db, k.clone());
+            if let Some(queue) = self.queues.get_mut(&queue_key) {
+                queue.retain(|x| *x != id);
+                if queue.is_empty() {
+                    self.queues.remove(&queue_key);
+                }
+            }
+        }
+        Some(client)
+    }
+}
@@ -1,64 +1,442 @@
-use std::{collections::BTreeMap, ops::Bound, time::Duration, u64};
+use std::{
+    collections::{hash_map::RandomState, HashMap, VecDeque},
+    hash::{BuildHasher, Hasher},
+    ops::Bound,
+    sync::atomic::Ordering,
+    time::Duration,
+};
 
-use tokio::sync::mpsc;
+use bytes::Bytes;
+use tokio::sync::{mpsc, oneshot, MutexGuard};
 
-use crate::{error::DBError, protocol::Protocol, server::Server, storage::now_in_millis};
+use crate::{
+    aof,
+    blocking::{BlockedOp, BlockingKeys},
+    error::DBError,
+    glob::glob_match,
+    hash::Hash,
+    listpack::string_to_int,
+    options,
+    protocol::{Protocol, RESP2, RESP3},
+    rdb,
+    server::Server,
+    set::Set,
+    storage::{now_in_millis, Storage, Value},
+    stream::{Stream, StreamId},
+    zset::ZSet,
+};
+
+const NOT_INTEGER_ERR: &str = "ERR value is not an integer or out of range";
+const STRING_TOO_LONG_ERR: &str = "ERR string exceeds maximum allowed size (proto-max-bulk-len)";
+// the largest string APPEND and SETRANGE may build, Redis' proto-max-bulk-len default
+const MAX_STRING_LEN: usize = 512 * 1024 * 1024;
+const INVALID_STREAM_ID_ERR: &str = "ERR Invalid stream ID specified as stream command argument";
+// the latest expire time a hash field can be given, in unix milliseconds
+const HASH_FIELD_MAX_EXPIRE: i128 = 1 << 48;
+
+// the unit an expire time is given in or a TTL is reported in, relative to now or as a unix time
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeFormat {
+    Seconds,
+    Millis,
+    UnixSeconds,
+    UnixMillis,
+}
+
+impl TimeFormat {
+    // the absolute expire time in milliseconds `n` stands for, may be negative or out of range
+    fn expire_at(self, n: i64, now: u128) -> i128 {
+        let n = n as i128;
+        match self {
+            TimeFormat::Seconds => now as i128 + n * 1000,
+            TimeFormat::Millis => now as i128 + n,
+            TimeFormat::UnixSeconds => n * 1000,
+            TimeFormat::UnixMillis => n,
+        }
+    }
+
+    // how a TTL command reports `expire_at`, which must not have passed
+    fn format(self, expire_at: u128, now: u128) -> i64 {
+        let ms = match self {
+            TimeFormat::Seconds | TimeFormat::Millis => expire_at.saturating_sub(now),
+            TimeFormat::UnixSeconds | TimeFormat::UnixMillis => expire_at,
+        };
+        match self {
+            TimeFormat::Seconds => ms.div_ceil(1000) as i64,
+            TimeFormat::UnixSeconds => (ms / 1000) as i64,
+            TimeFormat::Millis | TimeFormat::UnixMillis => ms as i64,
+        }
+    }
+}
+
+// SINTER, SUNION and SDIFF, their sorted set counterparts, and their STORE forms
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SetOp {
+    Inter,
+    Union,
+    Diff,
+}
+
+// the options of ZADD
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ZaddFlags {
+    nx: bool,
+    xx: bool,
+    gt: bool,
+    lt: bool,
+    ch: bool,
+    incr: bool,
+}
+
+// what the start and stop of ZRANGE are: ranks, scores or members
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RangeBy {
+    Rank,
+    Score,
+    Lex,
+}
+
+// a sorted set range as given to ZRANGE; with `rev` it runs from the highest element, and start
+// and stop are given highest first
+#[derive(Debug, Clone, PartialEq)]
+pub struct ZrangeSpec {
+    key: Bytes,
+    start: Bytes,
+    stop: Bytes,
+    by: RangeBy,
+    rev: bool,
+    // offset, count
+    limit: Option<(String, String)>,
+}
+
+// how ZUNION and ZINTER combine the scores a member has in several sets
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aggregate {
+    Sum,
+    Min,
+    Max,
+}
+
+// NX, XX, GT and LT of the expire commands
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExpireCondition {
+    Nx,
+    Xx,
+    Gt,
+    Lt,
+}
+
+impl ExpireCondition {
+    fn parse(s: &str) -> Option<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "nx" => Some(ExpireCondition::Nx),
+            "xx" => Some(ExpireCondition::Xx),
+            "gt" => Some(ExpireCondition::Gt),
+            "lt" => Some(ExpireCondition::Lt),
+            _ => None,
+        }
+    }
+
+    // whether `expire_at` may replace `current`, no expiration counting as infinite
+    fn allows(self, current: Option<u128>, expire_at: u128) -> bool {
+        match self {
+            ExpireCondition::Nx => current.is_none(),
+            ExpireCondition::Xx => current.is_some(),
+            ExpireCondition::Gt => current.is_some_and(|c| expire_at > c),
+            ExpireCondition::Lt => current.is_none_or(|c| expire_at < c),
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub enum Cmd {
     Ping,
-    Echo(String),
-    Get(String),
-    Set(String, String),
-    SetPx(String, String, u128),
-    SetEx(String, String, u128),
+    // the arguments, checked for arity when run
+    Echo(Vec<Bytes>),
+    Get(Bytes),
+    // key, value, options
+    Set(Bytes, Bytes, Vec<String>),
     Keys,
     ConfigGet(String),
+    ConfigSet(String, String),
     Info(Option<String>),
-    Del(String),
+    Del(Vec<Bytes>),
     Replconf(String),
     Psync,
-    Type(String),
-    Xadd(String, String, Vec<(String, String)>),
-    Xrange(String, String, String),
-    Xread(Vec<String>, Vec<String>, Option<u64>),
-    Incr(String),
+    Type(Bytes),
+    Xadd(Bytes, String, Vec<(Bytes, Bytes)>),
+    Xrange(Bytes, String, String),
+    Xread(Vec<Bytes>, Vec<String>, Option<u64>),
+    // key, increment, negated for DECR and DECRBY
+    Incrby(Bytes, String, bool),
+    Incrbyfloat(Bytes, String),
+    Mget(Vec<Bytes>),
+    // pairs, only if none of the keys exists
+    Mset(Vec<(Bytes, Bytes)>, bool),
+    Setnx(Bytes, Bytes),
+    Append(Bytes, Bytes),
+    // key, start, end
+    Getrange(Bytes, String, String),
+    // key, offset, value
+    Setrange(Bytes, String, Bytes),
+    Strlen(Bytes),
+    Getdel(Bytes),
+    // key, the new expiration, PERSIST
+    Getex(Bytes, Option<(TimeFormat, String)>, bool),
+    // key, time, unit, condition
+    Expire(Bytes, String, TimeFormat, Option<ExpireCondition>),
+    Ttl(Bytes, TimeFormat),
+    Persist(Bytes),
     Multi,
     Exec,
     Unknow,
     Discard,
+    Hello(Option<u8>),
+    Save,
+    Bgsave,
+    Lastsave,
+    Bgrewriteaof,
+    Select(String),
+    Move(Bytes, String),
+    SwapDb(String, String),
+    FlushDb,
+    FlushAll,
+    DbSize,
+    Lpush(Bytes, Vec<Bytes>),
+    Rpush(Bytes, Vec<Bytes>),
+    Lpop(Bytes, Option<String>),
+    Rpop(Bytes, Option<String>),
+    Lrange(Bytes, String, String),
+    Lindex(Bytes, String),
+    Lset(Bytes, String, Bytes),
+    Lrem(Bytes, String, Bytes),
+    Ltrim(Bytes, String, String),
+    // key, whether to insert before the pivot, pivot, element
+    Linsert(Bytes, bool, Bytes, Bytes),
+    Llen(Bytes),
+    // source, destination, pop from the left, push to the left
+    Lmove(Bytes, Bytes, bool, bool),
+    // keys, timeout
+    Blpop(Vec<Bytes>, String),
+    Brpop(Vec<Bytes>, String),
+    // source, destination, pop from the left, push to the left, timeout
+    Blmove(Bytes, Bytes, bool, bool, String),
+    Hset(Bytes, Vec<(Bytes, Bytes)>),
+    Hmset(Bytes, Vec<(Bytes, Bytes)>),
+    Hsetnx(Bytes, Bytes, Bytes),
+    Hget(Bytes, Bytes),
+    Hmget(Bytes, Vec<Bytes>),
+    Hdel(Bytes, Vec<Bytes>),
+    Hlen(Bytes),
+    Hexists(Bytes, Bytes),
+    Hgetall(Bytes),
+    Hkeys(Bytes),
+    Hvals(Bytes),
+    Hstrlen(Bytes, Bytes),
+    Hincrby(Bytes, Bytes, String),
+    Hincrbyfloat(Bytes, Bytes, String),
+    // key, cursor, options
+    Hscan(Bytes, String, Vec<String>),
+    // key, time, its format, condition, fields
+    Hexpire(
+        Bytes,
+        String,
+        TimeFormat,
+        Option<ExpireCondition>,
+        Vec<Bytes>,
+    ),
+    Httl(Bytes, TimeFormat, Vec<Bytes>),
+    Hpersist(Bytes, Vec<Bytes>),
+    Sadd(Bytes, Vec<Bytes>),
+    Srem(Bytes, Vec<Bytes>),
+    Sismember(Bytes, Bytes),
+    Smismember(Bytes, Vec<Bytes>),
+    Smembers(Bytes),
+    Scard(Bytes),
+    Scombine(SetOp, Vec<Bytes>),
+    // operation, destination, keys
+    ScombineStore(SetOp, Bytes, Vec<Bytes>),
+    // keys, limit
+    Sintercard(Vec<Bytes>, Option<String>),
+    Srandmember(Bytes, Option<String>),
+    Spop(Bytes, Option<String>),
+    // source, destination, member
+    Smove(Bytes, Bytes, Bytes),
+    // key, cursor, options
+    Sscan(Bytes, String, Vec<String>),
+    // key, options, (score, member) pairs
+    Zadd(Bytes, ZaddFlags, Vec<(String, Bytes)>),
+    Zrem(Bytes, Vec<Bytes>),
+    Zcard(Bytes),
+    Zscore(Bytes, Bytes),
+    // key, member, highest first, with the score
+    Zrank(Bytes, Bytes, bool, bool),
+    // key, min, max
+    Zcount(Bytes, Bytes, Bytes),
+    // range, with the scores
+    Zrange(ZrangeSpec, bool),
+    // destination, range
+    Zrangestore(Bytes, ZrangeSpec),
+    // key, lowest first, count
+    Zpop(Bytes, bool, Option<String>),
+    // keys, timeout, lowest first
+    Bzpop(Vec<Bytes>, String, bool),
+    // operation, the destination of the STORE forms, keys, weights, aggregate, with the scores
+    Zcombine(SetOp, Option<Bytes>, Vec<Bytes>, Vec<f64>, Aggregate, bool),
+}
+
+// `FIELDS numfields field [field ...]` at `at`, running to the end of the command
+fn parse_fields(cmd: &[String], args: &[Bytes], at: usize) -> Option<Vec<Bytes>> {
+    if !cmd.get(at)?.eq_ignore_ascii_case("fields") {
+        return None;
+    }
+    let n = cmd.get(at + 1)?.parse::<usize>().ok()?;
+    if n == 0 || cmd.len() != at + 2 + n {
+        return None;
+    }
+    Some(args[at + 2..].to_vec())
+}
+
+// LEFT or RIGHT, as true for the left end
+fn parse_list_end(s: &str) -> Option<bool> {
+    if s.eq_ignore_ascii_case("left") {
+        Some(true)
+    } else if s.eq_ignore_ascii_case("right") {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+// `key start stop` at `at` and the options after it, and whether WITHSCORES was given. `by` and
+// `rev` are the command's own, which BYSCORE, BYLEX and REV may change when `options` allows them
+fn parse_zrange(
+    cmd: &[String],
+    args: &[Bytes],
+    at: usize,
+    mut by: RangeBy,
+    mut rev: bool,
+    options: bool,
+) -> Option<(ZrangeSpec, bool)> {
+    if cmd.len() < at + 3 {
+        return None;
+    }
+    let mut limit = None;
+    let mut withscores = false;
+    let mut i = at + 3;
+    while i < cmd.len() {
+        match cmd[i].to_ascii_lowercase().as_str() {
+            "byscore" if options => by = RangeBy::Score,
+            "bylex" if options => by = RangeBy::Lex,
+            "rev" if options => rev = true,
+            "withscores" => withscores = true,
+            "limit" => {
+                limit = Some((cmd.get(i + 1)?.clone(), cmd.get(i + 2)?.clone()));
+                i += 2;
+            }
+            _ => return None,
+        }
+        i += 1;
+    }
+    // LIMIT only applies to score and member ranges, and member ranges have no scores to show
+    if (limit.is_some() && by == RangeBy::Rank) || (withscores && by == RangeBy::Lex) {
+        return None;
+    }
+    let spec = ZrangeSpec {
+        key: args[at].clone(),
+        start: args[at + 1].clone(),
+        stop: args[at + 2].clone(),
+        by,
+        rev,
+        limit,
+    };
+    Some((spec, withscores))
+}
+
+// `numkeys key [key ...]` at `at` and the WEIGHTS, AGGREGATE and WITHSCORES options after it
+fn parse_zcombine(
+    cmd: &[String],
+    args: &[Bytes],
+    at: usize,
+    op: SetOp,
+    store: bool,
+) -> Option<(Vec<Bytes>, Vec<f64>, Aggregate, bool)> {
+    let numkeys = cmd.get(at)?.parse::<usize>().ok().filter(|n| *n > 0)?;
+    let keys = args.get(at + 1..at + 1 + numkeys)?.to_vec();
+    let mut weights = vec![1.0; numkeys];
+    let mut aggregate = Aggregate::Sum;
+    let mut withscores = false;
+    let mut i = at + 1 + numkeys;
+    while i < cmd.len() {
+        match cmd[i].to_ascii_lowercase().as_str() {
+            "weights" if op != SetOp::Diff => {
+                for (j, weight) in weights.iter_mut().enumerate() {
+                    *weight = cmd
+                        .get(i + 1 + j)?
+                        .parse::<f64>()
+                        .ok()
+                        .filter(|w| !w.is_nan())?;
+                }
+                i += numkeys;
+            }
+            "aggregate" if op != SetOp::Diff => {
+                aggregate = match cmd.get(i + 1)?.to_ascii_lowercase().as_str() {
+                    "sum" => Aggregate::Sum,
+                    "min" => Aggregate::Min,
+                    "max" => Aggregate::Max,
+                    _ => return None,
+                };
+                i += 1;
+            }
+            "withscores" if !store => withscores = true,
+            _ => return None,
+        }
+        i += 1;
+    }
+    Some((keys, weights, aggregate, withscores))
 }
 
 impl Cmd {
-    pub fn from(s: &str) -> Result<(Self, Protocol), DBError> {
-        let protocol = Protocol::from(s)?;
-        match protocol.clone().0 {
+    pub fn from(protocol: Protocol) -> Result<(Self, Protocol), DBError> {
+        match protocol.clone() {
             Protocol::Array(p) => {
-                let cmd = p.into_iter().map(|x| x.decode()).collect::<Vec<_>>();
-                if cmd.is_empty() {
+                let args = p
+                    .into_iter()
+                    .map(|x| match x {
+                        Protocol::BulkString(b) => b,
+                        other => Bytes::from(other.decode()),
+                    })
+                    .collect::<Vec<_>>();
+                if args.is_empty() {
                     return Err(DBError("cmd length is 0".to_string()));
                 }
+                let cmd = args
+                    .iter()
+                    .map(|x| String::from_utf8_lossy(x).to_string())
+                    .collect::<Vec<_>>();
                 Ok((
-                    match cmd[0].as_str() {
-                        "echo" => Cmd::Echo(cmd[1].clone()),
+                    match cmd[0].to_ascii_lowercase().as_str() {
+                        "echo" => Cmd::Echo(args[1..].to_vec()),
                         "ping" => Cmd::Ping,
-                        "get" => Cmd::Get(cmd[1].clone()),
+                        "get" => {
+                            if cmd.len() != 2 {
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            }
+                            Cmd::Get(args[1].clone())
+                        }
                         "set" => {
-                            if cmd.len() == 5 && cmd[3] == "px" {
-                                Cmd::SetPx(cmd[1].clone(), cmd[2].clone(), cmd[4].parse().unwrap())
-                            } else if cmd.len() == 5 && cmd[3] == "ex" {
-                                Cmd::SetEx(cmd[1].clone(), cmd[2].clone(), cmd[4].parse().unwrap())
-                            } else if cmd.len() == 3 {
-                                Cmd::Set(cmd[1].clone(), cmd[2].clone())
-                            } else {
+                            if cmd.len() < 3 {
                                 return Err(DBError(format!("unsupported cmd {:?}", cmd)));
                             }
+                            Cmd::Set(args[1].clone(), args[2].clone(), cmd[3..].to_vec())
                         }
                         "config" => {
-                            if cmd.len() != 3 || cmd[1] != "get" {
-                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            if cmd.len() == 3 && cmd[1].eq_ignore_ascii_case("get") {
+                                Cmd::ConfigGet(cmd[2].to_ascii_lowercase())
+                            } else if cmd.len() == 4 && cmd[1].eq_ignore_ascii_case("set") {
+                                Cmd::ConfigSet(cmd[2].to_ascii_lowercase(), cmd[3].clone())
                             } else {
-                                Cmd::ConfigGet(cmd[2].clone())
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
                             }
                         }
                         "keys" => {
@@ -70,7 +448,7 @@ impl Cmd {
                         }
                         "info" => {
                             let section = if cmd.len() == 2 {
-                                Some(cmd[1].clone())
+                                Some(cmd[1].to_ascii_lowercase())
                             } else {
                                 None
                             };
@@ -80,7 +458,7 @@ impl Cmd {
                             if cmd.len() < 3 {
                                 return Err(DBError(format!("unsupported cmd {:?}", cmd)));
                             }
-                            Cmd::Replconf(cmd[1].clone())
+                            Cmd::Replconf(cmd[1].to_ascii_lowercase())
                         }
                         "psync" => {
                             if cmd.len() != 3 {
@@ -89,35 +467,35 @@ impl Cmd {
                             Cmd::Psync
                         }
                         "del" => {
-                            if cmd.len() != 2 {
+                            if cmd.len() < 2 {
                                 return Err(DBError(format!("unsupported cmd {:?}", cmd)));
                             }
-                            Cmd::Del(cmd[1].clone())
+                            Cmd::Del(args[1..].to_vec())
                         }
                         "type" => {
                             if cmd.len() != 2 {
                                 return Err(DBError(format!("unsupported cmd {:?}", cmd)));
                             }
-                            Cmd::Type(cmd[1].clone())
+                            Cmd::Type(args[1].clone())
                         }
                         "xadd" => {
                             if cmd.len() < 5 {
                                 return Err(DBError(format!("unsupported cmd {:?}", cmd)));
                             }
 
-                            let mut key_value = Vec::<(String, String)>::new();
+                            let mut key_value = Vec::<(Bytes, Bytes)>::new();
                             let mut i = 3;
                             while i < cmd.len() - 1 {
-                                key_value.push((cmd[i].clone(), cmd[i + 1].clone()));
+                                key_value.push((args[i].clone(), args[i + 1].clone()));
                                 i += 2;
                             }
-                            Cmd::Xadd(cmd[1].clone(), cmd[2].clone(), key_value)
+                            Cmd::Xadd(args[1].clone(), cmd[2].clone(), key_value)
                         }
                         "xrange" => {
                             if cmd.len() != 4 {
                                 return Err(DBError(format!("unsupported cmd {:?}", cmd)));
                             }
-                            Cmd::Xrange(cmd[1].clone(), cmd[2].clone(), cmd[3].clone())
+                            Cmd::Xrange(args[1].clone(), cmd[2].clone(), cmd[3].clone())
                         }
                         "xread" => {
                             if cmd.len() < 4 || cmd.len() % 2 != 0 {
@@ -126,7 +504,7 @@ impl Cmd {
                             let mut offset = 2;
                             // block cmd
                             let mut block = None;
-                            if cmd[1] == "block" {
+                            if cmd[1].eq_ignore_ascii_case("block") {
                                 offset += 2;
                                 if let Ok(block_time) = cmd[2].parse() {
                                     block = Some(block_time);
@@ -134,190 +512,3502 @@ impl Cmd {
                                     return Err(DBError(format!("unsupported cmd {:?}", cmd)));
                                 }
                             }
-                            let cmd2 = &cmd[offset..];
-                            let len2 = cmd2.len() / 2;
-                            Cmd::Xread(cmd2[0..len2].to_vec(), cmd2[len2..].to_vec(), block)
+                            let len2 = (cmd.len() - offset) / 2;
+                            Cmd::Xread(
+                                args[offset..offset + len2].to_vec(),
+                                cmd[offset + len2..].to_vec(),
+                                block,
+                            )
                         }
-                        "incr" => {
+                        "incr" | "decr" => {
                             if cmd.len() != 2 {
                                 return Err(DBError(format!("unsupported cmd {:?}", cmd)));
                             }
-                            Cmd::Incr(cmd[1].clone())
+                            let decr = cmd[0].eq_ignore_ascii_case("decr");
+                            Cmd::Incrby(args[1].clone(), "1".to_string(), decr)
                         }
-                        "multi" => {
-                            if cmd.len() != 1 {
+                        "incrby" | "decrby" => {
+                            if cmd.len() != 3 {
                                 return Err(DBError(format!("unsupported cmd {:?}", cmd)));
                             }
-                            Cmd::Multi
+                            let decr = cmd[0].eq_ignore_ascii_case("decrby");
+                            Cmd::Incrby(args[1].clone(), cmd[2].clone(), decr)
                         }
-                        "exec" => {
-                            if cmd.len() != 1 {
+                        "incrbyfloat" => {
+                            if cmd.len() != 3 {
                                 return Err(DBError(format!("unsupported cmd {:?}", cmd)));
                             }
-                            Cmd::Exec
+                            Cmd::Incrbyfloat(args[1].clone(), cmd[2].clone())
                         }
-                        "discard" => Cmd::Discard,
-                        _ => Cmd::Unknow,
-                    },
-                    protocol.0,
-                ))
-            }
-            _ => Err(DBError(format!(
-                "fail to parse as cmd for {:?}",
-                protocol.0
-            ))),
-        }
-    }
-
-    pub async fn run(
-        &self,
-        server: &mut Server,
-        protocol: Protocol,
-        is_rep_con: bool,
-        queued_cmd: &mut Option<Vec<(Cmd, Protocol)>>,
-    ) -> Result<Protocol, DBError> {
-        // return if the command is a write command
-        let p = protocol.clone();
-        if queued_cmd.is_some()
-            && !matches!(self, Cmd::Exec)
-            && !matches!(self, Cmd::Multi)
-            && !matches!(self, Cmd::Discard)
-        {
-            queued_cmd
-                .as_mut()
-                .unwrap()
-                .push((self.clone(), protocol.clone()));
-            return Ok(Protocol::SimpleString("QUEUED".to_string()));
-        }
-        let ret = match self {
-            Cmd::Ping => Ok(Protocol::SimpleString("PONG".to_string())),
-            Cmd::Echo(s) => Ok(Protocol::SimpleString(s.clone())),
-            Cmd::Get(k) => get_cmd(server, k).await,
-            Cmd::Set(k, v) => set_cmd(server, k, v, protocol, is_rep_con).await,
-            Cmd::SetPx(k, v, x) => set_px_cmd(server, k, v, x, protocol, is_rep_con).await,
-            Cmd::SetEx(k, v, x) => set_ex_cmd(server, k, v, x, protocol, is_rep_con).await,
-            Cmd::Del(k) => del_cmd(server, k, protocol, is_rep_con).await,
-            Cmd::ConfigGet(name) => config_get_cmd(name, server),
-            Cmd::Keys => keys_cmd(server).await,
-            Cmd::Info(section) => info_cmd(section, server),
-            Cmd::Replconf(sub_cmd) => replconf_cmd(sub_cmd, server),
-            Cmd::Psync => psync_cmd(server),
-            Cmd::Type(k) => type_cmd(server, k).await,
-            Cmd::Xadd(stream_key, offset, kvps) => {
-                xadd_cmd(
-                    offset.as_str(),
-                    server,
-                    stream_key.as_str(),
-                    kvps,
-                    protocol,
-                    is_rep_con,
-                )
-                .await
-            }
-
-            Cmd::Xrange(stream_key, start, end) => xrange_cmd(server, stream_key, start, end).await,
-            Cmd::Xread(stream_keys, starts, block) => {
-                xread_cmd(starts, server, stream_keys, block).await
-            }
-            Cmd::Incr(key) => incr_cmd(server, key).await,
-            Cmd::Multi => {
-                *queued_cmd = Some(Vec::<(Cmd, Protocol)>::new());
-                Ok(Protocol::SimpleString("ok".to_string()))
-            }
-            Cmd::Exec => exec_cmd(queued_cmd, server, is_rep_con).await,
-            Cmd::Discard => {
-                if queued_cmd.is_some() {
-                    *queued_cmd = None;
-                    Ok(Protocol::SimpleString("ok".to_string()))
-                } else {
-                    Ok(Protocol::err("ERR Discard without MULTI"))
-                }
-            }
-            Cmd::Unknow => Ok(Protocol::err("unknow cmd")),
-        };
-        if ret.is_ok() {
-            server.offset.fetch_add(
-                p.encode().len() as u64,
-                std::sync::atomic::Ordering::Relaxed,
-            );
+                        "mget" => {
+                            if cmd.len() < 2 {
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            }
+                            Cmd::Mget(args[1..].to_vec())
+                        }
+                        "mset" | "msetnx" => {
+                            if cmd.len() < 3 || cmd.len() % 2 != 1 {
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            }
+                            let pairs = (1..cmd.len())
+                                .step_by(2)
+                                .map(|i| (args[i].clone(), args[i + 1].clone()))
+                                .collect();
+                            Cmd::Mset(pairs, cmd[0].eq_ignore_ascii_case("msetnx"))
+                        }
+                        "setnx" | "append" => {
+                            if cmd.len() != 3 {
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            }
+                            if cmd[0].eq_ignore_ascii_case("setnx") {
+                                Cmd::Setnx(args[1].clone(), args[2].clone())
+                            } else {
+                                Cmd::Append(args[1].clone(), args[2].clone())
+                            }
+                        }
+                        "getrange" | "substr" => {
+                            if cmd.len() != 4 {
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            }
+                            Cmd::Getrange(args[1].clone(), cmd[2].clone(), cmd[3].clone())
+                        }
+                        "setrange" => {
+                            if cmd.len() != 4 {
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            }
+                            Cmd::Setrange(args[1].clone(), cmd[2].clone(), args[3].clone())
+                        }
+                        "strlen" | "getdel" => {
+                            if cmd.len() != 2 {
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            }
+                            if cmd[0].eq_ignore_ascii_case("strlen") {
+                                Cmd::Strlen(args[1].clone())
+                            } else {
+                                Cmd::Getdel(args[1].clone())
+                            }
+                        }
+                        "expire" | "pexpire" | "expireat" | "pexpireat" => {
+                            // EXPIRE key time [NX | XX | GT | LT]
+                            let condition = match cmd.get(3).map(|c| ExpireCondition::parse(c)) {
+                                Some(None) => {
+                                    return Err(DBError(format!("unsupported cmd {:?}", cmd)))
+                                }
+                                Some(condition) => condition,
+                                None => None,
+                            };
+                            if cmd.len() != 3 && cmd.len() != 4 {
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            }
+                            let format = match cmd[0].to_ascii_lowercase().as_str() {
+                                "expire" => TimeFormat::Seconds,
+                                "pexpire" => TimeFormat::Millis,
+                                "expireat" => TimeFormat::UnixSeconds,
+                                _ => TimeFormat::UnixMillis,
+                            };
+                            Cmd::Expire(args[1].clone(), cmd[2].clone(), format, condition)
+                        }
+                        "ttl" | "pttl" | "expiretime" | "pexpiretime" | "persist" => {
+                            if cmd.len() != 2 {
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            }
+                            match cmd[0].to_ascii_lowercase().as_str() {
+                                "ttl" => Cmd::Ttl(args[1].clone(), TimeFormat::Seconds),
+                                "pttl" => Cmd::Ttl(args[1].clone(), TimeFormat::Millis),
+                                "expiretime" => Cmd::Ttl(args[1].clone(), TimeFormat::UnixSeconds),
+                                "pexpiretime" => Cmd::Ttl(args[1].clone(), TimeFormat::UnixMillis),
+                                _ => Cmd::Persist(args[1].clone()),
+                            }
+                        }
+                        "getex" => {
+                            // GETEX key [EX seconds | PX ms | EXAT unix-seconds | PXAT unix-ms | PERSIST]
+                            let option = cmd.get(2).map(|o| o.to_ascii_lowercase());
+                            match (option.as_deref(), cmd.len()) {
+                                (None, 2) => Cmd::Getex(args[1].clone(), None, false),
+                                (Some("persist"), 3) => Cmd::Getex(args[1].clone(), None, true),
+                                (Some(option), 4) => {
+                                    let format = match option {
+                                        "ex" => TimeFormat::Seconds,
+                                        "px" => TimeFormat::Millis,
+                                        "exat" => TimeFormat::UnixSeconds,
+                                        "pxat" => TimeFormat::UnixMillis,
+                                        _ => {
+                                            return Err(DBError(format!(
+                                                "unsupported cmd {:?}",
+                                                cmd
+                                            )))
+                                        }
+                                    };
+                                    Cmd::Getex(
+                                        args[1].clone(),
+                                        Some((format, cmd[3].clone())),
+                                        false,
+                                    )
+                                }
+                                _ => return Err(DBError(format!("unsupported cmd {:?}", cmd))),
+                            }
+                        }
+                        "lpush" | "rpush" => {
+                            if cmd.len() < 3 {
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            }
+                            if cmd[0].eq_ignore_ascii_case("lpush") {
+                                Cmd::Lpush(args[1].clone(), args[2..].to_vec())
+                            } else {
+                                Cmd::Rpush(args[1].clone(), args[2..].to_vec())
+                            }
+                        }
+                        "lpop" | "rpop" => {
+                            if cmd.len() != 2 && cmd.len() != 3 {
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            }
+                            let count = cmd.get(2).cloned();
+                            if cmd[0].eq_ignore_ascii_case("lpop") {
+                                Cmd::Lpop(args[1].clone(), count)
+                            } else {
+                                Cmd::Rpop(args[1].clone(), count)
+                            }
+                        }
+                        "lrange" => {
+                            if cmd.len() != 4 {
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            }
+                            Cmd::Lrange(args[1].clone(), cmd[2].clone(), cmd[3].clone())
+                        }
+                        "lindex" => {
+                            if cmd.len() != 3 {
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            }
+                            Cmd::Lindex(args[1].clone(), cmd[2].clone())
+                        }
+                        "lset" => {
+                            if cmd.len() != 4 {
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            }
+                            Cmd::Lset(args[1].clone(), cmd[2].clone(), args[3].clone())
+                        }
+                        "lrem" => {
+                            if cmd.len() != 4 {
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            }
+                            Cmd::Lrem(args[1].clone(), cmd[2].clone(), args[3].clone())
+                        }
+                        "ltrim" => {
+                            if cmd.len() != 4 {
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            }
+                            Cmd::Ltrim(args[1].clone(), cmd[2].clone(), cmd[3].clone())
+                        }
+                        "linsert" => {
+                            let before = match cmd.get(2).map(|w| w.to_ascii_lowercase()) {
+                                Some(w) if w == "before" => true,
+                                Some(w) if w == "after" => false,
+                                _ => return Err(DBError(format!("unsupported cmd {:?}", cmd))),
+                            };
+                            if cmd.len() != 5 {
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            }
+                            Cmd::Linsert(args[1].clone(), before, args[3].clone(), args[4].clone())
+                        }
+                        "llen" => {
+                            if cmd.len() != 2 {
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            }
+                            Cmd::Llen(args[1].clone())
+                        }
+                        "lmove" | "blmove" => {
+                            let blocking = cmd[0].eq_ignore_ascii_case("blmove");
+                            if cmd.len() != if blocking { 6 } else { 5 } {
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            }
+                            let (from_left, to_left) =
+                                match (parse_list_end(&cmd[3]), parse_list_end(&cmd[4])) {
+                                    (Some(from_left), Some(to_left)) => (from_left, to_left),
+                                    _ => return Err(DBError(format!("unsupported cmd {:?}", cmd))),
+                                };
+                            if blocking {
+                                Cmd::Blmove(
+                                    args[1].clone(),
+                                    args[2].clone(),
+                                    from_left,
+                                    to_left,
+                                    cmd[5].clone(),
+                                )
+                            } else {
+                                Cmd::Lmove(args[1].clone(), args[2].clone(), from_left, to_left)
+                            }
+                        }
+                        "rpoplpush" => {
+                            if cmd.len() != 3 {
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            }
+                            Cmd::Lmove(args[1].clone(), args[2].clone(), false, true)
+                        }
+                        "brpoplpush" => {
+                            if cmd.len() != 4 {
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            }
+                            Cmd::Blmove(
+                                args[1].clone(),
+                                args[2].clone(),
+                                false,
+                                true,
+                                cmd[3].clone(),
+                            )
+                        }
+                        "blpop" | "brpop" => {
+                            if cmd.len() < 3 {
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            }
+                            let keys = args[1..cmd.len() - 1].to_vec();
+                            let timeout = cmd[cmd.len() - 1].clone();
+                            if cmd[0].eq_ignore_ascii_case("blpop") {
+                                Cmd::Blpop(keys, timeout)
+                            } else {
+                                Cmd::Brpop(keys, timeout)
+                            }
+                        }
+                        "hset" | "hmset" => {
+                            if cmd.len() < 4 || cmd.len() % 2 != 0 {
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            }
+                            let pairs = args[2..]
+                                .chunks(2)
+                                .map(|p| (p[0].clone(), p[1].clone()))
+                                .collect();
+                            if cmd[0].eq_ignore_ascii_case("hset") {
+                                Cmd::Hset(args[1].clone(), pairs)
+                            } else {
+                                Cmd::Hmset(args[1].clone(), pairs)
+                            }
+                        }
+                        "hsetnx" => {
+                            if cmd.len() != 4 {
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            }
+                            Cmd::Hsetnx(args[1].clone(), args[2].clone(), args[3].clone())
+                        }
+                        "hget" | "hexists" | "hstrlen" => {
+                            if cmd.len() != 3 {
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            }
+                            match cmd[0].to_ascii_lowercase().as_str() {
+                                "hget" => Cmd::Hget(args[1].clone(), args[2].clone()),
+                                "hexists" => Cmd::Hexists(args[1].clone(), args[2].clone()),
+                                _ => Cmd::Hstrlen(args[1].clone(), args[2].clone()),
+                            }
+                        }
+                        "hmget" | "hdel" => {
+                            if cmd.len() < 3 {
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            }
+                            if cmd[0].eq_ignore_ascii_case("hmget") {
+                                Cmd::Hmget(args[1].clone(), args[2..].to_vec())
+                            } else {
+                                Cmd::Hdel(args[1].clone(), args[2..].to_vec())
+                            }
+                        }
+                        "hlen" | "hgetall" | "hkeys" | "hvals" => {
+                            if cmd.len() != 2 {
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            }
+                            match cmd[0].to_ascii_lowercase().as_str() {
+                                "hlen" => Cmd::Hlen(args[1].clone()),
+                                "hgetall" => Cmd::Hgetall(args[1].clone()),
+                                "hkeys" => Cmd::Hkeys(args[1].clone()),
+                                _ => Cmd::Hvals(args[1].clone()),
+                            }
+                        }
+                        "hincrby" | "hincrbyfloat" => {
+                            if cmd.len() != 4 {
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            }
+                            if cmd[0].eq_ignore_ascii_case("hincrby") {
+                                Cmd::Hincrby(args[1].clone(), args[2].clone(), cmd[3].clone())
+                            } else {
+                                Cmd::Hincrbyfloat(args[1].clone(), args[2].clone(), cmd[3].clone())
+                            }
+                        }
+                        "hscan" => {
+                            if cmd.len() < 3 {
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            }
+                            Cmd::Hscan(args[1].clone(), cmd[2].clone(), cmd[3..].to_vec())
+                        }
+                        "hexpire" | "hpexpire" | "hexpireat" | "hpexpireat" => {
+                            let format = match cmd[0].to_ascii_lowercase().as_str() {
+                                "hexpire" => TimeFormat::Seconds,
+                                "hpexpire" => TimeFormat::Millis,
+                                "hexpireat" => TimeFormat::UnixSeconds,
+                                _ => TimeFormat::UnixMillis,
+                            };
+                            // HEXPIRE key time [NX | XX | GT | LT] FIELDS numfields field ...
+                            let condition = cmd.get(3).and_then(|c| ExpireCondition::parse(c));
+                            let at = if condition.is_some() { 4 } else { 3 };
+                            match (cmd.get(2), parse_fields(&cmd, &args, at)) {
+                                (Some(time), Some(fields)) => Cmd::Hexpire(
+                                    args[1].clone(),
+                                    time.clone(),
+                                    format,
+                                    condition,
+                                    fields,
+                                ),
+                                _ => return Err(DBError(format!("unsupported cmd {:?}", cmd))),
+                            }
+                        }
+                        "httl" | "hpttl" | "hexpiretime" | "hpexpiretime" | "hpersist" => {
+                            let fields = match parse_fields(&cmd, &args, 2) {
+                                Some(fields) => fields,
+                                None => return Err(DBError(format!("unsupported cmd {:?}", cmd))),
+                            };
+                            match cmd[0].to_ascii_lowercase().as_str() {
+                                "httl" => Cmd::Httl(args[1].clone(), TimeFormat::Seconds, fields),
+                                "hpttl" => Cmd::Httl(args[1].clone(), TimeFormat::Millis, fields),
+                                "hexpiretime" => {
+                                    Cmd::Httl(args[1].clone(), TimeFormat::UnixSeconds, fields)
+                                }
+                                "hpexpiretime" => {
+                                    Cmd::Httl(args[1].clone(), TimeFormat::UnixMillis, fields)
+                                }
+                                _ => Cmd::Hpersist(args[1].clone(), fields),
+                            }
+                        }
+                        "sadd" | "srem" | "smismember" => {
+                            if cmd.len() < 3 {
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            }
+                            match cmd[0].to_ascii_lowercase().as_str() {
+                                "sadd" => Cmd::Sadd(args[1].clone(), args[2..].to_vec()),
+                                "srem" => Cmd::Srem(args[1].clone(), args[2..].to_vec()),
+                                _ => Cmd::Smismember(args[1].clone(), args[2..].to_vec()),
+                            }
+                        }
+                        "sismember" => {
+                            if cmd.len() != 3 {
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            }
+                            Cmd::Sismember(args[1].clone(), args[2].clone())
+                        }
+                        "smembers" | "scard" => {
+                            if cmd.len() != 2 {
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            }
+                            if cmd[0].eq_ignore_ascii_case("smembers") {
+                                Cmd::Smembers(args[1].clone())
+                            } else {
+                                Cmd::Scard(args[1].clone())
+                            }
+                        }
+                        "sinter" | "sunion" | "sdiff" | "sinterstore" | "sunionstore"
+                        | "sdiffstore" => {
+                            let name = cmd[0].to_ascii_lowercase();
+                            let op = match &name[..5] {
+                                "sinte" => SetOp::Inter,
+                                "sunio" => SetOp::Union,
+                                _ => SetOp::Diff,
+                            };
+                            if name.ends_with("store") {
+                                if cmd.len() < 3 {
+                                    return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                                }
+                                Cmd::ScombineStore(op, args[1].clone(), args[2..].to_vec())
+                            } else {
+                                if cmd.len() < 2 {
+                                    return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                                }
+                                Cmd::Scombine(op, args[1..].to_vec())
+                            }
+                        }
+                        "sintercard" => {
+                            // SINTERCARD numkeys key [key ...] [LIMIT limit]
+                            let numkeys = cmd.get(1).and_then(|n| n.parse::<usize>().ok());
+                            match numkeys {
+                                Some(n) if n > 0 && cmd.len() == 2 + n => {
+                                    Cmd::Sintercard(args[2..].to_vec(), None)
+                                }
+                                Some(n)
+                                    if n > 0
+                                        && cmd.len() == 4 + n
+                                        && cmd[2 + n].eq_ignore_ascii_case("limit") =>
+                                {
+                                    Cmd::Sintercard(
+                                        args[2..2 + n].to_vec(),
+                                        Some(cmd[3 + n].clone()),
+                                    )
+                                }
+                                _ => return Err(DBError(format!("unsupported cmd {:?}", cmd))),
+                            }
+                        }
+                        "srandmember" | "spop" => {
+                            if cmd.len() != 2 && cmd.len() != 3 {
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            }
+                            let count = cmd.get(2).cloned();
+                            if cmd[0].eq_ignore_ascii_case("srandmember") {
+                                Cmd::Srandmember(args[1].clone(), count)
+                            } else {
+                                Cmd::Spop(args[1].clone(), count)
+                            }
+                        }
+                        "smove" => {
+                            if cmd.len() != 4 {
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            }
+                            Cmd::Smove(args[1].clone(), args[2].clone(), args[3].clone())
+                        }
+                        "sscan" => {
+                            if cmd.len() < 3 {
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            }
+                            Cmd::Sscan(args[1].clone(), cmd[2].clone(), cmd[3..].to_vec())
+                        }
+                        "zadd" => {
+                            // ZADD key [NX | XX] [GT | LT] [CH] [INCR] score member ...
+                            let mut flags = ZaddFlags::default();
+                            let mut i = 2;
+                            while let Some(flag) = cmd.get(i) {
+                                match flag.to_ascii_lowercase().as_str() {
+                                    "nx" => flags.nx = true,
+                                    "xx" => flags.xx = true,
+                                    "gt" => flags.gt = true,
+                                    "lt" => flags.lt = true,
+                                    "ch" => flags.ch = true,
+                                    "incr" => flags.incr = true,
+                                    _ => break,
+                                }
+                                i += 1;
+                            }
+                            if cmd.len() == i || (cmd.len() - i) % 2 != 0 {
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            }
+                            let pairs = (i..cmd.len())
+                                .step_by(2)
+                                .map(|j| (cmd[j].clone(), args[j + 1].clone()))
+                                .collect();
+                            Cmd::Zadd(args[1].clone(), flags, pairs)
+                        }
+                        "zincrby" => {
+                            if cmd.len() != 4 {
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            }
+                            let flags = ZaddFlags {
+                                incr: true,
+                                ..Default::default()
+                            };
+                            Cmd::Zadd(
+                                args[1].clone(),
+                                flags,
+                                vec![(cmd[2].clone(), args[3].clone())],
+                            )
+                        }
+                        "zrem" => {
+                            if cmd.len() < 3 {
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            }
+                            Cmd::Zrem(args[1].clone(), args[2..].to_vec())
+                        }
+                        "zcard" => {
+                            if cmd.len() != 2 {
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            }
+                            Cmd::Zcard(args[1].clone())
+                        }
+                        "zscore" => {
+                            if cmd.len() != 3 {
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            }
+                            Cmd::Zscore(args[1].clone(), args[2].clone())
+                        }
+                        "zrank" | "zrevrank" => {
+                            let withscore = match cmd.get(3) {
+                                None => false,
+                                Some(o) if o.eq_ignore_ascii_case("withscore") => true,
+                                Some(_) => {
+                                    return Err(DBError(format!("unsupported cmd {:?}", cmd)))
+                                }
+                            };
+                            if cmd.len() < 3 || cmd.len() > 4 {
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            }
+                            let rev = cmd[0].eq_ignore_ascii_case("zrevrank");
+                            Cmd::Zrank(args[1].clone(), args[2].clone(), rev, withscore)
+                        }
+                        "zcount" => {
+                            if cmd.len() != 4 {
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            }
+                            Cmd::Zcount(args[1].clone(), args[2].clone(), args[3].clone())
+                        }
+                        "zrange" | "zrevrange" | "zrangebyscore" | "zrevrangebyscore"
+                        | "zrangebylex" | "zrevrangebylex" => {
+                            let name = cmd[0].to_ascii_lowercase();
+                            let by = if name.ends_with("byscore") {
+                                RangeBy::Score
+                            } else if name.ends_with("bylex") {
+                                RangeBy::Lex
+                            } else {
+                                RangeBy::Rank
+                            };
+                            let rev = name.starts_with("zrev");
+                            match parse_zrange(&cmd, &args, 1, by, rev, name == "zrange") {
+                                Some((spec, withscores)) => Cmd::Zrange(spec, withscores),
+                                None => return Err(DBError(format!("unsupported cmd {:?}", cmd))),
+                            }
+                        }
+                        "zrangestore" => {
+                            match parse_zrange(&cmd, &args, 2, RangeBy::Rank, false, true) {
+                                Some((spec, false)) => Cmd::Zrangestore(args[1].clone(), spec),
+                                _ => return Err(DBError(format!("unsupported cmd {:?}", cmd))),
+                            }
+                        }
+                        "zpopmin" | "zpopmax" => {
+                            if cmd.len() != 2 && cmd.len() != 3 {
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            }
+                            let min = cmd[0].eq_ignore_ascii_case("zpopmin");
+                            Cmd::Zpop(args[1].clone(), min, cmd.get(2).cloned())
+                        }
+                        "bzpopmin" | "bzpopmax" => {
+                            if cmd.len() < 3 {
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            }
+                            let keys = args[1..cmd.len() - 1].to_vec();
+                            let timeout = cmd[cmd.len() - 1].clone();
+                            Cmd::Bzpop(keys, timeout, cmd[0].eq_ignore_ascii_case("bzpopmin"))
+                        }
+                        "zunion" | "zinter" | "zdiff" | "zunionstore" | "zinterstore"
+                        | "zdiffstore" => {
+                            let name = cmd[0].to_ascii_lowercase();
+                            let op = match &name[..5] {
+                                "zinte" => SetOp::Inter,
+                                "zunio" => SetOp::Union,
+                                _ => SetOp::Diff,
+                            };
+                            let store = name.ends_with("store");
+                            let at = if store { 2 } else { 1 };
+                            match parse_zcombine(&cmd, &args, at, op, store) {
+                                Some((keys, weights, aggregate, withscores)) => Cmd::Zcombine(
+                                    op,
+                                    store.then(|| args[1].clone()),
+                                    keys,
+                                    weights,
+                                    aggregate,
+                                    withscores,
+                                ),
+                                None => return Err(DBError(format!("unsupported cmd {:?}", cmd))),
+                            }
+                        }
+                        "multi" => {
+                            if cmd.len() != 1 {
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            }
+                            Cmd::Multi
+                        }
+                        "exec" => {
+                            if cmd.len() != 1 {
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            }
+                            Cmd::Exec
+                        }
+                        "discard" => Cmd::Discard,
+                        "save" => Cmd::Save,
+                        "bgsave" => Cmd::Bgsave,
+                        "lastsave" => Cmd::Lastsave,
+                        "bgrewriteaof" => Cmd::Bgrewriteaof,
+                        "select" => {
+                            if cmd.len() != 2 {
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            }
+                            Cmd::Select(cmd[1].clone())
+                        }
+                        "move" => {
+                            if cmd.len() != 3 {
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            }
+                            Cmd::Move(args[1].clone(), cmd[2].clone())
+                        }
+                        "swapdb" => {
+                            if cmd.len() != 3 {
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            }
+                            Cmd::SwapDb(cmd[1].clone(), cmd[2].clone())
+                        }
+                        "flushdb" | "flushall" => {
+                            // ASYNC and SYNC are accepted, flushing is always synchronous
+                            let valid_mode = |m: &String| {
+                                m.eq_ignore_ascii_case("async") || m.eq_ignore_ascii_case("sync")
+                            };
+                            if cmd.len() > 2 || !cmd[1..].iter().all(valid_mode) {
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            }
+                            if cmd[0].eq_ignore_ascii_case("flushdb") {
+                                Cmd::FlushDb
+                            } else {
+                                Cmd::FlushAll
+                            }
+                        }
+                        "dbsize" => {
+                            if cmd.len() != 1 {
+                                return Err(DBError(format!("unsupported cmd {:?}", cmd)));
+                            }
+                            Cmd::DbSize
+                        }
+                        "hello" => {
+                            // HELLO [protover [AUTH username password] [SETNAME clientname]]
+                            let mut i = 2;
+                            while i < cmd.len() {
+                                match cmd[i].to_ascii_lowercase().as_str() {
+                                    "auth" if i + 2 < cmd.len() => i += 3,
+                                    "setname" if i + 1 < cmd.len() => i += 2,
+                                    _ => return Err(DBError(format!("unsupported cmd {:?}", cmd))),
+                                }
+                            }
+                            match cmd.get(1) {
+                                Some(v) => Cmd::Hello(Some(v.parse().unwrap_or(0))),
+                                None => Cmd::Hello(None),
+                            }
+                        }
+                        _ => Cmd::Unknow,
+                    },
+                    protocol,
+                ))
+            }
+            _ => Err(DBError(format!("fail to parse as cmd for {:?}", protocol))),
+        }
+    }
+
+    // the commands that may take more memory, refused once it is over maxmemory and nothing can
+    // be evicted, like the denyoom flag of Redis
+    fn denies_oom(&self) -> bool {
+        matches!(
+            self,
+            Cmd::Set(..)
+                | Cmd::Xadd(..)
+                | Cmd::Incrby(..)
+                | Cmd::Incrbyfloat(..)
+                | Cmd::Mset(..)
+                | Cmd::Setnx(..)
+                | Cmd::Append(..)
+                | Cmd::Setrange(..)
+                | Cmd::Lpush(..)
+                | Cmd::Rpush(..)
+                | Cmd::Lset(..)
+                | Cmd::Linsert(..)
+                | Cmd::Lmove(..)
+                | Cmd::Blmove(..)
+                | Cmd::Hset(..)
+                | Cmd::Hmset(..)
+                | Cmd::Hsetnx(..)
+                | Cmd::Hincrby(..)
+                | Cmd::Hincrbyfloat(..)
+                | Cmd::Sadd(..)
+                | Cmd::ScombineStore(..)
+                | Cmd::Zadd(..)
+                | Cmd::Zrangestore(..)
+                | Cmd::Zcombine(_, Some(_), ..)
+        )
+    }
+
+    // the commands that change the dataset, which a replica only takes from its master
+    fn is_write(&self) -> bool {
+        self.denies_oom()
+            || matches!(
+                self,
+                Cmd::Del(..)
+                    | Cmd::Getdel(..)
+                    | Cmd::Getex(..)
+                    | Cmd::Expire(..)
+                    | Cmd::Persist(..)
+                    | Cmd::Move(..)
+                    | Cmd::SwapDb(..)
+                    | Cmd::FlushDb
+                    | Cmd::FlushAll
+                    | Cmd::Lpop(..)
+                    | Cmd::Rpop(..)
+                    | Cmd::Lrem(..)
+                    | Cmd::Ltrim(..)
+                    | Cmd::Blpop(..)
+                    | Cmd::Brpop(..)
+                    | Cmd::Hdel(..)
+                    | Cmd::Hexpire(..)
+                    | Cmd::Hpersist(..)
+                    | Cmd::Srem(..)
+                    | Cmd::Spop(..)
+                    | Cmd::Smove(..)
+                    | Cmd::Zrem(..)
+                    | Cmd::Zpop(..)
+                    | Cmd::Bzpop(..)
+            )
+    }
+
+    // the commands that may wait for another client's push before they reply
+    pub fn may_block(&self) -> bool {
+        matches!(
+            self,
+            Cmd::Blpop(..) | Cmd::Brpop(..) | Cmd::Blmove(..) | Cmd::Bzpop(..)
+        )
+    }
+
+    pub async fn run(
+        &self,
+        server: &mut Server,
+        protocol: Protocol,
+        is_rep_con: bool,
+        queued_cmd: &mut Option<Vec<(Cmd, Protocol)>>,
+    ) -> Result<Protocol, DBError> {
+        // a replica refuses writes from its own clients before anything changes, queued or not
+        if self.is_write() && !server.is_master() && !is_rep_con {
+            return Ok(Protocol::write_on_slave_err());
+        }
+        // return if the command is a write command
+        let p = protocol.clone();
+        if queued_cmd.is_some()
+            && !matches!(self, Cmd::Exec)
+            && !matches!(self, Cmd::Multi)
+            && !matches!(self, Cmd::Discard)
+        {
+            queued_cmd
+                .as_mut()
+                .unwrap()
+                .push((self.clone(), protocol.clone()));
+            return Ok(Protocol::SimpleString("QUEUED".to_string()));
+        }
+        // only a master evicts, the writes of its stream go through on a replica
+        if self.denies_oom() && server.is_master() && !is_rep_con && !server.evict_if_needed().await
+        {
+            return Ok(Protocol::oom_err());
+        }
+        // the blocking commands take the write lock themselves, as they must not hold it while
+        // they wait
+        let _write_order = match self.is_write() && !self.may_block() {
+            true => Some(server.write_lock.clone().lock_owned().await),
+            false => None,
+        };
+        let ret = match self {
+            Cmd::Ping => Ok(Protocol::SimpleString("PONG".to_string())),
+            Cmd::Echo(args) => match args.as_slice() {
+                [s] => Ok(Protocol::BulkString(s.clone())),
+                _ => Ok(Protocol::err(
+                    "ERR wrong number of arguments for 'echo' command",
+                )),
+            },
+            Cmd::Get(k) => get_cmd(server, k).await,
+            Cmd::Set(k, v, options) => set_cmd(server, k, v, options, is_rep_con).await,
+            Cmd::Del(k) => del_cmd(server, k, protocol, is_rep_con).await,
+            Cmd::ConfigGet(name) => config_get_cmd(name, server).await,
+            Cmd::ConfigSet(name, value) => config_set_cmd(name, value, server).await,
+            Cmd::Keys => keys_cmd(server).await,
+            Cmd::Info(section) => info_cmd(section, server).await,
+            Cmd::Replconf(sub_cmd) => replconf_cmd(sub_cmd, server),
+            Cmd::Psync => psync_cmd(server),
+            Cmd::Type(k) => type_cmd(server, k).await,
+            Cmd::Xadd(stream_key, offset, kvps) => {
+                xadd_cmd(
+                    offset.as_str(),
+                    server,
+                    stream_key,
+                    kvps,
+                    protocol,
+                    is_rep_con,
+                )
+                .await
+            }
+
+            Cmd::Xrange(stream_key, start, end) => xrange_cmd(server, stream_key, start, end).await,
+            Cmd::Xread(stream_keys, starts, block) => {
+                xread_cmd(starts, server, stream_keys, block).await
+            }
+            Cmd::Incrby(k, by, decr) => {
+                incrby_cmd(server, k, by, *decr, protocol, is_rep_con).await
+            }
+            Cmd::Incrbyfloat(k, by) => incrbyfloat_cmd(server, k, by, protocol, is_rep_con).await,
+            Cmd::Mget(keys) => mget_cmd(server, keys).await,
+            Cmd::Mset(pairs, nx) => mset_cmd(server, pairs, *nx, protocol, is_rep_con).await,
+            Cmd::Setnx(k, v) => setnx_cmd(server, k, v, protocol, is_rep_con).await,
+            Cmd::Append(k, v) => append_cmd(server, k, v, protocol, is_rep_con).await,
+            Cmd::Getrange(k, start, end) => getrange_cmd(server, k, start, end).await,
+            Cmd::Setrange(k, offset, v) => {
+                setrange_cmd(server, k, offset, v, protocol, is_rep_con).await
+            }
+            Cmd::Strlen(k) => strlen_cmd(server, k).await,
+            Cmd::Getdel(k) => getdel_cmd(server, k, protocol, is_rep_con).await,
+            Cmd::Expire(k, time, format, condition) => {
+                expire_cmd(server, k, time, *format, *condition, is_rep_con).await
+            }
+            Cmd::Ttl(k, format) => ttl_cmd(server, k, *format).await,
+            Cmd::Persist(k) => persist_cmd(server, k, protocol, is_rep_con).await,
+            Cmd::Getex(k, expire, persist) => {
+                getex_cmd(server, k, expire, *persist, is_rep_con).await
+            }
+            Cmd::Lpush(k, vs) => push_cmd(server, k, vs, true, protocol, is_rep_con).await,
+            Cmd::Rpush(k, vs) => push_cmd(server, k, vs, false, protocol, is_rep_con).await,
+            Cmd::Lpop(k, count) => pop_cmd(server, k, count, true, protocol, is_rep_con).await,
+            Cmd::Rpop(k, count) => pop_cmd(server, k, count, false, protocol, is_rep_con).await,
+            Cmd::Lrange(k, start, stop) => lrange_cmd(server, k, start, stop).await,
+            Cmd::Lindex(k, index) => lindex_cmd(server, k, index).await,
+            Cmd::Lset(k, index, v) => lset_cmd(server, k, index, v, protocol, is_rep_con).await,
+            Cmd::Lrem(k, count, v) => lrem_cmd(server, k, count, v, protocol, is_rep_con).await,
+            Cmd::Ltrim(k, start, stop) => {
+                ltrim_cmd(server, k, start, stop, protocol, is_rep_con).await
+            }
+            Cmd::Linsert(k, before, pivot, v) => {
+                linsert_cmd(server, k, *before, pivot, v, protocol, is_rep_con).await
+            }
+            Cmd::Llen(k) => llen_cmd(server, k).await,
+            Cmd::Lmove(src, dst, from_left, to_left) => {
+                lmove_cmd(server, src, dst, *from_left, *to_left, protocol, is_rep_con).await
+            }
+            Cmd::Blpop(keys, timeout) => {
+                blocking_pop_cmd(server, keys, timeout, true, is_rep_con).await
+            }
+            Cmd::Brpop(keys, timeout) => {
+                blocking_pop_cmd(server, keys, timeout, false, is_rep_con).await
+            }
+            Cmd::Blmove(src, dst, from_left, to_left, timeout) => {
+                blmove_cmd(server, src, dst, *from_left, *to_left, timeout, is_rep_con).await
+            }
+            Cmd::Hset(k, pairs) => hset_cmd(server, k, pairs, false, protocol, is_rep_con).await,
+            Cmd::Hmset(k, pairs) => hset_cmd(server, k, pairs, true, protocol, is_rep_con).await,
+            Cmd::Hsetnx(k, field, v) => hsetnx_cmd(server, k, field, v, protocol, is_rep_con).await,
+            Cmd::Hget(k, field) => hget_cmd(server, k, field).await,
+            Cmd::Hmget(k, fields) => hmget_cmd(server, k, fields).await,
+            Cmd::Hdel(k, fields) => hdel_cmd(server, k, fields, protocol, is_rep_con).await,
+            Cmd::Hlen(k) => hlen_cmd(server, k).await,
+            Cmd::Hexists(k, field) => hexists_cmd(server, k, field).await,
+            Cmd::Hgetall(k) => hgetall_cmd(server, k).await,
+            Cmd::Hkeys(k) => hkeys_cmd(server, k, true).await,
+            Cmd::Hvals(k) => hkeys_cmd(server, k, false).await,
+            Cmd::Hstrlen(k, field) => hstrlen_cmd(server, k, field).await,
+            Cmd::Hincrby(k, field, by) => {
+                hincrby_cmd(server, k, field, by, protocol, is_rep_con).await
+            }
+            Cmd::Hincrbyfloat(k, field, by) => {
+                hincrbyfloat_cmd(server, k, field, by, protocol, is_rep_con).await
+            }
+            Cmd::Hscan(k, cursor, options) => hscan_cmd(server, k, cursor, options).await,
+            Cmd::Hexpire(k, time, format, condition, fields) => {
+                hexpire_cmd(server, k, time, *format, *condition, fields, is_rep_con).await
+            }
+            Cmd::Httl(k, format, fields) => httl_cmd(server, k, *format, fields).await,
+            Cmd::Hpersist(k, fields) => hpersist_cmd(server, k, fields, protocol, is_rep_con).await,
+            Cmd::Sadd(k, members) => sadd_cmd(server, k, members, protocol, is_rep_con).await,
+            Cmd::Srem(k, members) => srem_cmd(server, k, members, protocol, is_rep_con).await,
+            Cmd::Sismember(k, member) => {
+                smismember_cmd(server, k, std::slice::from_ref(member), false).await
+            }
+            Cmd::Smismember(k, members) => smismember_cmd(server, k, members, true).await,
+            Cmd::Smembers(k) => smembers_cmd(server, k).await,
+            Cmd::Scard(k) => scard_cmd(server, k).await,
+            Cmd::Scombine(op, keys) => scombine_cmd(server, *op, keys).await,
+            Cmd::ScombineStore(op, dst, keys) => {
+                scombine_store_cmd(server, *op, dst, keys, protocol, is_rep_con).await
+            }
+            Cmd::Sintercard(keys, limit) => sintercard_cmd(server, keys, limit).await,
+            Cmd::Srandmember(k, count) => srandmember_cmd(server, k, count).await,
+            Cmd::Spop(k, count) => spop_cmd(server, k, count, is_rep_con).await,
+            Cmd::Smove(src, dst, member) => {
+                smove_cmd(server, src, dst, member, protocol, is_rep_con).await
+            }
+            Cmd::Sscan(k, cursor, options) => sscan_cmd(server, k, cursor, options).await,
+            Cmd::Zadd(k, flags, pairs) => {
+                zadd_cmd(server, k, *flags, pairs, protocol, is_rep_con).await
+            }
+            Cmd::Zrem(k, members) => zrem_cmd(server, k, members, protocol, is_rep_con).await,
+            Cmd::Zcard(k) => zcard_cmd(server, k).await,
+            Cmd::Zscore(k, member) => zscore_cmd(server, k, member).await,
+            Cmd::Zrank(k, member, rev, withscore) => {
+                zrank_cmd(server, k, member, *rev, *withscore).await
+            }
+            Cmd::Zcount(k, min, max) => zcount_cmd(server, k, min, max).await,
+            Cmd::Zrange(spec, withscores) => zrange_cmd(server, spec, *withscores).await,
+            Cmd::Zrangestore(dst, spec) => {
+                zrangestore_cmd(server, dst, spec, protocol, is_rep_con).await
+            }
+            Cmd::Zpop(k, min, count) => {
+                zpop_cmd(server, k, *min, count, protocol, is_rep_con).await
+            }
+            Cmd::Bzpop(keys, timeout, min) => {
+                bzpop_cmd(server, keys, timeout, *min, is_rep_con).await
+            }
+            Cmd::Zcombine(op, dst, keys, weights, aggregate, withscores) => {
+                zcombine_cmd(
+                    server,
+                    *op,
+                    dst,
+                    keys,
+                    weights,
+                    *aggregate,
+                    *withscores,
+                    protocol,
+                    is_rep_con,
+                )
+                .await
+            }
+            Cmd::Multi => {
+                *queued_cmd = Some(Vec::<(Cmd, Protocol)>::new());
+                Ok(Protocol::ok())
+            }
+            Cmd::Exec => exec_cmd(queued_cmd, server, is_rep_con).await,
+            Cmd::Discard => {
+                if queued_cmd.is_some() {
+                    *queued_cmd = None;
+                    Ok(Protocol::ok())
+                } else {
+                    Ok(Protocol::err("ERR Discard without MULTI"))
+                }
+            }
+            Cmd::Unknow => Ok(Protocol::unknown_cmd_err()),
+            Cmd::Hello(version) => hello_cmd(server, version),
+            Cmd::Save => {
+                // held for the whole save, so a BGSAVE can't write the same temp file meanwhile
+                if server.bgsave_in_progress.swap(true, Ordering::AcqRel) {
+                    return Ok(Protocol::err("ERR Background save already in progress"));
+                }
+                let ret = rdb::save_rdb_file(server).await;
+                server.bgsave_in_progress.store(false, Ordering::Release);
+                ret?;
+                Ok(Protocol::ok())
+            }
+            Cmd::Bgsave => {
+                if rdb::bgsave_rdb_file(server).await {
+                    Ok(Protocol::SimpleString(
+                        "Background saving started".to_string(),
+                    ))
+                } else {
+                    Ok(Protocol::err("ERR Background save already in progress"))
+                }
+            }
+            Cmd::Select(index) => select_cmd(server, index),
+            Cmd::Move(k, db) => move_cmd(server, k, db, protocol, is_rep_con).await,
+            Cmd::SwapDb(a, b) => swapdb_cmd(server, a, b, protocol, is_rep_con).await,
+            Cmd::FlushDb => {
+                server.lock_storage().await.clear();
+                resp_and_replicate(server, Protocol::ok(), protocol, is_rep_con).await
+            }
+            Cmd::FlushAll => {
+                for db in server.dbs.iter() {
+                    db.lock().await.clear();
+                }
+                resp_and_replicate(server, Protocol::ok(), protocol, is_rep_con).await
+            }
+            Cmd::DbSize => Ok(Protocol::Integer(server.lock_storage().await.len() as i64)),
+            Cmd::Bgrewriteaof => {
+                if server.aof.is_none() {
+                    Ok(Protocol::err("ERR AOF is not enabled"))
+                } else if aof::bgrewrite_aof(server).await {
+                    Ok(Protocol::SimpleString(
+                        "Background append only file rewriting started".to_string(),
+                    ))
+                } else {
+                    Ok(Protocol::err(
+                        "ERR Background append only file rewriting already in progress",
+                    ))
+                }
+            }
+            Cmd::Lastsave => Ok(Protocol::Integer(
+                server.last_save.load(Ordering::Relaxed) as i64
+            )),
+        };
+        if ret.is_ok() {
+            server.offset.fetch_add(
+                p.encode().len() as u64,
+                std::sync::atomic::Ordering::Relaxed,
+            );
+        }
+        ret
+    }
+}
+
+async fn exec_cmd(
+    queued_cmd: &mut Option<Vec<(Cmd, Protocol)>>,
+    server: &mut Server,
+    is_rep_con: bool,
+) -> Result<Protocol, DBError> {
+    if let Some(queued) = queued_cmd.take() {
+        let mut vec = Vec::new();
+        server.in_exec = true;
+        let mut ret = Ok(());
+        for (cmd, protocol) in queued {
+            match Box::pin(cmd.run(server, protocol, is_rep_con, &mut None)).await {
+                Ok(res) => vec.push(res),
+                Err(e) => {
+                    ret = Err(e);
+                    break;
+                }
+            }
+        }
+        server.in_exec = false;
+        ret?;
+        Ok(Protocol::Array(vec))
+    } else {
+        Ok(Protocol::err("ERR EXEC without MULTI"))
+    }
+}
+
+fn parse_int(s: &str) -> Result<i64, Protocol> {
+    s.parse().map_err(|_| Protocol::err(NOT_INTEGER_ERR))
+}
+
+// the list stored at `k`, None if the key is missing, or the WRONGTYPE reply
+fn list_mut<'a>(
+    storage: &'a mut Storage,
+    k: &Bytes,
+) -> Result<Option<&'a mut VecDeque<Bytes>>, Protocol> {
+    match storage.get_value_mut(k) {
+        Some(Value::List(list)) => Ok(Some(list)),
+        Some(_) => Err(Protocol::wrong_type_err()),
+        None => Ok(None),
+    }
+}
+
+// an inclusive range with negative indexes counting from the tail, clamped to the list; None
+// when it selects nothing
+fn list_range(len: usize, start: i64, stop: i64) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let start = if start < 0 {
+        (len + start).max(0)
+    } else {
+        start
+    };
+    let stop = if stop < 0 {
+        len + stop
+    } else {
+        stop.min(len - 1)
+    };
+    if start > stop || start >= len {
+        None
+    } else {
+        Some((start as usize, stop as usize))
+    }
+}
+
+fn list_index(len: usize, index: i64) -> Option<usize> {
+    let index = if index < 0 { len as i64 + index } else { index };
+    (0..len as i64).contains(&index).then_some(index as usize)
+}
+
+// push `vs` one by one onto the list at `k`, creating it if missing, and return the new length
+fn push_values(
+    storage: &mut Storage,
+    k: &Bytes,
+    vs: &[Bytes],
+    left: bool,
+) -> Result<usize, Protocol> {
+    let list = match list_mut(storage, k)? {
+        Some(list) => list,
+        None => {
+            storage.insert(k.clone(), Value::List(VecDeque::new()), None);
+            list_mut(storage, k)?.unwrap()
+        }
+    };
+    for v in vs {
+        if left {
+            list.push_front(v.clone());
+        } else {
+            list.push_back(v.clone());
+        }
+    }
+    Ok(list.len())
+}
+
+// pop from `src` and push onto `dst`, None if `src` is missing; a wrong-typed key fails before
+// anything changes
+fn list_move(
+    storage: &mut Storage,
+    src: &Bytes,
+    dst: &Bytes,
+    from_left: bool,
+    to_left: bool,
+) -> Result<Option<Bytes>, Protocol> {
+    if list_mut(storage, src)?.is_none() {
+        return Ok(None);
+    }
+    list_mut(storage, dst)?;
+    let list = list_mut(storage, src)?.unwrap();
+    let v = if from_left {
+        list.pop_front()
+    } else {
+        list.pop_back()
+    };
+    let v = v.unwrap();
+    if list.is_empty() {
+        storage.del(src);
+    }
+    push_values(storage, dst, std::slice::from_ref(&v), to_left)?;
+    Ok(Some(v))
+}
+
+fn list_end(left: bool) -> &'static str {
+    if left {
+        "LEFT"
+    } else {
+        "RIGHT"
+    }
+}
+
+// the LMOVE a move is replicated as, whichever command did it
+fn lmove_protocol(src: &Bytes, dst: &Bytes, from_left: bool, to_left: bool) -> Protocol {
+    Protocol::Array(vec![
+        Protocol::bulk("LMOVE"),
+        Protocol::bulk(src.clone()),
+        Protocol::bulk(dst.clone()),
+        Protocol::bulk(list_end(from_left)),
+        Protocol::bulk(list_end(to_left)),
+    ])
+}
+
+// hand the elements of a list or sorted set that was just written to over to the clients blocked
+// on it, oldest first, and return the commands replicating the pops done for them
+fn serve_blocked(
+    storage: &mut Storage,
+    blocking_keys: &mut BlockingKeys,
+    db: usize,
+    key: &Bytes,
+) -> Vec<Protocol> {
+    let mut ready = VecDeque::from([key.clone()]);
+    let mut served = Vec::new();
+    while let Some(key) = ready.pop_front() {
+        loop {
+            // list and sorted set waiters are only served by their own type
+            let zset = match storage.get_value(&key) {
+                Some(Value::List(list)) if !list.is_empty() => false,
+                Some(Value::ZSet(zset)) if !zset.is_empty() => true,
+                _ => break,
+            };
+            let accepts = |op: &BlockedOp| matches!(op, BlockedOp::ZPop { .. }) == zset;
+            let (op, sender) = match blocking_keys.pop_waiter(db, &key, accepts) {
+                Some(waiter) => waiter,
+                None => break,
+            };
+            // the client is gone, leave the element for the next one
+            if sender.is_closed() {
+                continue;
+            }
+            match op {
+                BlockedOp::Pop { left } => {
+                    let Ok(Some(list)) = list_mut(storage, &key) else {
+                        break;
+                    };
+                    let v = if left {
+                        list.pop_front()
+                    } else {
+                        list.pop_back()
+                    };
+                    if list.is_empty() {
+                        storage.del(&key);
+                    }
+                    let Some(v) = v else {
+                        break;
+                    };
+                    let _ = sender.send(Protocol::Array(vec![
+                        Protocol::bulk(key.clone()),
+                        Protocol::BulkString(v),
+                    ]));
+                    served.push(Protocol::Array(vec![
+                        Protocol::bulk(if left { "LPOP" } else { "RPOP" }),
+                        Protocol::bulk(key.clone()),
+                    ]));
+                }
+                BlockedOp::Move {
+                    dst,
+                    from_left,
+                    to_left,
+                } => match list_move(storage, &key, &dst, from_left, to_left) {
+                    Ok(v) => {
+                        let _ = sender.send(v.map_or(Protocol::Null, Protocol::BulkString));
+                        served.push(lmove_protocol(&key, &dst, from_left, to_left));
+                        // the destination may have clients of its own waiting
+                        if dst != key {
+                            ready.push_back(dst);
+                        }
+                    }
+                    Err(e) => {
+                        let _ = sender.send(e);
+                    }
+                },
+                BlockedOp::ZPop { min } => {
+                    let Ok(Some(zset)) = zset_mut(storage, &key) else {
+                        break;
+                    };
+                    let popped = zpop(zset, min, 1).pop();
+                    if zset.is_empty() {
+                        storage.del(&key);
+                    }
+                    let Some((member, score)) = popped else {
+                        break;
+                    };
+                    let _ = sender.send(Protocol::Array(vec![
+                        Protocol::bulk(key.clone()),
+                        Protocol::BulkString(member),
+                        Protocol::Double(score),
+                    ]));
+                    served.push(Protocol::Array(vec![
+                        Protocol::bulk(if min { "ZPOPMIN" } else { "ZPOPMAX" }),
+                        Protocol::bulk(key.clone()),
+                    ]));
+                }
+            }
+        }
+    }
+    served
+}
+
+// replicate further commands making up a write, like the pops done for blocked clients after
+// the push that served them
+async fn replicate_all(
+    server: &mut Server,
+    protocols: Vec<Protocol>,
+    is_rep_con: bool,
+) -> Result<(), DBError> {
+    for protocol in protocols {
+        resp_and_replicate(server, Protocol::ok(), protocol, is_rep_con).await?;
+    }
+    Ok(())
+}
+
+async fn push_cmd(
+    server: &mut Server,
+    k: &Bytes,
+    vs: &[Bytes],
+    left: bool,
+    protocol: Protocol,
+    is_rep_con: bool,
+) -> Result<Protocol, DBError> {
+    let (len, served) = {
+        let mut storage = server.lock_storage().await;
+        let len = match push_values(&mut storage, k, vs, left) {
+            Ok(len) => len,
+            Err(e) => return Ok(e),
+        };
+        let mut blocking_keys = server.blocking_keys.lock().await;
+        (
+            len,
+            serve_blocked(&mut storage, &mut blocking_keys, server.db_index, k),
+        )
+    };
+    let ret =
+        resp_and_replicate(server, Protocol::Integer(len as i64), protocol, is_rep_con).await?;
+    replicate_all(server, served, is_rep_con).await?;
+    Ok(ret)
+}
+
+async fn lmove_cmd(
+    server: &mut Server,
+    src: &Bytes,
+    dst: &Bytes,
+    from_left: bool,
+    to_left: bool,
+    protocol: Protocol,
+    is_rep_con: bool,
+) -> Result<Protocol, DBError> {
+    let (v, served) = {
+        let mut storage = server.lock_storage().await;
+        match list_move(&mut storage, src, dst, from_left, to_left) {
+            Ok(Some(v)) => {
+                let mut blocking_keys = server.blocking_keys.lock().await;
+                (
+                    v,
+                    serve_blocked(&mut storage, &mut blocking_keys, server.db_index, dst),
+                )
+            }
+            Ok(None) => return Ok(Protocol::Null),
+            Err(e) => return Ok(e),
+        }
+    };
+    let ret = resp_and_replicate(server, Protocol::BulkString(v), protocol, is_rep_con).await?;
+    replicate_all(server, served, is_rep_con).await?;
+    Ok(ret)
+}
+
+// timeout of the blocking list commands in seconds, zero blocks forever
+fn parse_timeout(s: &str) -> Result<Option<Duration>, Protocol> {
+    let timeout = s
+        .parse::<f64>()
+        .ok()
+        .filter(|t| t.is_finite())
+        .ok_or_else(|| Protocol::err("ERR timeout is not a float or out of range"))?;
+    if timeout < 0.0 {
+        return Err(Protocol::err("ERR timeout is negative"));
+    }
+    Ok((timeout > 0.0).then(|| Duration::from_secs_f64(timeout)))
+}
+
+// wait for a push to serve a blocked client, or reply `timeout_reply` once the timeout expires
+async fn wait_blocked(
+    server: &Server,
+    id: u64,
+    mut receiver: oneshot::Receiver<Protocol>,
+    timeout: Option<Duration>,
+    timeout_reply: Protocol,
+) -> Result<Protocol, DBError> {
+    let wait = async {
+        match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, &mut receiver).await.ok(),
+            None => Some((&mut receiver).await),
+        }
+    };
+    // a client that went away stops waiting like on a timeout
+    let reply = tokio::select! {
+        reply = wait => reply,
+        _ = server.client_gone.notified() => None,
+    };
+    if let Some(Ok(reply)) = reply {
+        return Ok(reply);
+    }
+    // a push may have served the client between the timeout and taking the lock
+    if server.blocking_keys.lock().await.unblock(id) {
+        Ok(timeout_reply)
+    } else {
+        Ok(receiver.try_recv().unwrap_or(timeout_reply))
+    }
+}
+
+async fn blocking_pop_cmd(
+    server: &mut Server,
+    keys: &[Bytes],
+    timeout: &str,
+    left: bool,
+    is_rep_con: bool,
+) -> Result<Protocol, DBError> {
+    let timeout = match parse_timeout(timeout) {
+        Ok(timeout) => timeout,
+        Err(e) => return Ok(e),
+    };
+    let write_order = server.write_lock.clone().lock_owned().await;
+    let (id, receiver) = {
+        let mut storage = server.lock_storage().await;
+        let mut popped = None;
+        for k in keys {
+            match list_mut(&mut storage, k) {
+                Ok(Some(list)) => {
+                    let v = if left {
+                        list.pop_front()
+                    } else {
+                        list.pop_back()
+                    };
+                    if list.is_empty() {
+                        storage.del(k);
+                    }
+                    if let Some(v) = v {
+                        popped = Some((k, v));
+                        break;
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => return Ok(e),
+            }
+        }
+        match popped {
+            Some((k, v)) => {
+                drop(storage);
+                // replicated as the plain pop, which never blocks on the replica
+                let pop = Protocol::Array(vec![
+                    Protocol::bulk(if left { "LPOP" } else { "RPOP" }),
+                    Protocol::bulk(k.clone()),
+                ]);
+                let resp =
+                    Protocol::Array(vec![Protocol::bulk(k.clone()), Protocol::BulkString(v)]);
+                return resp_and_replicate(server, resp, pop, is_rep_con).await;
+            }
+            None if server.in_exec || is_rep_con => return Ok(Protocol::NullArray),
+            None => server.blocking_keys.lock().await.block(
+                server.db_index,
+                keys,
+                BlockedOp::Pop { left },
+            ),
+        }
+    };
+    drop(write_order);
+    wait_blocked(server, id, receiver, timeout, Protocol::NullArray).await
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn blmove_cmd(
+    server: &mut Server,
+    src: &Bytes,
+    dst: &Bytes,
+    from_left: bool,
+    to_left: bool,
+    timeout: &str,
+    is_rep_con: bool,
+) -> Result<Protocol, DBError> {
+    let timeout = match parse_timeout(timeout) {
+        Ok(timeout) => timeout,
+        Err(e) => return Ok(e),
+    };
+    let write_order = server.write_lock.clone().lock_owned().await;
+    let (id, receiver) = {
+        let mut storage = server.lock_storage().await;
+        match list_move(&mut storage, src, dst, from_left, to_left) {
+            Ok(Some(v)) => {
+                let served = {
+                    let mut blocking_keys = server.blocking_keys.lock().await;
+                    serve_blocked(&mut storage, &mut blocking_keys, server.db_index, dst)
+                };
+                drop(storage);
+                let lmove = lmove_protocol(src, dst, from_left, to_left);
+                let ret =
+                    resp_and_replicate(server, Protocol::BulkString(v), lmove, is_rep_con).await?;
+                replicate_all(server, served, is_rep_con).await?;
+                return Ok(ret);
+            }
+            Ok(None) if server.in_exec || is_rep_con => return Ok(Protocol::Null),
+            Ok(None) => server.blocking_keys.lock().await.block(
+                server.db_index,
+                std::slice::from_ref(src),
+                BlockedOp::Move {
+                    dst: dst.clone(),
+                    from_left,
+                    to_left,
+                },
+            ),
+            Err(e) => return Ok(e),
+        }
+    };
+    drop(write_order);
+    wait_blocked(server, id, receiver, timeout, Protocol::Null).await
+}
+
+async fn pop_cmd(
+    server: &mut Server,
+    k: &Bytes,
+    count: &Option<String>,
+    left: bool,
+    protocol: Protocol,
+    is_rep_con: bool,
+) -> Result<Protocol, DBError> {
+    let count = match count.as_ref().map(|c| c.parse::<i64>()) {
+        Some(Ok(c)) if c >= 0 => Some(c as usize),
+        Some(_) => return Ok(Protocol::err("ERR value is out of range, must be positive")),
+        None => None,
+    };
+    let popped = {
+        let mut storage = server.lock_storage().await;
+        let list = match list_mut(&mut storage, k) {
+            Ok(Some(list)) => list,
+            Ok(None) if count.is_some() => return Ok(Protocol::NullArray),
+            Ok(None) => return Ok(Protocol::Null),
+            Err(e) => return Ok(e),
+        };
+        let n = count.unwrap_or(1).min(list.len());
+        let popped = (0..n)
+            .filter_map(|_| {
+                if left {
+                    list.pop_front()
+                } else {
+                    list.pop_back()
+                }
+            })
+            .collect::<Vec<_>>();
+        // an emptied list is removed
+        if list.is_empty() {
+            storage.del(k);
+        }
+        popped
+    };
+    let changed = !popped.is_empty();
+    let resp = if count.is_some() {
+        Protocol::Array(popped.into_iter().map(Protocol::BulkString).collect())
+    } else {
+        popped
+            .into_iter()
+            .next()
+            .map_or(Protocol::Null, Protocol::BulkString)
+    };
+    if !changed {
+        return Ok(resp);
+    }
+    resp_and_replicate(server, resp, protocol, is_rep_con).await
+}
+
+async fn lrange_cmd(
+    server: &mut Server,
+    k: &Bytes,
+    start: &str,
+    stop: &str,
+) -> Result<Protocol, DBError> {
+    let (start, stop) = match (parse_int(start), parse_int(stop)) {
+        (Ok(start), Ok(stop)) => (start, stop),
+        (Err(e), _) | (_, Err(e)) => return Ok(e),
+    };
+    let mut storage = server.lock_storage().await;
+    match list_mut(&mut storage, k) {
+        Ok(Some(list)) => Ok(Protocol::Array(match list_range(list.len(), start, stop) {
+            Some((start, stop)) => list
+                .range(start..=stop)
+                .cloned()
+                .map(Protocol::BulkString)
+                .collect(),
+            None => vec![],
+        })),
+        Ok(None) => Ok(Protocol::Array(vec![])),
+        Err(e) => Ok(e),
+    }
+}
+
+async fn lindex_cmd(server: &mut Server, k: &Bytes, index: &str) -> Result<Protocol, DBError> {
+    let index = match parse_int(index) {
+        Ok(index) => index,
+        Err(e) => return Ok(e),
+    };
+    let mut storage = server.lock_storage().await;
+    match list_mut(&mut storage, k) {
+        Ok(Some(list)) => Ok(list_index(list.len(), index)
+            .map_or(Protocol::Null, |i| Protocol::BulkString(list[i].clone()))),
+        Ok(None) => Ok(Protocol::Null),
+        Err(e) => Ok(e),
+    }
+}
+
+async fn lset_cmd(
+    server: &mut Server,
+    k: &Bytes,
+    index: &str,
+    v: &Bytes,
+    protocol: Protocol,
+    is_rep_con: bool,
+) -> Result<Protocol, DBError> {
+    let index = match parse_int(index) {
+        Ok(index) => index,
+        Err(e) => return Ok(e),
+    };
+    {
+        let mut storage = server.lock_storage().await;
+        match list_mut(&mut storage, k) {
+            Ok(Some(list)) => match list_index(list.len(), index) {
+                Some(i) => list[i] = v.clone(),
+                None => return Ok(Protocol::err("ERR index out of range")),
+            },
+            Ok(None) => return Ok(Protocol::err("ERR no such key")),
+            Err(e) => return Ok(e),
+        }
+    }
+    resp_and_replicate(server, Protocol::ok(), protocol, is_rep_con).await
+}
+
+async fn lrem_cmd(
+    server: &mut Server,
+    k: &Bytes,
+    count: &str,
+    v: &Bytes,
+    protocol: Protocol,
+    is_rep_con: bool,
+) -> Result<Protocol, DBError> {
+    let count = match parse_int(count) {
+        Ok(count) => count,
+        Err(e) => return Ok(e),
+    };
+    // a positive count removes from the head, a negative one from the tail, zero removes all
+    let limit = if count == 0 {
+        usize::MAX
+    } else {
+        count.unsigned_abs() as usize
+    };
+    let removed = {
+        let mut storage = server.lock_storage().await;
+        let list = match list_mut(&mut storage, k) {
+            Ok(Some(list)) => list,
+            Ok(None) => return Ok(Protocol::Integer(0)),
+            Err(e) => return Ok(e),
+        };
+        if count < 0 {
+            list.make_contiguous().reverse();
+        }
+        let mut removed = 0;
+        list.retain(|x| {
+            if removed < limit && x == v {
+                removed += 1;
+                false
+            } else {
+                true
+            }
+        });
+        if count < 0 {
+            list.make_contiguous().reverse();
+        }
+        if list.is_empty() {
+            storage.del(k);
+        }
+        removed
+    };
+    if removed == 0 {
+        return Ok(Protocol::Integer(0));
+    }
+    resp_and_replicate(
+        server,
+        Protocol::Integer(removed as i64),
+        protocol,
+        is_rep_con,
+    )
+    .await
+}
+
+async fn ltrim_cmd(
+    server: &mut Server,
+    k: &Bytes,
+    start: &str,
+    stop: &str,
+    protocol: Protocol,
+    is_rep_con: bool,
+) -> Result<Protocol, DBError> {
+    let (start, stop) = match (parse_int(start), parse_int(stop)) {
+        (Ok(start), Ok(stop)) => (start, stop),
+        (Err(e), _) | (_, Err(e)) => return Ok(e),
+    };
+    let changed = {
+        let mut storage = server.lock_storage().await;
+        let list = match list_mut(&mut storage, k) {
+            Ok(Some(list)) => list,
+            Ok(None) => return Ok(Protocol::ok()),
+            Err(e) => return Ok(e),
+        };
+        let len = list.len();
+        match list_range(len, start, stop) {
+            Some((start, stop)) => {
+                list.truncate(stop + 1);
+                list.drain(..start);
+            }
+            None => list.clear(),
+        }
+        let changed = list.len() != len;
+        if list.is_empty() {
+            storage.del(k);
+        }
+        changed
+    };
+    if !changed {
+        return Ok(Protocol::ok());
+    }
+    resp_and_replicate(server, Protocol::ok(), protocol, is_rep_con).await
+}
+
+async fn linsert_cmd(
+    server: &mut Server,
+    k: &Bytes,
+    before: bool,
+    pivot: &Bytes,
+    v: &Bytes,
+    protocol: Protocol,
+    is_rep_con: bool,
+) -> Result<Protocol, DBError> {
+    let len = {
+        let mut storage = server.lock_storage().await;
+        let list = match list_mut(&mut storage, k) {
+            Ok(Some(list)) => list,
+            Ok(None) => return Ok(Protocol::Integer(0)),
+            Err(e) => return Ok(e),
+        };
+        match list.iter().position(|x| x == pivot) {
+            Some(i) => list.insert(if before { i } else { i + 1 }, v.clone()),
+            None => return Ok(Protocol::Integer(-1)),
+        }
+        list.len()
+    };
+    resp_and_replicate(server, Protocol::Integer(len as i64), protocol, is_rep_con).await
+}
+
+async fn llen_cmd(server: &mut Server, k: &Bytes) -> Result<Protocol, DBError> {
+    let mut storage = server.lock_storage().await;
+    match list_mut(&mut storage, k) {
+        Ok(list) => Ok(Protocol::Integer(list.map_or(0, |l| l.len()) as i64)),
+        Err(e) => Ok(e),
+    }
+}
+
+// the hash stored at `k`, None if the key is missing, or the WRONGTYPE reply
+fn hash_mut<'a>(storage: &'a mut Storage, k: &Bytes) -> Result<Option<&'a mut Hash>, Protocol> {
+    match storage.get_value_mut(k) {
+        Some(Value::Hash(hash)) => Ok(Some(hash)),
+        Some(_) => Err(Protocol::wrong_type_err()),
+        None => Ok(None),
+    }
+}
+
+// the hash stored at `k`, created empty if missing; the caller must add a field to it
+fn hash_entry<'a>(storage: &'a mut Storage, k: &Bytes) -> Result<&'a mut Hash, Protocol> {
+    if hash_mut(storage, k)?.is_none() {
+        storage.insert(k.clone(), Value::Hash(Hash::default()), None);
+    }
+    Ok(hash_mut(storage, k)?.unwrap())
+}
+
+async fn hset_cmd(
+    server: &mut Server,
+    k: &Bytes,
+    pairs: &[(Bytes, Bytes)],
+    hmset: bool,
+    protocol: Protocol,
+    is_rep_con: bool,
+) -> Result<Protocol, DBError> {
+    let added = {
+        let mut storage = server.lock_storage().await;
+        let hash = match hash_entry(&mut storage, k) {
+            Ok(hash) => hash,
+            Err(e) => return Ok(e),
+        };
+        pairs
+            .iter()
+            .filter(|(field, v)| hash.insert(field.clone(), v.clone()))
+            .count()
+    };
+    let resp = if hmset {
+        Protocol::ok()
+    } else {
+        Protocol::Integer(added as i64)
+    };
+    resp_and_replicate(server, resp, protocol, is_rep_con).await
+}
+
+async fn hsetnx_cmd(
+    server: &mut Server,
+    k: &Bytes,
+    field: &Bytes,
+    v: &Bytes,
+    protocol: Protocol,
+    is_rep_con: bool,
+) -> Result<Protocol, DBError> {
+    {
+        let mut storage = server.lock_storage().await;
+        let hash = match hash_entry(&mut storage, k) {
+            Ok(hash) => hash,
+            Err(e) => return Ok(e),
+        };
+        if hash.get(field).is_some() {
+            return Ok(Protocol::Integer(0));
+        }
+        hash.insert(field.clone(), v.clone());
+    }
+    resp_and_replicate(server, Protocol::Integer(1), protocol, is_rep_con).await
+}
+
+async fn hget_cmd(server: &mut Server, k: &Bytes, field: &Bytes) -> Result<Protocol, DBError> {
+    let mut storage = server.lock_storage().await;
+    match hash_mut(&mut storage, k) {
+        Ok(hash) => Ok(hash
+            .and_then(|h| h.get(field).cloned())
+            .map_or(Protocol::Null, Protocol::BulkString)),
+        Err(e) => Ok(e),
+    }
+}
+
+async fn hmget_cmd(server: &mut Server, k: &Bytes, fields: &[Bytes]) -> Result<Protocol, DBError> {
+    let mut storage = server.lock_storage().await;
+    let hash = match hash_mut(&mut storage, k) {
+        Ok(hash) => hash,
+        Err(e) => return Ok(e),
+    };
+    Ok(Protocol::Array(
+        fields
+            .iter()
+            .map(|field| {
+                hash.as_ref()
+                    .and_then(|h| h.get(field).cloned())
+                    .map_or(Protocol::Null, Protocol::BulkString)
+            })
+            .collect(),
+    ))
+}
+
+async fn hdel_cmd(
+    server: &mut Server,
+    k: &Bytes,
+    fields: &[Bytes],
+    protocol: Protocol,
+    is_rep_con: bool,
+) -> Result<Protocol, DBError> {
+    let deleted = {
+        let mut storage = server.lock_storage().await;
+        let hash = match hash_mut(&mut storage, k) {
+            Ok(Some(hash)) => hash,
+            Ok(None) => return Ok(Protocol::Integer(0)),
+            Err(e) => return Ok(e),
+        };
+        let deleted = fields.iter().filter(|field| hash.remove(field)).count();
+        if hash.is_empty() {
+            storage.del(k);
+        }
+        deleted
+    };
+    if deleted == 0 {
+        return Ok(Protocol::Integer(0));
+    }
+    resp_and_replicate(
+        server,
+        Protocol::Integer(deleted as i64),
+        protocol,
+        is_rep_con,
+    )
+    .await
+}
+
+async fn hlen_cmd(server: &mut Server, k: &Bytes) -> Result<Protocol, DBError> {
+    let mut storage = server.lock_storage().await;
+    match hash_mut(&mut storage, k) {
+        Ok(hash) => Ok(Protocol::Integer(hash.map_or(0, |h| h.len()) as i64)),
+        Err(e) => Ok(e),
+    }
+}
+
+async fn hexists_cmd(server: &mut Server, k: &Bytes, field: &Bytes) -> Result<Protocol, DBError> {
+    let mut storage = server.lock_storage().await;
+    match hash_mut(&mut storage, k) {
+        Ok(hash) => Ok(Protocol::Integer(
+            hash.is_some_and(|h| h.get(field).is_some()) as i64,
+        )),
+        Err(e) => Ok(e),
+    }
+}
+
+async fn hgetall_cmd(server: &mut Server, k: &Bytes) -> Result<Protocol, DBError> {
+    let mut storage = server.lock_storage().await;
+    match hash_mut(&mut storage, k) {
+        Ok(hash) => Ok(Protocol::Map(hash.map_or(vec![], |h| {
+            h.iter()
+                .map(|(field, v)| {
+                    (
+                        Protocol::BulkString(field.clone()),
+                        Protocol::BulkString(v.clone()),
+                    )
+                })
+                .collect()
+        }))),
+        Err(e) => Ok(e),
+    }
+}
+
+// HKEYS, or HVALS when `keys` is false
+async fn hkeys_cmd(server: &mut Server, k: &Bytes, keys: bool) -> Result<Protocol, DBError> {
+    let mut storage = server.lock_storage().await;
+    match hash_mut(&mut storage, k) {
+        Ok(hash) => Ok(Protocol::Array(hash.map_or(vec![], |h| {
+            h.iter()
+                .map(|(field, v)| Protocol::BulkString(if keys { field } else { v }.clone()))
+                .collect()
+        }))),
+        Err(e) => Ok(e),
+    }
+}
+
+async fn hstrlen_cmd(server: &mut Server, k: &Bytes, field: &Bytes) -> Result<Protocol, DBError> {
+    let mut storage = server.lock_storage().await;
+    match hash_mut(&mut storage, k) {
+        Ok(hash) => Ok(Protocol::Integer(
+            hash.and_then(|h| h.get(field)).map_or(0, |v| v.len()) as i64,
+        )),
+        Err(e) => Ok(e),
+    }
+}
+
+async fn hincrby_cmd(
+    server: &mut Server,
+    k: &Bytes,
+    field: &Bytes,
+    by: &str,
+    protocol: Protocol,
+    is_rep_con: bool,
+) -> Result<Protocol, DBError> {
+    let by = match parse_int(by) {
+        Ok(by) => by,
+        Err(e) => return Ok(e),
+    };
+    let v = {
+        let mut storage = server.lock_storage().await;
+        let hash = match hash_entry(&mut storage, k) {
+            Ok(hash) => hash,
+            Err(e) => return Ok(e),
+        };
+        let current = match hash.get(field) {
+            Some(v) => match std::str::from_utf8(v)
+                .ok()
+                .and_then(|v| v.parse::<i64>().ok())
+            {
+                Some(v) => v,
+                None => return Ok(Protocol::err("ERR hash value is not an integer")),
+            },
+            None => 0,
+        };
+        let v = match current.checked_add(by) {
+            Some(v) => v,
+            None => return Ok(Protocol::err("ERR increment or decrement would overflow")),
+        };
+        // an existing field keeps its TTL
+        match hash.get_mut(field) {
+            Some(slot) => *slot = Bytes::from(v.to_string()),
+            None => {
+                hash.insert(field.clone(), Bytes::from(v.to_string()));
+            }
+        }
+        v
+    };
+    resp_and_replicate(server, Protocol::Integer(v), protocol, is_rep_con).await
+}
+
+// f64 arithmetic like INCRBYFLOAT, with the same loss of precision against Redis' long double
+async fn hincrbyfloat_cmd(
+    server: &mut Server,
+    k: &Bytes,
+    field: &Bytes,
+    by: &str,
+    protocol: Protocol,
+    is_rep_con: bool,
+) -> Result<Protocol, DBError> {
+    let by = match by.parse::<f64>() {
+        Ok(by) if by.is_finite() => by,
+        _ => return Ok(Protocol::err("ERR value is not a valid float")),
+    };
+    let v = {
+        let mut storage = server.lock_storage().await;
+        let hash = match hash_entry(&mut storage, k) {
+            Ok(hash) => hash,
+            Err(e) => return Ok(e),
+        };
+        let current = match hash.get(field) {
+            Some(v) => match std::str::from_utf8(v)
+                .ok()
+                .and_then(|v| v.parse::<f64>().ok())
+            {
+                Some(v) if v.is_finite() => v,
+                _ => return Ok(Protocol::err("ERR hash value is not a float")),
+            },
+            None => 0.0,
+        };
+        let v = current + by;
+        if !v.is_finite() {
+            return Ok(Protocol::err("ERR increment would produce NaN or Infinity"));
+        }
+        let v = Bytes::from(v.to_string());
+        match hash.get_mut(field) {
+            Some(slot) => *slot = v.clone(),
+            None => {
+                hash.insert(field.clone(), v.clone());
+            }
+        }
+        v
+    };
+    resp_and_replicate(server, Protocol::BulkString(v), protocol, is_rep_con).await
+}
+
+// the cursor and the MATCH, COUNT and NOVALUES options of the SCAN family, or the error reply
+fn parse_scan_args(
+    cursor: &str,
+    options: &[String],
+) -> Result<(u64, Option<String>, usize, bool), Protocol> {
+    let cursor = cursor
+        .parse::<u64>()
+        .map_err(|_| Protocol::err("ERR invalid cursor"))?;
+    let (mut pattern, mut count, mut no_values) = (None, 10, false);
+    let mut i = 0;
+    while i < options.len() {
+        match options[i].to_ascii_lowercase().as_str() {
+            "match" if i + 1 < options.len() => {
+                pattern = Some(options[i + 1].clone());
+                i += 2;
+            }
+            "count" if i + 1 < options.len() => {
+                count = match parse_int(&options[i + 1])? {
+                    n if n < 1 => return Err(Protocol::err("ERR syntax error")),
+                    n => n as usize,
+                };
+                i += 2;
+            }
+            "novalues" => {
+                no_values = true;
+                i += 1;
+            }
+            _ => return Err(Protocol::err("ERR syntax error")),
+        }
+    }
+    Ok((cursor, pattern, count, no_values))
+}
+
+async fn hscan_cmd(
+    server: &mut Server,
+    k: &Bytes,
+    cursor: &str,
+    options: &[String],
+) -> Result<Protocol, DBError> {
+    let (cursor, pattern, count, no_values) = match parse_scan_args(cursor, options) {
+        Ok(args) => args,
+        Err(e) => return Ok(e),
+    };
+    let mut storage = server.lock_storage().await;
+    let hash = match hash_mut(&mut storage, k) {
+        Ok(Some(hash)) => hash,
+        Ok(None) => {
+            return Ok(Protocol::Array(vec![
+                Protocol::bulk("0"),
+                Protocol::Array(vec![]),
+            ]))
+        }
+        Err(e) => return Ok(e),
+    };
+    let (next, fields) = hash.scan(cursor, count);
+    let mut items = Vec::new();
+    for (field, v) in fields {
+        if pattern
+            .as_ref()
+            .is_some_and(|p| !glob_match(p.as_bytes(), field))
+        {
+            continue;
+        }
+        items.push(Protocol::BulkString(field.clone()));
+        if !no_values {
+            items.push(Protocol::BulkString(v.clone()));
+        }
+    }
+    Ok(Protocol::Array(vec![
+        Protocol::bulk(next.to_string()),
+        Protocol::Array(items),
+    ]))
+}
+
+// HEXPIRE and friends, replicated as HPEXPIREAT for the fields given a TTL and HDEL for the
+// fields it deleted
+async fn hexpire_cmd(
+    server: &mut Server,
+    k: &Bytes,
+    time: &str,
+    format: TimeFormat,
+    condition: Option<ExpireCondition>,
+    fields: &[Bytes],
+    is_rep_con: bool,
+) -> Result<Protocol, DBError> {
+    let now = now_in_millis();
+    let expire_at = match parse_int(time) {
+        Ok(n) if n >= 0 && format.expire_at(n, now) <= HASH_FIELD_MAX_EXPIRE => {
+            format.expire_at(n, now) as u128
+        }
+        Ok(_) => {
+            return Ok(Protocol::err(
+                "ERR invalid expire time, must be >= 0 and <= 2^48",
+            ))
+        }
+        Err(e) => return Ok(e),
+    };
+    let (codes, set, deleted) = {
+        let mut storage = server.lock_storage().await;
+        let hash = match hash_mut(&mut storage, k) {
+            Ok(Some(hash)) => hash,
+            Ok(None) => return Ok(Protocol::Array(vec![Protocol::Integer(-2); fields.len()])),
+            Err(e) => return Ok(e),
+        };
+        let (mut codes, mut set, mut deleted) = (Vec::new(), Vec::new(), Vec::new());
+        for field in fields {
+            let code = if hash.get(field).is_none() {
+                -2
+            } else if !condition.is_none_or(|c| c.allows(hash.expire_at(field), expire_at)) {
+                0
+            } else if expire_at <= now {
+                hash.remove(field);
+                deleted.push(field.clone());
+                2
+            } else {
+                hash.set_expire(field, expire_at);
+                set.push(field.clone());
+                1
+            };
+            codes.push(Protocol::Integer(code));
+        }
+        if hash.is_empty() {
+            storage.del(k);
+        }
+        (codes, set, deleted)
+    };
+
+    let mut protocols = Vec::new();
+    if !set.is_empty() {
+        let mut args = vec![
+            Protocol::bulk("HPEXPIREAT"),
+            Protocol::bulk(k.clone()),
+            Protocol::bulk(expire_at.to_string()),
+            Protocol::bulk("FIELDS"),
+            Protocol::bulk(set.len().to_string()),
+        ];
+        args.extend(set.into_iter().map(Protocol::BulkString));
+        protocols.push(Protocol::Array(args));
+    }
+    if !deleted.is_empty() {
+        let mut args = vec![Protocol::bulk("HDEL"), Protocol::bulk(k.clone())];
+        args.extend(deleted.into_iter().map(Protocol::BulkString));
+        protocols.push(Protocol::Array(args));
+    }
+    let resp = Protocol::Array(codes);
+    if protocols.is_empty() {
+        return Ok(resp);
+    }
+    let first = protocols.remove(0);
+    let ret = resp_and_replicate(server, resp, first, is_rep_con).await?;
+    replicate_all(server, protocols, is_rep_con).await?;
+    Ok(ret)
+}
+
+async fn httl_cmd(
+    server: &mut Server,
+    k: &Bytes,
+    format: TimeFormat,
+    fields: &[Bytes],
+) -> Result<Protocol, DBError> {
+    let now = now_in_millis();
+    let mut storage = server.lock_storage().await;
+    let hash = match hash_mut(&mut storage, k) {
+        Ok(Some(hash)) => hash,
+        Ok(None) => return Ok(Protocol::Array(vec![Protocol::Integer(-2); fields.len()])),
+        Err(e) => return Ok(e),
+    };
+    Ok(Protocol::Array(
+        fields
+            .iter()
+            .map(|field| {
+                Protocol::Integer(match (hash.get(field), hash.expire_at(field)) {
+                    (None, _) => -2,
+                    (Some(_), None) => -1,
+                    (Some(_), Some(expire_at)) => format.format(expire_at, now),
+                })
+            })
+            .collect(),
+    ))
+}
+
+async fn hpersist_cmd(
+    server: &mut Server,
+    k: &Bytes,
+    fields: &[Bytes],
+    protocol: Protocol,
+    is_rep_con: bool,
+) -> Result<Protocol, DBError> {
+    let codes = {
+        let mut storage = server.lock_storage().await;
+        let hash = match hash_mut(&mut storage, k) {
+            Ok(Some(hash)) => hash,
+            Ok(None) => return Ok(Protocol::Array(vec![Protocol::Integer(-2); fields.len()])),
+            Err(e) => return Ok(e),
+        };
+        fields
+            .iter()
+            .map(|field| {
+                if hash.get(field).is_none() {
+                    -2
+                } else if hash.persist(field) {
+                    1
+                } else {
+                    -1
+                }
+            })
+            .collect::<Vec<_>>()
+    };
+    let resp = Protocol::Array(codes.iter().map(|c| Protocol::Integer(*c)).collect());
+    if !codes.contains(&1) {
+        return Ok(resp);
+    }
+    resp_and_replicate(server, resp, protocol, is_rep_con).await
+}
+
+// the set stored at `k`, None if the key is missing, or the WRONGTYPE reply
+fn set_mut<'a>(storage: &'a mut Storage, k: &Bytes) -> Result<Option<&'a mut Set>, Protocol> {
+    match storage.get_value_mut(k) {
+        Some(Value::Set(set)) => Ok(Some(set)),
+        Some(_) => Err(Protocol::wrong_type_err()),
+        None => Ok(None),
+    }
+}
+
+// a random index below `n`, which must not be zero
+fn random_index(n: usize) -> usize {
+    (RandomState::new().build_hasher().finish() % n as u64) as usize
+}
+
+// `count` members picked at random, all different, or as many as there are
+fn random_distinct(mut members: Vec<Bytes>, count: usize) -> Vec<Bytes> {
+    let count = count.min(members.len());
+    for i in 0..count {
+        let j = i + random_index(members.len() - i);
+        members.swap(i, j);
+    }
+    members.truncate(count);
+    members
+}
+
+async fn sadd_cmd(
+    server: &mut Server,
+    k: &Bytes,
+    members: &[Bytes],
+    protocol: Protocol,
+    is_rep_con: bool,
+) -> Result<Protocol, DBError> {
+    let added = {
+        let mut storage = server.lock_storage().await;
+        let set = match set_mut(&mut storage, k) {
+            Ok(Some(set)) => set,
+            Ok(None) => {
+                storage.insert(k.clone(), Value::Set(Set::default()), None);
+                set_mut(&mut storage, k).unwrap().unwrap()
+            }
+            Err(e) => return Ok(e),
+        };
+        members.iter().filter(|m| set.insert((*m).clone())).count()
+    };
+    if added == 0 {
+        return Ok(Protocol::Integer(0));
+    }
+    resp_and_replicate(
+        server,
+        Protocol::Integer(added as i64),
+        protocol,
+        is_rep_con,
+    )
+    .await
+}
+
+async fn srem_cmd(
+    server: &mut Server,
+    k: &Bytes,
+    members: &[Bytes],
+    protocol: Protocol,
+    is_rep_con: bool,
+) -> Result<Protocol, DBError> {
+    let removed = {
+        let mut storage = server.lock_storage().await;
+        let set = match set_mut(&mut storage, k) {
+            Ok(Some(set)) => set,
+            Ok(None) => return Ok(Protocol::Integer(0)),
+            Err(e) => return Ok(e),
+        };
+        let removed = members.iter().filter(|m| set.remove(m)).count();
+        if set.is_empty() {
+            storage.del(k);
+        }
+        removed
+    };
+    if removed == 0 {
+        return Ok(Protocol::Integer(0));
+    }
+    resp_and_replicate(
+        server,
+        Protocol::Integer(removed as i64),
+        protocol,
+        is_rep_con,
+    )
+    .await
+}
+
+// SMISMEMBER, or SISMEMBER when `multi` is false
+async fn smismember_cmd(
+    server: &mut Server,
+    k: &Bytes,
+    members: &[Bytes],
+    multi: bool,
+) -> Result<Protocol, DBError> {
+    let mut storage = server.lock_storage().await;
+    let set = match set_mut(&mut storage, k) {
+        Ok(set) => set,
+        Err(e) => return Ok(e),
+    };
+    let mut found = members
+        .iter()
+        .map(|m| Protocol::Integer(set.as_ref().is_some_and(|s| s.contains(m)) as i64))
+        .collect::<Vec<_>>();
+    if multi {
+        Ok(Protocol::Array(found))
+    } else {
+        Ok(found.remove(0))
+    }
+}
+
+async fn smembers_cmd(server: &mut Server, k: &Bytes) -> Result<Protocol, DBError> {
+    let mut storage = server.lock_storage().await;
+    match set_mut(&mut storage, k) {
+        Ok(set) => {
+            Ok(Protocol::Set(set.map_or(vec![], |s| {
+                s.iter().map(Protocol::BulkString).collect()
+            })))
+        }
+        Err(e) => Ok(e),
+    }
+}
+
+async fn scard_cmd(server: &mut Server, k: &Bytes) -> Result<Protocol, DBError> {
+    let mut storage = server.lock_storage().await;
+    match set_mut(&mut storage, k) {
+        Ok(set) => Ok(Protocol::Integer(set.map_or(0, |s| s.len()) as i64)),
+        Err(e) => Ok(e),
+    }
+}
+
+// the intersection, union or difference of the sets at `keys`, missing keys being empty sets
+fn combine_sets(storage: &mut Storage, op: SetOp, keys: &[Bytes]) -> Result<Set, Protocol> {
+    // check every key's type up front, and start intersections from the smallest set
+    let mut lens = Vec::with_capacity(keys.len());
+    for k in keys {
+        lens.push(set_mut(storage, k)?.map_or(0, |s| s.len()));
+    }
+    match op {
+        SetOp::Inter => {
+            let (smallest, _) = lens
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, len)| **len)
+                .unwrap();
+            let mut members = match set_mut(storage, &keys[smallest])? {
+                Some(set) => set.iter().collect::<Vec<_>>(),
+                None => return Ok(Set::default()),
+            };
+            for (i, k) in keys.iter().enumerate() {
+                if i == smallest {
+                    continue;
+                }
+                match set_mut(storage, k)? {
+                    Some(set) => members.retain(|m| set.contains(m)),
+                    None => members.clear(),
+                }
+            }
+            Ok(members.into_iter().collect())
+        }
+        SetOp::Union => {
+            let mut result = Set::default();
+            for k in keys {
+                if let Some(set) = set_mut(storage, k)? {
+                    for m in set.iter() {
+                        result.insert(m);
+                    }
+                }
+            }
+            Ok(result)
+        }
+        SetOp::Diff => {
+            let mut members = match set_mut(storage, &keys[0])? {
+                Some(set) => set.iter().collect::<Vec<_>>(),
+                None => return Ok(Set::default()),
+            };
+            for k in &keys[1..] {
+                if let Some(set) = set_mut(storage, k)? {
+                    members.retain(|m| !set.contains(m));
+                }
+            }
+            Ok(members.into_iter().collect())
+        }
+    }
+}
+
+async fn scombine_cmd(server: &mut Server, op: SetOp, keys: &[Bytes]) -> Result<Protocol, DBError> {
+    let mut storage = server.lock_storage().await;
+    match combine_sets(&mut storage, op, keys) {
+        Ok(set) => Ok(Protocol::Set(
+            set.iter().map(Protocol::BulkString).collect(),
+        )),
+        Err(e) => Ok(e),
+    }
+}
+
+async fn scombine_store_cmd(
+    server: &mut Server,
+    op: SetOp,
+    dst: &Bytes,
+    keys: &[Bytes],
+    protocol: Protocol,
+    is_rep_con: bool,
+) -> Result<Protocol, DBError> {
+    let len = {
+        let mut storage = server.lock_storage().await;
+        let set = match combine_sets(&mut storage, op, keys) {
+            Ok(set) => set,
+            Err(e) => return Ok(e),
+        };
+        let len = set.len();
+        // the destination is overwritten whatever it held, and an empty result deletes it
+        if set.is_empty() {
+            storage.del(dst);
+        } else {
+            storage.insert(dst.clone(), Value::Set(set), None);
+        }
+        len
+    };
+    resp_and_replicate(server, Protocol::Integer(len as i64), protocol, is_rep_con).await
+}
+
+async fn sintercard_cmd(
+    server: &mut Server,
+    keys: &[Bytes],
+    limit: &Option<String>,
+) -> Result<Protocol, DBError> {
+    // a zero limit means no limit
+    let limit = match limit.as_deref().map(parse_int) {
+        Some(Ok(limit)) if limit < 0 => return Ok(Protocol::err("ERR LIMIT can't be negative")),
+        Some(Ok(limit)) if limit > 0 => limit as usize,
+        Some(Err(e)) => return Ok(e),
+        _ => usize::MAX,
+    };
+    let mut storage = server.lock_storage().await;
+    match combine_sets(&mut storage, SetOp::Inter, keys) {
+        Ok(set) => Ok(Protocol::Integer(set.len().min(limit) as i64)),
+        Err(e) => Ok(e),
+    }
+}
+
+async fn srandmember_cmd(
+    server: &mut Server,
+    k: &Bytes,
+    count: &Option<String>,
+) -> Result<Protocol, DBError> {
+    let count = match count.as_deref().map(parse_int) {
+        Some(Ok(count)) => Some(count),
+        Some(Err(e)) => return Ok(e),
+        None => None,
+    };
+    let mut storage = server.lock_storage().await;
+    let members = match set_mut(&mut storage, k) {
+        Ok(Some(set)) => set.iter().collect::<Vec<_>>(),
+        Ok(None) if count.is_some() => return Ok(Protocol::Array(vec![])),
+        Ok(None) => return Ok(Protocol::Null),
+        Err(e) => return Ok(e),
+    };
+    let picked = match count {
+        None => {
+            return Ok(Protocol::BulkString(
+                members[random_index(members.len())].clone(),
+            ))
+        }
+        // a negative count may return the same member more than once
+        Some(count) if count < 0 => (0..count.unsigned_abs())
+            .map(|_| members[random_index(members.len())].clone())
+            .collect(),
+        Some(count) => random_distinct(members, count as usize),
+    };
+    Ok(Protocol::Array(
+        picked.into_iter().map(Protocol::BulkString).collect(),
+    ))
+}
+
+// replicated as SREM of the members that were picked, so replicas pop the same ones
+async fn spop_cmd(
+    server: &mut Server,
+    k: &Bytes,
+    count: &Option<String>,
+    is_rep_con: bool,
+) -> Result<Protocol, DBError> {
+    let count = match count.as_ref().map(|c| c.parse::<i64>()) {
+        Some(Ok(c)) if c >= 0 => Some(c as usize),
+        Some(_) => return Ok(Protocol::err("ERR value is out of range, must be positive")),
+        None => None,
+    };
+    let popped = {
+        let mut storage = server.lock_storage().await;
+        let set = match set_mut(&mut storage, k) {
+            Ok(Some(set)) => set,
+            Ok(None) if count.is_some() => return Ok(Protocol::Array(vec![])),
+            Ok(None) => return Ok(Protocol::Null),
+            Err(e) => return Ok(e),
+        };
+        let popped = random_distinct(set.iter().collect(), count.unwrap_or(1));
+        for m in &popped {
+            set.remove(m);
+        }
+        if set.is_empty() {
+            storage.del(k);
+        }
+        popped
+    };
+    let resp = match count {
+        Some(_) => Protocol::Array(popped.iter().cloned().map(Protocol::BulkString).collect()),
+        None => Protocol::BulkString(popped[0].clone()),
+    };
+    if popped.is_empty() {
+        return Ok(resp);
+    }
+    let mut srem = vec![Protocol::bulk("SREM"), Protocol::bulk(k.clone())];
+    srem.extend(popped.into_iter().map(Protocol::BulkString));
+    resp_and_replicate(server, resp, Protocol::Array(srem), is_rep_con).await
+}
+
+async fn smove_cmd(
+    server: &mut Server,
+    src: &Bytes,
+    dst: &Bytes,
+    member: &Bytes,
+    protocol: Protocol,
+    is_rep_con: bool,
+) -> Result<Protocol, DBError> {
+    {
+        let mut storage = server.lock_storage().await;
+        if let Err(e) = set_mut(&mut storage, dst) {
+            return Ok(e);
+        }
+        let set = match set_mut(&mut storage, src) {
+            Ok(Some(set)) => set,
+            Ok(None) => return Ok(Protocol::Integer(0)),
+            Err(e) => return Ok(e),
+        };
+        if src == dst {
+            return Ok(Protocol::Integer(set.contains(member) as i64));
+        }
+        if !set.remove(member) {
+            return Ok(Protocol::Integer(0));
+        }
+        if set.is_empty() {
+            storage.del(src);
+        }
+        match set_mut(&mut storage, dst) {
+            Ok(Some(set)) => {
+                set.insert(member.clone());
+            }
+            _ => storage.insert(
+                dst.clone(),
+                Value::Set(std::iter::once(member.clone()).collect()),
+                None,
+            ),
+        }
+    }
+    resp_and_replicate(server, Protocol::Integer(1), protocol, is_rep_con).await
+}
+
+async fn sscan_cmd(
+    server: &mut Server,
+    k: &Bytes,
+    cursor: &str,
+    options: &[String],
+) -> Result<Protocol, DBError> {
+    let (cursor, pattern, count) = match parse_scan_args(cursor, options) {
+        Ok((_, _, _, true)) => return Ok(Protocol::err("ERR syntax error")),
+        Ok((cursor, pattern, count, false)) => (cursor, pattern, count),
+        Err(e) => return Ok(e),
+    };
+    let mut storage = server.lock_storage().await;
+    let (next, members) = match set_mut(&mut storage, k) {
+        Ok(set) => set.map_or((0, vec![]), |s| s.scan(cursor, count)),
+        Err(e) => return Ok(e),
+    };
+    Ok(Protocol::Array(vec![
+        Protocol::bulk(next.to_string()),
+        Protocol::Array(
+            members
+                .into_iter()
+                .filter(|m| pattern.as_ref().is_none_or(|p| glob_match(p.as_bytes(), m)))
+                .map(Protocol::BulkString)
+                .collect(),
+        ),
+    ]))
+}
+
+// the sorted set stored at `k`, None if the key is missing, or the WRONGTYPE reply
+fn zset_mut<'a>(storage: &'a mut Storage, k: &Bytes) -> Result<Option<&'a mut ZSet>, Protocol> {
+    match storage.get_value_mut(k) {
+        Some(Value::ZSet(zset)) => Ok(Some(zset)),
+        Some(_) => Err(Protocol::wrong_type_err()),
+        None => Ok(None),
+    }
+}
+
+// a score argument, which may be inf or -inf but not nan
+fn parse_score(s: &str) -> Result<f64, Protocol> {
+    s.parse::<f64>()
+        .ok()
+        .filter(|score| !score.is_nan())
+        .ok_or_else(|| Protocol::err("ERR value is not a valid float"))
+}
+
+// a BYSCORE bound: a score, exclusive when prefixed with `(`
+fn parse_score_bound(s: &[u8]) -> Result<Bound<f64>, Protocol> {
+    let (s, exclusive) = match s.strip_prefix(b"(") {
+        Some(s) => (s, true),
+        None => (s, false),
+    };
+    let score = std::str::from_utf8(s)
+        .ok()
+        .and_then(|s| s.parse::<f64>().ok())
+        .filter(|score| !score.is_nan())
+        .ok_or_else(|| Protocol::err("ERR min or max is not a float"))?;
+    Ok(if exclusive {
+        Bound::Excluded(score)
+    } else {
+        Bound::Included(score)
+    })
+}
+
+// the ranks `start..end` of the elements with scores between `min` and `max`
+fn score_ranks(zset: &ZSet, min: &[u8], max: &[u8]) -> Result<(usize, usize), Protocol> {
+    let (min, max) = (parse_score_bound(min)?, parse_score_bound(max)?);
+    let start = zset.count_while(|score, _| match min {
+        Bound::Included(min) => score < min,
+        Bound::Excluded(min) => score <= min,
+        Bound::Unbounded => false,
+    });
+    let end = zset.count_while(|score, _| match max {
+        Bound::Included(max) => score <= max,
+        Bound::Excluded(max) => score < max,
+        Bound::Unbounded => true,
+    });
+    Ok((start, end.max(start)))
+}
+
+// the ranks `start..end` of the members between the BYLEX bounds `min` and `max`: `-` and `+`
+// for the lowest and highest member, or a member prefixed with `[` or `(` when exclusive
+fn lex_ranks(zset: &ZSet, min: &[u8], max: &[u8]) -> Result<(usize, usize), Protocol> {
+    let invalid = || Protocol::err("ERR min or max not valid string range item");
+    let start = match min {
+        b"-" => 0,
+        b"+" => zset.len(),
+        [b'[', min @ ..] => zset.count_while(|_, member| member < min),
+        [b'(', min @ ..] => zset.count_while(|_, member| member <= min),
+        _ => return Err(invalid()),
+    };
+    let end = match max {
+        b"-" => 0,
+        b"+" => zset.len(),
+        [b'[', max @ ..] => zset.count_while(|_, member| member <= max),
+        [b'(', max @ ..] => zset.count_while(|_, member| member < max),
+        _ => return Err(invalid()),
+    };
+    Ok((start, end.max(start)))
+}
+
+// the elements a ZRANGE selects, in the order it returns them
+fn zrange_select(zset: &ZSet, spec: &ZrangeSpec) -> Result<Vec<(Bytes, f64)>, Protocol> {
+    // the ascending ranks `start..end` the range covers before LIMIT
+    let (start, end) = match spec.by {
+        RangeBy::Rank => {
+            let len = zset.len() as i64;
+            let parse = |b: &Bytes| parse_int(&String::from_utf8_lossy(b));
+            let (start, stop) = (parse(&spec.start)?, parse(&spec.stop)?);
+            let start = if start < 0 { start + len } else { start }.max(0);
+            let stop = if stop < 0 { stop + len } else { stop }.min(len - 1);
+            if start > stop {
+                (0, 0)
+            } else if spec.rev {
+                ((len - 1 - stop) as usize, (len - start) as usize)
+            } else {
+                (start as usize, stop as usize + 1)
+            }
+        }
+        RangeBy::Score if spec.rev => score_ranks(zset, &spec.stop, &spec.start)?,
+        RangeBy::Score => score_ranks(zset, &spec.start, &spec.stop)?,
+        RangeBy::Lex if spec.rev => lex_ranks(zset, &spec.stop, &spec.start)?,
+        RangeBy::Lex => lex_ranks(zset, &spec.start, &spec.stop)?,
+    };
+    // LIMIT counts from the end the range is returned from, a negative count meaning all
+    let (start, end) = match &spec.limit {
+        Some((offset, count)) => {
+            let (offset, count) = (parse_int(offset)?, parse_int(count)?);
+            if offset < 0 {
+                return Ok(vec![]);
+            }
+            let count = if count < 0 {
+                usize::MAX
+            } else {
+                count as usize
+            };
+            if spec.rev {
+                let end = end.saturating_sub(offset as usize).max(start);
+                (end.saturating_sub(count).max(start), end)
+            } else {
+                let start = start.saturating_add(offset as usize).min(end);
+                (start, start.saturating_add(count).min(end))
+            }
+        }
+        None => (start, end),
+    };
+    let range = zset
+        .range(start, end)
+        .map(|(member, score)| (member.clone(), score));
+    Ok(if spec.rev {
+        range.rev().collect()
+    } else {
+        range.collect()
+    })
+}
+
+// members, or members and their scores: flat in RESP2, as pairs in RESP3
+fn scored_reply(server: &Server, items: Vec<(Bytes, f64)>, withscores: bool) -> Protocol {
+    let mut reply = Vec::with_capacity(items.len() * if withscores { 2 } else { 1 });
+    for (member, score) in items {
+        if !withscores {
+            reply.push(Protocol::BulkString(member));
+        } else if server.resp_version == RESP3 {
+            reply.push(Protocol::Array(vec![
+                Protocol::BulkString(member),
+                Protocol::Double(score),
+            ]));
+        } else {
+            reply.push(Protocol::BulkString(member));
+            reply.push(Protocol::Double(score));
+        }
+    }
+    Protocol::Array(reply)
+}
+
+// remove and return up to `count` of the lowest or the highest elements, in that order
+fn zpop(zset: &mut ZSet, min: bool, count: usize) -> Vec<(Bytes, f64)> {
+    let len = zset.len();
+    let count = count.min(len);
+    let range = if min {
+        zset.range(0, count)
+    } else {
+        zset.range(len - count, len)
+    };
+    let range = range.map(|(member, score)| (member.clone(), score));
+    let popped: Vec<_> = if min {
+        range.collect()
+    } else {
+        range.rev().collect()
+    };
+    for (member, _) in &popped {
+        zset.remove(member);
+    }
+    popped
+}
+
+// overwrite `dst` with the result of a STORE command, deleting it when the result is empty, and
+// return the commands replicating the pops done for clients blocked on it
+fn store_zset(
+    storage: &mut Storage,
+    blocking_keys: &mut BlockingKeys,
+    db: usize,
+    dst: &Bytes,
+    zset: ZSet,
+) -> Vec<Protocol> {
+    if zset.is_empty() {
+        storage.del(dst);
+        return vec![];
+    }
+    storage.insert(dst.clone(), Value::ZSet(zset), None);
+    serve_blocked(storage, blocking_keys, db, dst)
+}
+
+async fn zadd_cmd(
+    server: &mut Server,
+    k: &Bytes,
+    flags: ZaddFlags,
+    pairs: &[(String, Bytes)],
+    protocol: Protocol,
+    is_rep_con: bool,
+) -> Result<Protocol, DBError> {
+    if flags.nx && flags.xx {
+        return Ok(Protocol::err(
+            "ERR XX and NX options at the same time are not compatible",
+        ));
+    }
+    if [flags.nx, flags.gt, flags.lt]
+        .iter()
+        .filter(|f| **f)
+        .count()
+        > 1
+    {
+        return Ok(Protocol::err(
+            "ERR GT, LT, and/or NX options at the same time are not compatible",
+        ));
+    }
+    if flags.incr && pairs.len() > 1 {
+        return Ok(Protocol::err(
+            "ERR INCR option supports a single increment-element pair",
+        ));
+    }
+    let mut scores = Vec::with_capacity(pairs.len());
+    for (score, member) in pairs {
+        match parse_score(score) {
+            Ok(score) => scores.push((score, member)),
+            Err(e) => return Ok(e),
+        }
+    }
+    let (resp, changed, served) = {
+        let mut storage = server.lock_storage().await;
+        let zset = match zset_mut(&mut storage, k) {
+            Ok(Some(zset)) => zset,
+            Ok(None) if flags.xx => {
+                return Ok(if flags.incr {
+                    Protocol::Null
+                } else {
+                    Protocol::Integer(0)
+                });
+            }
+            Ok(None) => {
+                storage.insert(k.clone(), Value::ZSet(ZSet::default()), None);
+                zset_mut(&mut storage, k).unwrap().unwrap()
+            }
+            Err(e) => return Ok(e),
+        };
+        let (mut added, mut updated) = (0, 0);
+        let mut incr_score = None;
+        for (score, member) in scores {
+            let current = zset.score(member);
+            let score = match current {
+                Some(current) if flags.incr => current + score,
+                _ => score,
+            };
+            if score.is_nan() {
+                return Ok(Protocol::err("ERR resulting score is not a number (NaN)"));
+            }
+            match current {
+                None if flags.xx => continue,
+                None => added += 1,
+                Some(_) if flags.nx => continue,
+                Some(current) => {
+                    if (flags.gt && score <= current) || (flags.lt && score >= current) {
+                        continue;
+                    }
+                    if score != current {
+                        updated += 1;
+                    }
+                }
+            }
+            zset.insert(member.clone(), score);
+            incr_score = Some(score);
+        }
+        if zset.is_empty() {
+            storage.del(k);
+        }
+        let resp = if flags.incr {
+            incr_score.map_or(Protocol::Null, Protocol::Double)
+        } else if flags.ch {
+            Protocol::Integer(added + updated)
+        } else {
+            Protocol::Integer(added)
+        };
+        let served = if added > 0 {
+            let mut blocking_keys = server.blocking_keys.lock().await;
+            serve_blocked(&mut storage, &mut blocking_keys, server.db_index, k)
+        } else {
+            vec![]
+        };
+        (resp, added + updated > 0, served)
+    };
+    if !changed {
+        return Ok(resp);
+    }
+    let ret = resp_and_replicate(server, resp, protocol, is_rep_con).await?;
+    replicate_all(server, served, is_rep_con).await?;
+    Ok(ret)
+}
+
+async fn zrem_cmd(
+    server: &mut Server,
+    k: &Bytes,
+    members: &[Bytes],
+    protocol: Protocol,
+    is_rep_con: bool,
+) -> Result<Protocol, DBError> {
+    let removed = {
+        let mut storage = server.lock_storage().await;
+        let zset = match zset_mut(&mut storage, k) {
+            Ok(Some(zset)) => zset,
+            Ok(None) => return Ok(Protocol::Integer(0)),
+            Err(e) => return Ok(e),
+        };
+        let removed = members.iter().filter(|m| zset.remove(m)).count();
+        if zset.is_empty() {
+            storage.del(k);
+        }
+        removed
+    };
+    if removed == 0 {
+        return Ok(Protocol::Integer(0));
+    }
+    resp_and_replicate(
+        server,
+        Protocol::Integer(removed as i64),
+        protocol,
+        is_rep_con,
+    )
+    .await
+}
+
+async fn zcard_cmd(server: &mut Server, k: &Bytes) -> Result<Protocol, DBError> {
+    let mut storage = server.lock_storage().await;
+    match zset_mut(&mut storage, k) {
+        Ok(zset) => Ok(Protocol::Integer(zset.map_or(0, |z| z.len()) as i64)),
+        Err(e) => Ok(e),
+    }
+}
+
+async fn zscore_cmd(server: &mut Server, k: &Bytes, member: &Bytes) -> Result<Protocol, DBError> {
+    let mut storage = server.lock_storage().await;
+    match zset_mut(&mut storage, k) {
+        Ok(zset) => Ok(zset
+            .and_then(|z| z.score(member))
+            .map_or(Protocol::Null, Protocol::Double)),
+        Err(e) => Ok(e),
+    }
+}
+
+async fn zrank_cmd(
+    server: &mut Server,
+    k: &Bytes,
+    member: &Bytes,
+    rev: bool,
+    withscore: bool,
+) -> Result<Protocol, DBError> {
+    let mut storage = server.lock_storage().await;
+    let zset = match zset_mut(&mut storage, k) {
+        Ok(zset) => zset,
+        Err(e) => return Ok(e),
+    };
+    let rank = zset.and_then(|z| Some((z.rank(member)?, z.len(), z.score(member)?)));
+    Ok(match rank {
+        Some((rank, len, score)) => {
+            let rank = Protocol::Integer(if rev { len - 1 - rank } else { rank } as i64);
+            if withscore {
+                Protocol::Array(vec![rank, Protocol::Double(score)])
+            } else {
+                rank
+            }
+        }
+        None if withscore => Protocol::NullArray,
+        None => Protocol::Null,
+    })
+}
+
+async fn zcount_cmd(
+    server: &mut Server,
+    k: &Bytes,
+    min: &Bytes,
+    max: &Bytes,
+) -> Result<Protocol, DBError> {
+    let mut storage = server.lock_storage().await;
+    let zset = match zset_mut(&mut storage, k) {
+        Ok(Some(zset)) => zset,
+        Ok(None) => &ZSet::default(),
+        Err(e) => return Ok(e),
+    };
+    match score_ranks(zset, min, max) {
+        Ok((start, end)) => Ok(Protocol::Integer((end - start) as i64)),
+        Err(e) => Ok(e),
+    }
+}
+
+async fn zrange_cmd(
+    server: &mut Server,
+    spec: &ZrangeSpec,
+    withscores: bool,
+) -> Result<Protocol, DBError> {
+    let items = {
+        let mut storage = server.lock_storage().await;
+        let zset = match zset_mut(&mut storage, &spec.key) {
+            Ok(Some(zset)) => zset,
+            Ok(None) => &ZSet::default(),
+            Err(e) => return Ok(e),
+        };
+        match zrange_select(zset, spec) {
+            Ok(items) => items,
+            Err(e) => return Ok(e),
+        }
+    };
+    Ok(scored_reply(server, items, withscores))
+}
+
+async fn zrangestore_cmd(
+    server: &mut Server,
+    dst: &Bytes,
+    spec: &ZrangeSpec,
+    protocol: Protocol,
+    is_rep_con: bool,
+) -> Result<Protocol, DBError> {
+    let (len, served) = {
+        let mut storage = server.lock_storage().await;
+        let zset = match zset_mut(&mut storage, &spec.key) {
+            Ok(Some(zset)) => zset,
+            Ok(None) => &ZSet::default(),
+            Err(e) => return Ok(e),
+        };
+        let zset = match zrange_select(zset, spec) {
+            Ok(items) => items.into_iter().collect::<ZSet>(),
+            Err(e) => return Ok(e),
+        };
+        let len = zset.len();
+        let mut blocking_keys = server.blocking_keys.lock().await;
+        (
+            len,
+            store_zset(&mut storage, &mut blocking_keys, server.db_index, dst, zset),
+        )
+    };
+    let ret =
+        resp_and_replicate(server, Protocol::Integer(len as i64), protocol, is_rep_con).await?;
+    replicate_all(server, served, is_rep_con).await?;
+    Ok(ret)
+}
+
+async fn zpop_cmd(
+    server: &mut Server,
+    k: &Bytes,
+    min: bool,
+    count: &Option<String>,
+    protocol: Protocol,
+    is_rep_con: bool,
+) -> Result<Protocol, DBError> {
+    let count = match count.as_ref().map(|c| c.parse::<i64>()) {
+        Some(Ok(c)) if c >= 0 => Some(c as usize),
+        Some(_) => return Ok(Protocol::err("ERR value is out of range, must be positive")),
+        None => None,
+    };
+    let popped = {
+        let mut storage = server.lock_storage().await;
+        let zset = match zset_mut(&mut storage, k) {
+            Ok(Some(zset)) => zset,
+            Ok(None) => return Ok(Protocol::Array(vec![])),
+            Err(e) => return Ok(e),
+        };
+        let popped = zpop(zset, min, count.unwrap_or(1));
+        if zset.is_empty() {
+            storage.del(k);
+        }
+        popped
+    };
+    if popped.is_empty() {
+        return Ok(Protocol::Array(vec![]));
+    }
+    // a single pop without a count is a flat member and score in RESP3 too
+    let resp = match count {
+        Some(_) => scored_reply(server, popped, true),
+        None => {
+            let (member, score) = popped.into_iter().next().unwrap();
+            Protocol::Array(vec![Protocol::BulkString(member), Protocol::Double(score)])
+        }
+    };
+    resp_and_replicate(server, resp, protocol, is_rep_con).await
+}
+
+async fn bzpop_cmd(
+    server: &mut Server,
+    keys: &[Bytes],
+    timeout: &str,
+    min: bool,
+    is_rep_con: bool,
+) -> Result<Protocol, DBError> {
+    let timeout = match parse_timeout(timeout) {
+        Ok(timeout) => timeout,
+        Err(e) => return Ok(e),
+    };
+    let write_order = server.write_lock.clone().lock_owned().await;
+    let (id, receiver) = {
+        let mut storage = server.lock_storage().await;
+        let mut popped = None;
+        for k in keys {
+            match zset_mut(&mut storage, k) {
+                Ok(Some(zset)) => {
+                    let first = zpop(zset, min, 1).pop();
+                    if zset.is_empty() {
+                        storage.del(k);
+                    }
+                    if let Some((member, score)) = first {
+                        popped = Some((k, member, score));
+                        break;
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => return Ok(e),
+            }
+        }
+        match popped {
+            Some((k, member, score)) => {
+                drop(storage);
+                // replicated as the plain pop, which never blocks on the replica
+                let pop = Protocol::Array(vec![
+                    Protocol::bulk(if min { "ZPOPMIN" } else { "ZPOPMAX" }),
+                    Protocol::bulk(k.clone()),
+                ]);
+                let resp = Protocol::Array(vec![
+                    Protocol::bulk(k.clone()),
+                    Protocol::BulkString(member),
+                    Protocol::Double(score),
+                ]);
+                return resp_and_replicate(server, resp, pop, is_rep_con).await;
+            }
+            None if server.in_exec || is_rep_con => return Ok(Protocol::NullArray),
+            None => server.blocking_keys.lock().await.block(
+                server.db_index,
+                keys,
+                BlockedOp::ZPop { min },
+            ),
+        }
+    };
+    drop(write_order);
+    wait_blocked(server, id, receiver, timeout, Protocol::NullArray).await
+}
+
+// a member's score weighted, where 0 times infinity counts as 0
+fn weighted(score: f64, weight: f64) -> f64 {
+    let score = score * weight;
+    if score.is_nan() {
+        0.0
+    } else {
+        score
+    }
+}
+
+// the union, intersection or difference of the sorted sets at `keys`. Plain sets count as sorted
+// sets with every score 1, and missing keys as empty sets
+fn combine_zsets(
+    storage: &mut Storage,
+    op: SetOp,
+    keys: &[Bytes],
+    weights: &[f64],
+    aggregate: Aggregate,
+) -> Result<ZSet, Protocol> {
+    let mut inputs = Vec::with_capacity(keys.len());
+    for (k, weight) in keys.iter().zip(weights) {
+        let scores = match storage.get_value(k) {
+            Some(Value::ZSet(zset)) => zset
+                .iter()
+                .map(|(member, score)| (member.clone(), weighted(score, *weight)))
+                .collect::<HashMap<_, _>>(),
+            Some(Value::Set(set)) => set.iter().map(|m| (m, weighted(1.0, *weight))).collect(),
+            Some(_) => return Err(Protocol::wrong_type_err()),
+            None => HashMap::new(),
+        };
+        inputs.push(scores);
+    }
+    let combine = |a: f64, b: f64| match aggregate {
+        // inf plus -inf counts as 0
+        Aggregate::Sum => weighted(a + b, 1.0),
+        Aggregate::Min => a.min(b),
+        Aggregate::Max => a.max(b),
+    };
+    let mut result = HashMap::new();
+    match op {
+        SetOp::Union => {
+            for scores in inputs {
+                for (member, score) in scores {
+                    result
+                        .entry(member)
+                        .and_modify(|s| *s = combine(*s, score))
+                        .or_insert(score);
+                }
+            }
+        }
+        SetOp::Inter => {
+            let (first, rest) = inputs.split_first().unwrap();
+            'members: for (member, score) in first {
+                let mut score = *score;
+                for scores in rest {
+                    match scores.get(member) {
+                        Some(s) => score = combine(score, *s),
+                        None => continue 'members,
+                    }
+                }
+                result.insert(member.clone(), score);
+            }
+        }
+        SetOp::Diff => {
+            let (first, rest) = inputs.split_first().unwrap();
+            for (member, score) in first {
+                if rest.iter().all(|scores| !scores.contains_key(member)) {
+                    result.insert(member.clone(), *score);
+                }
+            }
+        }
+    }
+    Ok(result.into_iter().collect())
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn zcombine_cmd(
+    server: &mut Server,
+    op: SetOp,
+    dst: &Option<Bytes>,
+    keys: &[Bytes],
+    weights: &[f64],
+    aggregate: Aggregate,
+    withscores: bool,
+    protocol: Protocol,
+    is_rep_con: bool,
+) -> Result<Protocol, DBError> {
+    let mut storage = server.lock_storage().await;
+    let zset = match combine_zsets(&mut storage, op, keys, weights, aggregate) {
+        Ok(zset) => zset,
+        Err(e) => return Ok(e),
+    };
+    let dst = match dst {
+        Some(dst) => dst,
+        None => {
+            let items = zset
+                .iter()
+                .map(|(member, score)| (member.clone(), score))
+                .collect();
+            drop(storage);
+            return Ok(scored_reply(server, items, withscores));
+        }
+    };
+    let len = zset.len();
+    let served = {
+        let mut blocking_keys = server.blocking_keys.lock().await;
+        store_zset(&mut storage, &mut blocking_keys, server.db_index, dst, zset)
+    };
+    drop(storage);
+    let ret =
+        resp_and_replicate(server, Protocol::Integer(len as i64), protocol, is_rep_con).await?;
+    replicate_all(server, served, is_rep_con).await?;
+    Ok(ret)
+}
+
+// a database index argument, or the error to reply with
+fn parse_db_index(server: &Server, s: &str, not_int_err: &str) -> Result<usize, Protocol> {
+    let index = s.parse::<i64>().map_err(|_| Protocol::err(not_int_err))?;
+    if index < 0 || index as usize >= server.dbs.len() {
+        return Err(Protocol::err("ERR DB index is out of range"));
+    }
+    Ok(index as usize)
+}
+
+// lock two different databases in index order, so commands locking the same pair the other
+// way round cannot deadlock
+async fn lock_dbs(
+    server: &Server,
+    a: usize,
+    b: usize,
+) -> (MutexGuard<'_, Storage>, MutexGuard<'_, Storage>) {
+    if a < b {
+        let a = server.lock_db(a).await;
+        let b = server.lock_db(b).await;
+        (a, b)
+    } else {
+        let b = server.lock_db(b).await;
+        let a = server.lock_db(a).await;
+        (a, b)
+    }
+}
+
+fn select_cmd(server: &mut Server, index: &str) -> Result<Protocol, DBError> {
+    match parse_db_index(server, index, NOT_INTEGER_ERR) {
+        Ok(index) => {
+            server.select(index);
+            Ok(Protocol::ok())
         }
-        ret
+        Err(e) => Ok(e),
     }
 }
 
-async fn exec_cmd(
-    queued_cmd: &mut Option<Vec<(Cmd, Protocol)>>,
+async fn move_cmd(
     server: &mut Server,
+    k: &Bytes,
+    db: &str,
+    protocol: Protocol,
     is_rep_con: bool,
 ) -> Result<Protocol, DBError> {
-    if queued_cmd.is_some() {
-        let mut vec = Vec::new();
-        for (cmd, protocol) in queued_cmd.as_ref().unwrap() {
-            let res = Box::pin(cmd.run(server, protocol.clone(), is_rep_con, &mut None)).await?;
-            vec.push(res);
+    let dst = match parse_db_index(server, db, NOT_INTEGER_ERR) {
+        Ok(dst) => dst,
+        Err(e) => return Ok(e),
+    };
+    if dst == server.db_index {
+        return Ok(Protocol::err(
+            "ERR source and destination objects are the same",
+        ));
+    }
+    let moved = {
+        let (mut src, mut dst) = lock_dbs(server, server.db_index, dst).await;
+        // an existing key in the destination is left alone
+        if dst.get_value(k).is_some() {
+            false
+        } else if let Some((v, expire_at)) = src.take(k) {
+            dst.insert(k.clone(), v, expire_at);
+            true
+        } else {
+            false
         }
-        *queued_cmd = None;
-        Ok(Protocol::Array(vec))
-    } else {
-        Ok(Protocol::err("ERR EXEC without MULTI"))
+    };
+    if !moved {
+        return Ok(Protocol::Integer(0));
     }
+    resp_and_replicate(server, Protocol::Integer(1), protocol, is_rep_con).await
 }
 
-async fn incr_cmd(server: &mut Server, key: &String) -> Result<Protocol, DBError> {
-    let mut storage = server.storage.lock().await;
-    let v = storage.get(key);
-    // return 1 if key is missing
-    let v = v.map_or("1".to_string(), |v| v);
+async fn swapdb_cmd(
+    server: &mut Server,
+    a: &str,
+    b: &str,
+    protocol: Protocol,
+    is_rep_con: bool,
+) -> Result<Protocol, DBError> {
+    let a = match parse_db_index(server, a, "ERR invalid first DB index") {
+        Ok(a) => a,
+        Err(e) => return Ok(e),
+    };
+    let b = match parse_db_index(server, b, "ERR invalid second DB index") {
+        Ok(b) => b,
+        Err(e) => return Ok(e),
+    };
+    if a != b {
+        // swap the contents, so connections that selected either index see the other's keys
+        let (mut a, mut b) = lock_dbs(server, a, b).await;
+        a.swap(&mut b);
+    }
+    resp_and_replicate(server, Protocol::ok(), protocol, is_rep_con).await
+}
 
-    if let Ok(x) = v.parse::<u64>() {
-        let v = (x + 1).to_string();
-        storage.set(key.clone(), v.clone());
-        Ok(Protocol::SimpleString(v))
-    } else {
-        Ok(Protocol::err("ERR value is not an integer or out of range"))
+fn hello_cmd(server: &mut Server, version: &Option<u8>) -> Result<Protocol, DBError> {
+    if let Some(v) = version {
+        if *v != RESP2 && *v != RESP3 {
+            return Ok(Protocol::err("NOPROTO unsupported protocol version"));
+        }
+        server.resp_version = *v;
+    }
+    Ok(Protocol::Map(vec![
+        (Protocol::bulk("server"), Protocol::bulk("redis")),
+        (Protocol::bulk("version"), Protocol::bulk("7.2.0")),
+        (
+            Protocol::bulk("proto"),
+            Protocol::Integer(server.resp_version as i64),
+        ),
+        (Protocol::bulk("mode"), Protocol::bulk("standalone")),
+        (
+            Protocol::bulk("role"),
+            Protocol::bulk(server.option.replication.role.clone()),
+        ),
+        (Protocol::bulk("modules"), Protocol::Array(vec![])),
+    ]))
+}
+
+async fn config_set_cmd(name: &str, value: &str, server: &mut Server) -> Result<Protocol, DBError> {
+    match name {
+        "save" => match options::parse_save_params(value) {
+            Ok(params) => {
+                *server.save_params.lock().await = params;
+                Ok(Protocol::ok())
+            }
+            Err(_) => Ok(Protocol::err(&format!(
+                "ERR Invalid argument '{}' for CONFIG SET 'save'",
+                value
+            ))),
+        },
+        "maxmemory" => match options::parse_memory(value) {
+            Ok(maxmemory) => {
+                server.maxmemory.store(maxmemory, Ordering::Relaxed);
+                // a lower limit takes effect right away
+                server.evict_if_needed().await;
+                Ok(Protocol::ok())
+            }
+            Err(_) => Ok(Protocol::err(&format!(
+                "ERR Invalid argument '{}' for CONFIG SET 'maxmemory'",
+                value
+            ))),
+        },
+        "maxmemory-policy" => match options::MaxmemoryPolicy::parse(value) {
+            Ok(policy) => {
+                *server.maxmemory_policy.lock().await = policy;
+                Ok(Protocol::ok())
+            }
+            Err(_) => Ok(Protocol::err(&format!(
+                "ERR Invalid argument '{}' for CONFIG SET 'maxmemory-policy'",
+                value
+            ))),
+        },
+        _ => Ok(Protocol::err(&format!(
+            "ERR Unknown option or number of arguments for CONFIG SET - '{}'",
+            name
+        ))),
     }
 }
 
-fn config_get_cmd(name: &String, server: &mut Server) -> Result<Protocol, DBError> {
+async fn config_get_cmd(name: &String, server: &mut Server) -> Result<Protocol, DBError> {
     match name.as_str() {
-        "dir" => Ok(Protocol::Array(vec![
-            Protocol::BulkString(name.clone()),
-            Protocol::BulkString(server.option.dir.clone()),
-        ])),
-        "dbfilename" => Ok(Protocol::Array(vec![
-            Protocol::BulkString(name.clone()),
-            Protocol::BulkString(server.option.db_file_name.clone()),
-        ])),
+        "save" => Ok(Protocol::Map(vec![(
+            Protocol::bulk(name.clone()),
+            Protocol::bulk(options::format_save_params(
+                &server.save_params.lock().await,
+            )),
+        )])),
+        "dir" => Ok(Protocol::Map(vec![(
+            Protocol::bulk(name.clone()),
+            Protocol::bulk(server.option.dir.clone()),
+        )])),
+        "dbfilename" => Ok(Protocol::Map(vec![(
+            Protocol::bulk(name.clone()),
+            Protocol::bulk(server.option.db_file_name.clone()),
+        )])),
+        "databases" => Ok(Protocol::Map(vec![(
+            Protocol::bulk(name.clone()),
+            Protocol::bulk(server.dbs.len().to_string()),
+        )])),
+        "maxmemory" => Ok(Protocol::Map(vec![(
+            Protocol::bulk(name.clone()),
+            Protocol::bulk(server.maxmemory.load(Ordering::Relaxed).to_string()),
+        )])),
+        "maxmemory-policy" => Ok(Protocol::Map(vec![(
+            Protocol::bulk(name.clone()),
+            Protocol::bulk(server.maxmemory_policy.lock().await.name()),
+        )])),
+        "maxmemory-samples" => Ok(Protocol::Map(vec![(
+            Protocol::bulk(name.clone()),
+            Protocol::bulk(server.option.maxmemory_samples.to_string()),
+        )])),
         _ => Err(DBError(format!("unsupported config {:?}", name))),
     }
 }
 
 async fn keys_cmd(server: &mut Server) -> Result<Protocol, DBError> {
-    let keys = { server.storage.lock().await.keys() };
+    let keys = { server.lock_storage().await.keys() };
     Ok(Protocol::Array(
-        keys.into_iter().map(Protocol::BulkString).collect(),
+        keys.into_iter().map(Protocol::bulk).collect(),
     ))
 }
 
-fn info_cmd(section: &Option<String>, server: &mut Server) -> Result<Protocol, DBError> {
-    match section {
-        Some(s) => match s.as_str() {
-            "replication" => Ok(Protocol::BulkString(format!(
-                "role:{}\nmaster_replid:{}\nmaster_repl_offset:{}\n",
+async fn info_cmd(section: &Option<String>, server: &mut Server) -> Result<Protocol, DBError> {
+    let sections = match section {
+        Some(s) => vec![s.as_str()],
+        None => vec!["memory", "persistence", "replication", "keyspace"],
+    };
+    let mut info = String::new();
+    for section in sections {
+        match section {
+            "replication" => info.push_str(&format!(
+                "# Replication\nrole:{}\nmaster_replid:{}\nmaster_repl_offset:{}\n",
                 server.option.replication.role,
                 server.option.replication.master_replid,
                 server.option.replication.master_repl_offset
-            ))),
-            _ => Err(DBError(format!("unsupported section {:?}", s))),
-        },
-        None => Ok(Protocol::BulkString("default".to_string())),
+            )),
+            "persistence" => {
+                let aof_rewrite_in_progress = match &server.aof {
+                    Some(aof) => aof.lock().await.is_rewriting(),
+                    None => false,
+                };
+                let load_info = server.rdb_load_info.lock().await.clone();
+                info.push_str(&format!(
+                    "# Persistence\nrdb_changes_since_last_save:{}\nrdb_bgsave_in_progress:{}\nrdb_last_save_time:{}\nrdb_last_bgsave_status:{}\nrdb_last_load_keys_loaded:{}\nrdb_last_load_keys_expired:{}\nrdb_loaded_redis_ver:{}\nrdb_loaded_ctime:{}\nrdb_loaded_used_mem:{}\naof_enabled:{}\naof_rewrite_in_progress:{}\n",
+                    server.dirty.load(Ordering::Relaxed),
+                    server.bgsave_in_progress.load(Ordering::Relaxed) as u8,
+                    server.last_save.load(Ordering::Relaxed),
+                    if server.last_bgsave_ok.load(Ordering::Relaxed) {
+                        "ok"
+                    } else {
+                        "err"
+                    },
+                    load_info.keys_loaded,
+                    load_info.keys_expired,
+                    load_info.redis_ver.unwrap_or_default(),
+                    load_info.ctime.unwrap_or_default(),
+                    load_info.used_mem.unwrap_or_default(),
+                    server.aof.is_some() as u8,
+                    aof_rewrite_in_progress as u8,
+                ))
+            }
+            "memory" => info.push_str(&format!(
+                "# Memory\nused_memory:{}\nmaxmemory:{}\nmaxmemory_policy:{}\n",
+                server.used_memory().await,
+                server.maxmemory.load(Ordering::Relaxed),
+                server.maxmemory_policy.lock().await.name()
+            )),
+            "keyspace" => {
+                info.push_str("# Keyspace\n");
+                for (index, db) in server.dbs.iter().enumerate() {
+                    let db = db.lock().await;
+                    if db.len() > 0 {
+                        info.push_str(&format!(
+                            "db{}:keys={},expires={},avg_ttl=0\n",
+                            index,
+                            db.len(),
+                            db.expires_len()
+                        ));
+                    }
+                }
+            }
+            _ => return Err(DBError(format!("unsupported section {:?}", section))),
+        }
     }
+    Ok(Protocol::VerbatimString(
+        "txt".to_string(),
+        Bytes::from(info),
+    ))
 }
 
 async fn xread_cmd(
     starts: &[String],
     server: &mut Server,
-    stream_keys: &[String],
+    stream_keys: &[Bytes],
     block_millis: &Option<u64>,
 ) -> Result<Protocol, DBError> {
     if let Some(t) = block_millis {
@@ -329,38 +4019,68 @@ async fn xread_cmd(
                 let mut blocker = server.stream_reader_blocker.lock().await;
                 blocker.push(sender.clone());
             }
-            while let Some(_) = receiver.recv().await {
+            while receiver.recv().await.is_some() {
                 println!("get new xadd cmd, release block");
                 // break;
             }
         }
     }
-    let streams = server.streams.lock().await;
+    let mut storage = server.lock_storage().await;
     let mut ret = Vec::new();
     for (i, stream_key) in stream_keys.iter().enumerate() {
-        let stream = streams.get(stream_key);
-        if let Some(s) = stream {
-            let (offset_id, mut offset_seq, _) = split_offset(starts[i].as_str());
-            offset_seq += 1;
-            let start = format!("{}-{}", offset_id, offset_seq);
-            let end = format!("{}-{}", u64::MAX - 1, 0);
-
-            // query stream range
-            let range = s.range::<String, _>((Bound::Included(&start), Bound::Included(&end)));
-            let mut array = Vec::new();
-            for (k, v) in range {
-                array.push(Protocol::BulkString(k.clone()));
-                array.push(Protocol::from_vec(
-                    v.iter()
-                        .flat_map(|(a, b)| vec![a.as_str(), b.as_str()])
-                        .collect(),
-                ))
+        let start = match StreamId::parse(&starts[i], 0) {
+            Some(id) => id,
+            None => return Ok(Protocol::err(INVALID_STREAM_ID_ERR)),
+        };
+        match storage.get_value(stream_key) {
+            Some(Value::Stream(s)) => {
+                // entries strictly after the given ID
+                let range = s.entries.range((Bound::Excluded(start), Bound::Unbounded));
+                let array = stream_entries(range);
+                if !array.is_empty() {
+                    ret.push((Protocol::bulk(stream_key.clone()), Protocol::Array(array)));
+                }
             }
-            ret.push(Protocol::BulkString(stream_key.clone()));
-            ret.push(Protocol::Array(array));
+            Some(_) => return Ok(Protocol::wrong_type_err()),
+            None => {}
         }
     }
-    Ok(Protocol::Array(ret))
+    if ret.is_empty() {
+        return Ok(Protocol::NullArray);
+    }
+    // a map keyed by stream under RESP3, the [key, entries] pairs under RESP2
+    if server.resp_version == RESP3 {
+        Ok(Protocol::Map(ret))
+    } else {
+        Ok(Protocol::Array(
+            ret.into_iter()
+                .map(|(k, v)| Protocol::Array(vec![k, v]))
+                .collect(),
+        ))
+    }
+}
+
+fn stream_entries<'a>(
+    range: impl Iterator<Item = (&'a StreamId, &'a Vec<(Bytes, Bytes)>)>,
+) -> Vec<Protocol> {
+    range
+        .map(|(id, fields)| {
+            Protocol::Array(vec![
+                Protocol::bulk(id.to_string()),
+                Protocol::Array(
+                    fields
+                        .iter()
+                        .flat_map(|(f, v)| {
+                            [
+                                Protocol::BulkString(f.clone()),
+                                Protocol::BulkString(v.clone()),
+                            ]
+                        })
+                        .collect(),
+                ),
+            ])
+        })
+        .collect()
 }
 
 fn replconf_cmd(sub_cmd: &str, server: &mut Server) -> Result<Protocol, DBError> {
@@ -380,48 +4100,38 @@ fn replconf_cmd(sub_cmd: &str, server: &mut Server) -> Result<Protocol, DBError>
 
 async fn xrange_cmd(
     server: &mut Server,
-    stream_key: &String,
-    start: &String,
-    end: &String,
+    stream_key: &Bytes,
+    start: &str,
+    end: &str,
 ) -> Result<Protocol, DBError> {
-    let streams = server.streams.lock().await;
-    let stream = streams.get(stream_key);
-    Ok(stream.map_or(Protocol::none(), |s| {
-        // support query with '-'
-        let start = if start == "-" {
-            "0".to_string()
-        } else {
-            start.clone()
-        };
-
-        // support query with '+'
-        let end = if end == "+" {
-            u64::MAX.to_string()
-        } else {
-            end.clone()
-        };
-
-        // query stream range
-        let range = s.range::<String, _>((Bound::Included(&start), Bound::Included(&end)));
-        let mut array = Vec::new();
-        for (k, v) in range {
-            array.push(Protocol::BulkString(k.clone()));
-            array.push(Protocol::from_vec(
-                v.iter()
-                    .flat_map(|(a, b)| vec![a.as_str(), b.as_str()])
-                    .collect(),
-            ))
-        }
-        println!("after xrange: {:?}", array);
-        Protocol::Array(array)
-    }))
+    // '-' and '+' are the smallest and greatest IDs, a bare timestamp covers every sequence in it
+    let start = match start {
+        "-" => Some(StreamId::default()),
+        _ => StreamId::parse(start, 0),
+    };
+    let end = match end {
+        "+" => Some(StreamId::MAX),
+        _ => StreamId::parse(end, u64::MAX),
+    };
+    let (start, end) = match (start, end) {
+        (Some(start), Some(end)) => (start, end),
+        _ => return Ok(Protocol::err(INVALID_STREAM_ID_ERR)),
+    };
+    let mut storage = server.lock_storage().await;
+    match storage.get_value(stream_key) {
+        Some(Value::Stream(s)) if start <= end => Ok(Protocol::Array(stream_entries(
+            s.entries.range(start..=end),
+        ))),
+        Some(Value::Stream(_)) | None => Ok(Protocol::Array(vec![])),
+        Some(_) => Ok(Protocol::wrong_type_err()),
+    }
 }
 
 async fn xadd_cmd(
     offset: &str,
     server: &mut Server,
-    stream_key: &str,
-    kvps: &Vec<(String, String)>,
+    stream_key: &Bytes,
+    kvps: &[(Bytes, Bytes)],
     protocol: Protocol,
     is_rep_con: bool,
 ) -> Result<Protocol, DBError> {
@@ -435,32 +4145,35 @@ async fn xadd_cmd(
             "ERR The ID specified in XADD must be greater than 0-0",
         ));
     }
-    {
-        let mut streams = server.streams.lock().await;
-        let stream = streams
-            .entry(stream_key.to_string())
-            .or_insert_with(BTreeMap::new);
-
-        if let Some((last_offset, _)) = stream.last_key_value() {
-            let (last_offset_id, last_offset_seq, _) = split_offset(last_offset.as_str());
-            if last_offset_id > offset_id
-                || (last_offset_id == offset_id && last_offset_seq >= offset_seq && !has_wildcard)
+    let id = {
+        let mut storage = server.lock_storage().await;
+        if storage.get_value(stream_key).is_none() {
+            storage.insert(stream_key.clone(), Value::Stream(Stream::default()), None);
+        }
+        let stream = match storage.get_value_mut(stream_key) {
+            Some(Value::Stream(s)) => s,
+            _ => return Ok(Protocol::wrong_type_err()),
+        };
+
+        if stream.entries_added > 0 {
+            let last = stream.last_id;
+            if last.ms > offset_id
+                || (last.ms == offset_id && last.seq >= offset_seq && !has_wildcard)
             {
                 return Ok(Protocol::err("ERR The ID specified in XADD is equal or smaller than the target stream top item"));
             }
 
-            if last_offset_id == offset_id && last_offset_seq >= offset_seq && has_wildcard {
-                offset_seq = last_offset_seq + 1;
+            if last.ms == offset_id && last.seq >= offset_seq && has_wildcard {
+                offset_seq = last.seq + 1;
             }
         }
 
-        let offset = format!("{}-{}", offset_id, offset_seq);
-
-        let s = stream.entry(offset.clone()).or_insert_with(Vec::new);
-        for (key, value) in kvps {
-            s.push((key.clone(), value.clone()));
-        }
-    }
+        let id = StreamId::new(offset_id, offset_seq);
+        stream.entries.insert(id, kvps.to_vec());
+        stream.last_id = id;
+        stream.entries_added += 1;
+        id
+    };
     {
         let mut blocker = server.stream_reader_blocker.lock().await;
         for sender in blocker.iter() {
@@ -468,24 +4181,13 @@ async fn xadd_cmd(
         }
         blocker.clear();
     }
-    resp_and_replicate(
-        server,
-        Protocol::BulkString(offset.to_string()),
-        protocol,
-        is_rep_con,
-    )
-    .await
+    resp_and_replicate(server, Protocol::bulk(id.to_string()), protocol, is_rep_con).await
 }
 
-async fn type_cmd(server: &mut Server, k: &String) -> Result<Protocol, DBError> {
-    let v = { server.storage.lock().await.get(k) };
-    if v.is_some() {
-        return Ok(Protocol::SimpleString("string".to_string()));
-    }
-    let streams = server.streams.lock().await;
-    let v = streams.get(k);
-    Ok(v.map_or(Protocol::none(), |_| {
-        Protocol::SimpleString("stream".to_string())
+async fn type_cmd(server: &mut Server, k: &Bytes) -> Result<Protocol, DBError> {
+    let mut storage = server.lock_storage().await;
+    Ok(storage.get_value(k).map_or(Protocol::none(), |v| {
+        Protocol::SimpleString(v.type_name().to_string())
     }))
 }
 
@@ -502,84 +4204,565 @@ fn psync_cmd(server: &mut Server) -> Result<Protocol, DBError> {
 
 async fn del_cmd(
     server: &mut Server,
-    k: &str,
+    keys: &[Bytes],
     protocol: Protocol,
     is_rep_con: bool,
 ) -> Result<Protocol, DBError> {
-    // offset
-    let _ = {
-        let mut s = server.storage.lock().await;
-        s.del(k.to_string());
+    let mut deleted = 0;
+    {
+        let mut s = server.lock_storage().await;
+        for k in keys {
+            if s.del(k) {
+                deleted += 1;
+            }
+        }
         server
             .offset
-            .fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    }
+    resp_and_replicate(server, Protocol::Integer(deleted), protocol, is_rep_con).await
+}
+
+// replicated as PEXPIREAT with the absolute time, or as DEL when that time has already passed
+async fn expire_cmd(
+    server: &mut Server,
+    k: &Bytes,
+    time: &str,
+    format: TimeFormat,
+    condition: Option<ExpireCondition>,
+    is_rep_con: bool,
+) -> Result<Protocol, DBError> {
+    let n = match parse_int(time) {
+        Ok(n) => n,
+        Err(e) => return Ok(e),
     };
-    resp_and_replicate(server, Protocol::ok(), protocol, is_rep_con).await
+    let now = now_in_millis();
+    let expire_at = format.expire_at(n, now);
+    if expire_at > i64::MAX as i128 {
+        let name = match format {
+            TimeFormat::Seconds => "expire",
+            TimeFormat::Millis => "pexpire",
+            TimeFormat::UnixSeconds => "expireat",
+            TimeFormat::UnixMillis => "pexpireat",
+        };
+        return Ok(Protocol::err(&format!(
+            "ERR invalid expire time in '{}' command",
+            name
+        )));
+    }
+    let expire_at = expire_at.max(0) as u128;
+    let replication = {
+        let mut storage = server.lock_storage().await;
+        let current = match storage.expire_at(k) {
+            Some(current) => current,
+            None => return Ok(Protocol::Integer(0)),
+        };
+        if condition.is_some_and(|c| !c.allows(current, expire_at)) {
+            return Ok(Protocol::Integer(0));
+        }
+        if expire_at <= now {
+            storage.del(k);
+            Protocol::Array(vec![Protocol::bulk("DEL"), Protocol::bulk(k.clone())])
+        } else {
+            storage.set_expire(k, Some(expire_at));
+            Protocol::Array(vec![
+                Protocol::bulk("PEXPIREAT"),
+                Protocol::bulk(k.clone()),
+                Protocol::bulk(expire_at.to_string()),
+            ])
+        }
+    };
+    resp_and_replicate(server, Protocol::Integer(1), replication, is_rep_con).await
+}
+
+// TTL, PTTL, EXPIRETIME and PEXPIRETIME: -2 for a missing key, -1 for a key without a TTL
+async fn ttl_cmd(server: &mut Server, k: &Bytes, format: TimeFormat) -> Result<Protocol, DBError> {
+    let mut storage = server.lock_storage().await;
+    Ok(Protocol::Integer(match storage.expire_at(k) {
+        Some(Some(expire_at)) => format.format(expire_at, now_in_millis()),
+        Some(None) => -1,
+        None => -2,
+    }))
 }
 
-async fn set_ex_cmd(
+async fn persist_cmd(
     server: &mut Server,
-    k: &str,
-    v: &str,
-    x: &u128,
+    k: &Bytes,
     protocol: Protocol,
     is_rep_con: bool,
 ) -> Result<Protocol, DBError> {
-    // offset
-    let _ = {
-        let mut s = server.storage.lock().await;
-        s.setx(k.to_string(), v.to_string(), *x * 1000);
+    {
+        let mut storage = server.lock_storage().await;
+        if !matches!(storage.expire_at(k), Some(Some(_))) {
+            return Ok(Protocol::Integer(0));
+        }
+        storage.set_expire(k, None);
+    }
+    resp_and_replicate(server, Protocol::Integer(1), protocol, is_rep_con).await
+}
+
+// the options of SET
+#[derive(Default)]
+struct SetOptions {
+    nx: bool,
+    xx: bool,
+    get: bool,
+    keepttl: bool,
+    // EX, PX, EXAT or PXAT and the time given
+    expire: Option<(TimeFormat, i64)>,
+}
+
+// NX, XX, GET, KEEPTTL, EX, PX, EXAT and PXAT in any order, or the error to reply with
+fn parse_set_options(options: &[String]) -> Result<SetOptions, Protocol> {
+    let syntax_err = || Protocol::err("ERR syntax error");
+    let mut parsed = SetOptions::default();
+    let mut i = 0;
+    while i < options.len() {
+        let format = match options[i].to_ascii_lowercase().as_str() {
+            "nx" if !parsed.xx => {
+                parsed.nx = true;
+                None
+            }
+            "xx" if !parsed.nx => {
+                parsed.xx = true;
+                None
+            }
+            "get" => {
+                parsed.get = true;
+                None
+            }
+            "keepttl" if parsed.expire.is_none() => {
+                parsed.keepttl = true;
+                None
+            }
+            "ex" => Some(TimeFormat::Seconds),
+            "px" => Some(TimeFormat::Millis),
+            "exat" => Some(TimeFormat::UnixSeconds),
+            "pxat" => Some(TimeFormat::UnixMillis),
+            _ => return Err(syntax_err()),
+        };
+        if let Some(format) = format {
+            // only one expiration, and not together with KEEPTTL
+            if parsed.keepttl || parsed.expire.is_some() {
+                return Err(syntax_err());
+            }
+            let n = options.get(i + 1).ok_or_else(syntax_err)?;
+            let n = parse_int(n)?;
+            if n <= 0 {
+                return Err(Protocol::err("ERR invalid expire time in 'set' command"));
+            }
+            parsed.expire = Some((format, n));
+            i += 1;
+        }
+        i += 1;
+    }
+    Ok(parsed)
+}
+
+// replicated as a plain SET, with the expiration as an absolute PXAT or KEEPTTL, so replicas
+// and the AOF end up with the same expiration whenever they apply it
+async fn set_cmd(
+    server: &mut Server,
+    k: &Bytes,
+    v: &Bytes,
+    options: &[String],
+    is_rep_con: bool,
+) -> Result<Protocol, DBError> {
+    let options = match parse_set_options(options) {
+        Ok(options) => options,
+        Err(e) => return Ok(e),
+    };
+    let now = now_in_millis();
+    let expire_at = match options.expire {
+        Some((format, n)) => match format.expire_at(n, now) {
+            at if at > i64::MAX as i128 => {
+                return Ok(Protocol::err("ERR invalid expire time in 'set' command"))
+            }
+            at => Some(at as u128),
+        },
+        None => None,
+    };
+    let (resp, replication) = {
+        let mut storage = server.lock_storage().await;
+        // GET fails on other types before anything is written
+        let old = if options.get {
+            match string_mut(&mut storage, k) {
+                Ok(old) => old.cloned(),
+                Err(e) => return Ok(e),
+            }
+        } else {
+            None
+        };
+        let resp = if options.get {
+            old.map_or(Protocol::Null, Protocol::BulkString)
+        } else {
+            Protocol::ok()
+        };
+        let exists = storage.get_value(k).is_some();
+        if (options.nx && exists) || (options.xx && !exists) {
+            return Ok(if options.get { resp } else { Protocol::Null });
+        }
+        let mut replication = vec![
+            Protocol::bulk("SET"),
+            Protocol::bulk(k.clone()),
+            Protocol::BulkString(v.clone()),
+        ];
+        match expire_at {
+            Some(at) => {
+                storage.insert(k.clone(), Value::String(v.clone()), Some(at));
+                replication.push(Protocol::bulk("PXAT"));
+                replication.push(Protocol::bulk(at.to_string()));
+            }
+            None if options.keepttl => {
+                put_string(&mut storage, k, v.clone());
+                replication.push(Protocol::bulk("KEEPTTL"));
+            }
+            None => storage.set(k.clone(), v.clone()),
+        }
         server
             .offset
-            .fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        (resp, Protocol::Array(replication))
     };
-    resp_and_replicate(server, Protocol::ok(), protocol, is_rep_con).await
+    resp_and_replicate(server, resp, replication, is_rep_con).await
+}
+
+async fn get_cmd(server: &mut Server, k: &Bytes) -> Result<Protocol, DBError> {
+    let mut storage = server.lock_storage().await;
+    match string_mut(&mut storage, k) {
+        Ok(v) => Ok(v.map_or(Protocol::Null, |v| Protocol::BulkString(v.clone()))),
+        Err(e) => Ok(e),
+    }
+}
+
+// the string stored at `k`, None if the key is missing, or the WRONGTYPE reply
+fn string_mut<'a>(storage: &'a mut Storage, k: &Bytes) -> Result<Option<&'a mut Bytes>, Protocol> {
+    match storage.get_value_mut(k) {
+        Some(Value::String(v)) => Ok(Some(v)),
+        Some(_) => Err(Protocol::wrong_type_err()),
+        None => Ok(None),
+    }
 }
 
-async fn set_px_cmd(
+// store a string at `k`, keeping the expiration of the key it replaces
+fn put_string(storage: &mut Storage, k: &Bytes, v: Bytes) {
+    match storage.get_value_mut(k) {
+        Some(value) => *value = Value::String(v),
+        None => storage.set(k.clone(), v),
+    }
+}
+
+async fn incrby_cmd(
     server: &mut Server,
-    k: &str,
-    v: &str,
-    x: &u128,
+    k: &Bytes,
+    by: &str,
+    decr: bool,
     protocol: Protocol,
     is_rep_con: bool,
 ) -> Result<Protocol, DBError> {
-    // offset
-    let _ = {
-        let mut s = server.storage.lock().await;
-        s.setx(k.to_string(), v.to_string(), *x);
-        server
-            .offset
-            .fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+    let by = match string_to_int(by.as_bytes()) {
+        Some(by) if decr => match by.checked_neg() {
+            Some(by) => by,
+            None => return Ok(Protocol::err("ERR decrement would overflow")),
+        },
+        Some(by) => by,
+        None => return Ok(Protocol::err(NOT_INTEGER_ERR)),
     };
-    resp_and_replicate(server, Protocol::ok(), protocol, is_rep_con).await
+    let v = {
+        let mut storage = server.lock_storage().await;
+        let current = match string_mut(&mut storage, k) {
+            Ok(Some(v)) => match string_to_int(v) {
+                Some(current) => current,
+                None => return Ok(Protocol::err(NOT_INTEGER_ERR)),
+            },
+            Ok(None) => 0,
+            Err(e) => return Ok(e),
+        };
+        let v = match current.checked_add(by) {
+            Some(v) => v,
+            None => return Ok(Protocol::err("ERR increment or decrement would overflow")),
+        };
+        put_string(&mut storage, k, Bytes::from(v.to_string()));
+        v
+    };
+    resp_and_replicate(server, Protocol::Integer(v), protocol, is_rep_con).await
 }
 
-async fn set_cmd(
+// a float value or increment; Redis keeps these as long doubles, so results are rounded to the
+// 15 significant digits an f64 holds exactly, which makes 0.1 plus 0.2 read back as 0.3
+fn format_float(v: f64) -> String {
+    format!("{:.14e}", v).parse::<f64>().unwrap().to_string()
+}
+
+// the sum is computed in f64, where Redis uses a long double with about 18 significant digits.
+// Both drop 1e-20 added to 1e20, but 1e17 plus 1.5 gives 100000000000000000 here against
+// Redis' 100000000000000001.5
+async fn incrbyfloat_cmd(
     server: &mut Server,
-    k: &str,
-    v: &str,
+    k: &Bytes,
+    by: &str,
     protocol: Protocol,
     is_rep_con: bool,
 ) -> Result<Protocol, DBError> {
-    // offset
-    let _ = {
-        let mut s = server.storage.lock().await;
-        s.set(k.to_string(), v.to_string());
-        server
-            .offset
-            .fetch_add(1, std::sync::atomic::Ordering::Relaxed)
-            + 1
+    let by = match by.parse::<f64>() {
+        Ok(by) if !by.is_nan() => by,
+        _ => return Ok(Protocol::err("ERR value is not a valid float")),
     };
-    resp_and_replicate(server, Protocol::ok(), protocol, is_rep_con).await
+    let v = {
+        let mut storage = server.lock_storage().await;
+        let current = match string_mut(&mut storage, k) {
+            Ok(Some(v)) => match std::str::from_utf8(v)
+                .ok()
+                .and_then(|v| v.parse::<f64>().ok())
+            {
+                Some(current) if current.is_finite() => current,
+                _ => return Ok(Protocol::err("ERR value is not a valid float")),
+            },
+            Ok(None) => 0.0,
+            Err(e) => return Ok(e),
+        };
+        let v = current + by;
+        if !v.is_finite() {
+            return Ok(Protocol::err("ERR increment would produce NaN or Infinity"));
+        }
+        let v = Bytes::from(format_float(v));
+        put_string(&mut storage, k, v.clone());
+        v
+    };
+    resp_and_replicate(server, Protocol::BulkString(v), protocol, is_rep_con).await
+}
+
+// values of other types read as missing
+async fn mget_cmd(server: &mut Server, keys: &[Bytes]) -> Result<Protocol, DBError> {
+    let mut storage = server.lock_storage().await;
+    Ok(Protocol::Array(
+        keys.iter()
+            .map(|k| storage.get(k).map_or(Protocol::Null, Protocol::BulkString))
+            .collect(),
+    ))
+}
+
+// MSET, or MSETNX when `nx` is set, which writes nothing if any of the keys exists
+async fn mset_cmd(
+    server: &mut Server,
+    pairs: &[(Bytes, Bytes)],
+    nx: bool,
+    protocol: Protocol,
+    is_rep_con: bool,
+) -> Result<Protocol, DBError> {
+    {
+        let mut storage = server.lock_storage().await;
+        if nx && pairs.iter().any(|(k, _)| storage.get_value(k).is_some()) {
+            return Ok(Protocol::Integer(0));
+        }
+        for (k, v) in pairs {
+            storage.set(k.clone(), v.clone());
+        }
+    }
+    let resp = if nx {
+        Protocol::Integer(1)
+    } else {
+        Protocol::ok()
+    };
+    resp_and_replicate(server, resp, protocol, is_rep_con).await
+}
+
+async fn setnx_cmd(
+    server: &mut Server,
+    k: &Bytes,
+    v: &Bytes,
+    protocol: Protocol,
+    is_rep_con: bool,
+) -> Result<Protocol, DBError> {
+    {
+        let mut storage = server.lock_storage().await;
+        if storage.get_value(k).is_some() {
+            return Ok(Protocol::Integer(0));
+        }
+        storage.set(k.clone(), v.clone());
+    }
+    resp_and_replicate(server, Protocol::Integer(1), protocol, is_rep_con).await
+}
+
+async fn append_cmd(
+    server: &mut Server,
+    k: &Bytes,
+    v: &Bytes,
+    protocol: Protocol,
+    is_rep_con: bool,
+) -> Result<Protocol, DBError> {
+    let len = {
+        let mut storage = server.lock_storage().await;
+        let current = match string_mut(&mut storage, k) {
+            Ok(current) => current,
+            Err(e) => return Ok(e),
+        };
+        let mut appended = current.map_or(vec![], |c| c.to_vec());
+        if appended.len() + v.len() > MAX_STRING_LEN {
+            return Ok(Protocol::err(STRING_TOO_LONG_ERR));
+        }
+        appended.extend_from_slice(v);
+        let len = appended.len();
+        put_string(&mut storage, k, Bytes::from(appended));
+        len
+    };
+    resp_and_replicate(server, Protocol::Integer(len as i64), protocol, is_rep_con).await
+}
+
+async fn getrange_cmd(
+    server: &mut Server,
+    k: &Bytes,
+    start: &str,
+    end: &str,
+) -> Result<Protocol, DBError> {
+    let (start, end) = match (parse_int(start), parse_int(end)) {
+        (Ok(start), Ok(end)) => (start, end),
+        (Err(e), _) | (_, Err(e)) => return Ok(e),
+    };
+    let mut storage = server.lock_storage().await;
+    let v = match string_mut(&mut storage, k) {
+        Ok(Some(v)) => v,
+        Ok(None) => return Ok(Protocol::bulk("")),
+        Err(e) => return Ok(e),
+    };
+    // negative offsets count from the end, and the range is clamped to the string
+    let len = v.len() as i64;
+    let start = if start < 0 { start + len } else { start }.max(0);
+    let end = if end < 0 { end + len } else { end }.min(len - 1);
+    if start > end {
+        return Ok(Protocol::bulk(""));
+    }
+    Ok(Protocol::BulkString(
+        v.slice(start as usize..end as usize + 1),
+    ))
+}
+
+async fn setrange_cmd(
+    server: &mut Server,
+    k: &Bytes,
+    offset: &str,
+    v: &Bytes,
+    protocol: Protocol,
+    is_rep_con: bool,
+) -> Result<Protocol, DBError> {
+    let offset = match parse_int(offset) {
+        Ok(offset) if offset < 0 => return Ok(Protocol::err("ERR offset is out of range")),
+        Ok(offset) => offset as usize,
+        Err(e) => return Ok(e),
+    };
+    let len = {
+        let mut storage = server.lock_storage().await;
+        let current = match string_mut(&mut storage, k) {
+            Ok(current) => current,
+            Err(e) => return Ok(e),
+        };
+        let current_len = current.as_ref().map_or(0, |c| c.len());
+        // an empty value changes nothing, and does not create the key
+        if v.is_empty() {
+            return Ok(Protocol::Integer(current_len as i64));
+        }
+        if offset.saturating_add(v.len()) > MAX_STRING_LEN {
+            return Ok(Protocol::err(STRING_TOO_LONG_ERR));
+        }
+        // the gap past the end of the string is padded with zero bytes
+        let mut updated = current.map_or(vec![], |c| c.to_vec());
+        if updated.len() < offset + v.len() {
+            updated.resize(offset + v.len(), 0);
+        }
+        updated[offset..offset + v.len()].copy_from_slice(v);
+        let len = updated.len();
+        put_string(&mut storage, k, Bytes::from(updated));
+        len
+    };
+    resp_and_replicate(server, Protocol::Integer(len as i64), protocol, is_rep_con).await
+}
+
+async fn strlen_cmd(server: &mut Server, k: &Bytes) -> Result<Protocol, DBError> {
+    let mut storage = server.lock_storage().await;
+    match string_mut(&mut storage, k) {
+        Ok(v) => Ok(Protocol::Integer(v.map_or(0, |v| v.len()) as i64)),
+        Err(e) => Ok(e),
+    }
 }
 
-async fn get_cmd(server: &mut Server, k: &str) -> Result<Protocol, DBError> {
+async fn getdel_cmd(
+    server: &mut Server,
+    k: &Bytes,
+    protocol: Protocol,
+    is_rep_con: bool,
+) -> Result<Protocol, DBError> {
     let v = {
-        let mut s = server.storage.lock().await;
-        s.get(k)
+        let mut storage = server.lock_storage().await;
+        let v = match string_mut(&mut storage, k) {
+            Ok(Some(v)) => v.clone(),
+            Ok(None) => return Ok(Protocol::Null),
+            Err(e) => return Ok(e),
+        };
+        storage.del(k);
+        v
+    };
+    resp_and_replicate(server, Protocol::BulkString(v), protocol, is_rep_con).await
+}
+
+// replicated as a SET of the same value with the resulting absolute expiration, or as a DEL when
+// that time has already passed
+async fn getex_cmd(
+    server: &mut Server,
+    k: &Bytes,
+    expire: &Option<(TimeFormat, String)>,
+    persist: bool,
+    is_rep_con: bool,
+) -> Result<Protocol, DBError> {
+    let now = now_in_millis();
+    let expire_at = match expire {
+        Some((format, n)) => match n.parse::<i64>() {
+            Ok(n) if n > 0 && format.expire_at(n, now) <= i64::MAX as i128 => {
+                Some(format.expire_at(n, now))
+            }
+            _ => return Ok(Protocol::err("ERR invalid expire time in 'getex' command")),
+        },
+        None => None,
+    };
+    let (v, replication) = {
+        let mut storage = server.lock_storage().await;
+        let v = match string_mut(&mut storage, k) {
+            Ok(Some(v)) => v.clone(),
+            Ok(None) => return Ok(Protocol::Null),
+            Err(e) => return Ok(e),
+        };
+        match expire_at {
+            Some(at) if at <= now as i128 => {
+                storage.del(k);
+                (
+                    v,
+                    Protocol::Array(vec![Protocol::bulk("DEL"), Protocol::bulk(k.clone())]),
+                )
+            }
+            Some(at) => {
+                let at = at as u128;
+                storage.insert(k.clone(), Value::String(v.clone()), Some(at));
+                let set = vec![
+                    Protocol::bulk("SET"),
+                    Protocol::bulk(k.clone()),
+                    Protocol::BulkString(v.clone()),
+                    Protocol::bulk("PXAT"),
+                    Protocol::bulk(at.to_string()),
+                ];
+                (v, Protocol::Array(set))
+            }
+            None if persist => {
+                storage.set(k.clone(), v.clone());
+                let set = vec![
+                    Protocol::bulk("SET"),
+                    Protocol::bulk(k.clone()),
+                    Protocol::BulkString(v.clone()),
+                ];
+                (v, Protocol::Array(set))
+            }
+            None => return Ok(Protocol::BulkString(v)),
+        }
     };
-    Ok(v.map_or(Protocol::Null, Protocol::SimpleString))
+    resp_and_replicate(server, Protocol::BulkString(v), replication, is_rep_con).await
 }
 
 async fn resp_and_replicate(
@@ -589,28 +4772,36 @@ async fn resp_and_replicate(
     is_rep_con: bool,
 ) -> Result<Protocol, DBError> {
     if server.is_master() {
-        server
-            .master_repl_clients
-            .lock()
-            .await
-            .as_mut()
-            .unwrap()
-            .send_command(replication)
-            .await?;
+        let master_repl_clients = server.master_repl_clients.clone();
+        let mut master_repl_clients = master_repl_clients.lock().await;
+        let client = master_repl_clients.as_mut().unwrap();
+        // the keys the write expired on the way go first
+        server.propagate_expired(client).await?;
+        log_write(server, &replication).await?;
+        client.send_command(server.db_index, replication).await?;
         Ok(resp)
     } else if !is_rep_con {
         Ok(Protocol::write_on_slave_err())
     } else {
+        log_write(server, &replication).await?;
         Ok(resp)
     }
 }
 
+// count the write towards the save rules and append it to the AOF
+async fn log_write(server: &mut Server, protocol: &Protocol) -> Result<(), DBError> {
+    server.dirty.fetch_add(1, Ordering::Relaxed);
+    if let Some(aof) = &server.aof {
+        aof.lock().await.append(server.db_index, protocol)?;
+    }
+    Ok(())
+}
+
 fn split_offset(offset: &str) -> (u64, u64, bool) {
     let offset_split = offset.split('-').collect::<Vec<_>>();
-    let offset_id = offset_split[0].parse::<u64>().expect(&format!(
-        "ERR The ID specified in XADD must be a number: {}",
-        offset
-    ));
+    let offset_id = offset_split[0]
+        .parse::<u64>()
+        .unwrap_or_else(|_| panic!("ERR The ID specified in XADD must be a number: {}", offset));
 
     if offset_split.len() == 1 || offset_split[1] == "*" {
         return (offset_id, if offset_id == 0 { 1 } else { 0 }, true);
@@ -0,0 +1,98 @@
+use bytes::{Buf, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::{
+    error::DBError,
+    protocol::{Protocol, RESP2},
+};
+
+/// Frames a byte stream into RESP values.
+///
+/// Partial frames stay in the read buffer until the rest of their bytes arrive, so a single
+/// read may yield zero, one or many (pipelined) frames. The elements of a request array are
+/// taken off the buffer as each one completes, so a large request split across many reads is
+/// not parsed again from its start on every read. Inline commands are decoded into the same
+/// array frames as their RESP counterparts. Replies are encoded with the protocol version
+/// negotiated for the connection.
+#[derive(Debug, Clone)]
+pub struct RespCodec {
+    pub version: u8,
+    // the elements still expected of the request array being read, 0 between requests
+    multibulk_len: usize,
+    // the elements of that array read so far
+    args: Vec<Protocol>,
+}
+
+impl Default for RespCodec {
+    fn default() -> Self {
+        RespCodec {
+            version: RESP2,
+            multibulk_len: 0,
+            args: Vec::new(),
+        }
+    }
+}
+
+impl Decoder for RespCodec {
+    type Item = Protocol;
+    type Error = DBError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Protocol>, DBError> {
+        loop {
+            if self.multibulk_len == 0 {
+                if src.first() != Some(&b'*') {
+                    match Protocol::from_request(src)? {
+                        // blank inline lines are skipped, as Redis does
+                        Some((Protocol::Array(args), len)) if args.is_empty() => {
+                            src.advance(len);
+                            continue;
+                        }
+                        Some((protocol, len)) => {
+                            src.advance(len);
+                            return Ok(Some(protocol));
+                        }
+                        None => return Ok(None),
+                    }
+                }
+                match Protocol::parse_aggregate_len(&src[1..])? {
+                    // and so are empty arrays
+                    Some((Some(0), len)) => {
+                        src.advance(len + 1);
+                        continue;
+                    }
+                    Some((Some(n), len)) => {
+                        src.advance(len + 1);
+                        self.multibulk_len = n;
+                        // the count is only a claim until the elements arrive
+                        self.args = Vec::with_capacity(n.min(1024));
+                    }
+                    Some((None, len)) => {
+                        src.advance(len + 1);
+                        return Ok(Some(Protocol::NullArray));
+                    }
+                    None => return Ok(None),
+                }
+            }
+            while self.multibulk_len > 0 {
+                match Protocol::from(src)? {
+                    Some((protocol, len)) => {
+                        src.advance(len);
+                        self.args.push(protocol);
+                        self.multibulk_len -= 1;
+                    }
+                    None => return Ok(None),
+                }
+            }
+            return Ok(Some(Protocol::Array(std::mem::take(&mut self.args))));
+        }
+    }
+}
+
+impl Encoder<Protocol> for RespCodec {
+    type Error = DBError;
+
+    fn encode(&mut self, item: Protocol, dst: &mut BytesMut) -> Result<(), DBError> {
+        dst.extend_from_slice(&item.encode_with(self.version));
+        Ok(())
+    }
+}
@@ -0,0 +1,75 @@
+// CRC-64/Jones as used by Redis for RDB checksums: reflected polynomial 0xad93d23594c935a9,
+// zero initial value and no final xor
+
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tokio::io::{AsyncRead, ReadBuf};
+
+const POLY: u64 = 0x95ac9329ac4bc9b5; // 0xad93d23594c935a9 bit-reversed
+
+const TABLE: [u64; 256] = make_table();
+
+const fn make_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut j = 0;
+        while j < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ POLY
+            } else {
+                crc >> 1
+            };
+            j += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+pub fn update(mut crc: u64, data: &[u8]) -> u64 {
+    for b in data {
+        crc = TABLE[((crc ^ *b as u64) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+pub fn crc64(data: &[u8]) -> u64 {
+    update(0, data)
+}
+
+/// Passes reads through while keeping a running checksum of every byte read.
+pub struct Crc64Reader<R> {
+    inner: R,
+    crc: u64,
+}
+
+impl<R> Crc64Reader<R> {
+    pub fn new(inner: R) -> Self {
+        Crc64Reader { inner, crc: 0 }
+    }
+
+    pub fn crc(&self) -> u64 {
+        self.crc
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Crc64Reader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let ret = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = ret {
+            self.crc = update(self.crc, &buf.filled()[before..]);
+        }
+        ret
+    }
+}
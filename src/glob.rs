@@ -0,0 +1,64 @@
+// glob-style patterns as used by the SCAN family's MATCH option: `*` matches any run of bytes,
+// `?` any single byte, `[abc]`, `[^abc]` and `[a-z]` a byte class, and `\` escapes the next byte
+
+pub fn glob_match(pattern: &[u8], s: &[u8]) -> bool {
+    let (mut p, mut i) = (0, 0);
+    // where to resume after the last `*` when the rest of the pattern fails to match
+    let mut backtrack = None;
+    while i < s.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            backtrack = Some((p, i));
+            continue;
+        }
+        if let Some(len) = match_one(&pattern[p..], s[i]) {
+            p += len;
+            i += 1;
+            continue;
+        }
+        match backtrack {
+            // let the `*` swallow one more byte and try again
+            Some((star_p, star_i)) => {
+                p = star_p;
+                i = star_i + 1;
+                backtrack = Some((star_p, star_i + 1));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+// match one byte against the element at the start of `pattern`, returning the element's length
+fn match_one(pattern: &[u8], c: u8) -> Option<usize> {
+    match *pattern.first()? {
+        b'?' => Some(1),
+        b'\\' if pattern.len() > 1 => (pattern[1] == c).then_some(2),
+        b'[' => {
+            let negate = pattern.get(1) == Some(&b'^');
+            let mut j = if negate { 2 } else { 1 };
+            let mut matched = false;
+            while j < pattern.len() && pattern[j] != b']' {
+                if pattern[j] == b'\\' && j + 1 < pattern.len() {
+                    matched |= pattern[j + 1] == c;
+                    j += 2;
+                } else if j + 2 < pattern.len() && pattern[j + 1] == b'-' && pattern[j + 2] != b']'
+                {
+                    let (lo, hi) = if pattern[j] <= pattern[j + 2] {
+                        (pattern[j], pattern[j + 2])
+                    } else {
+                        (pattern[j + 2], pattern[j])
+                    };
+                    matched |= (lo..=hi).contains(&c);
+                    j += 3;
+                } else {
+                    matched |= pattern[j] == c;
+                    j += 1;
+                }
+            }
+            // an unterminated class runs to the end of the pattern
+            (matched != negate).then_some((j + 1).min(pattern.len()))
+        }
+        b => (b == c).then_some(1),
+    }
+}
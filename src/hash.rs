@@ -0,0 +1,137 @@
+// the hash value type: fields and values plus the expiration of the fields that have one
+
+use std::collections::HashMap;
+
+use bytes::Bytes;
+
+use crate::scan::ScanOrder;
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Hash {
+    fields: HashMap<Bytes, Bytes>,
+    // field -> absolute expire time in milliseconds, only for fields that have a TTL
+    expires: HashMap<Bytes, u128>,
+    // set on a replica while it serves its own clients: the fields expired by this time stay
+    // until the master's HDEL arrives, but read as missing
+    hidden_at: Option<u128>,
+    // the fields in HSCAN order
+    order: ScanOrder,
+}
+
+impl Hash {
+    pub fn get(&self, field: &[u8]) -> Option<&Bytes> {
+        self.fields.get(field).filter(|_| !self.is_hidden(field))
+    }
+
+    // change a value in place, keeping the field's TTL
+    pub fn get_mut(&mut self, field: &[u8]) -> Option<&mut Bytes> {
+        if self.is_hidden(field) {
+            return None;
+        }
+        self.fields.get_mut(field)
+    }
+
+    // set a field, dropping any TTL it had; true if the field is new
+    pub fn insert(&mut self, field: Bytes, value: Bytes) -> bool {
+        self.expires.remove(&field);
+        self.order.insert(&field);
+        self.fields.insert(field, value).is_none()
+    }
+
+    pub fn remove(&mut self, field: &[u8]) -> bool {
+        self.expires.remove(field);
+        self.order.remove(field);
+        self.fields.remove(field).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        let hidden = match self.hidden_at {
+            Some(now) => self.expires.values().filter(|t| now > **t).count(),
+            None => 0,
+        };
+        self.fields.len() - hidden
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Bytes, &Bytes)> {
+        self.fields
+            .iter()
+            .filter(|(field, _)| !self.is_hidden(field))
+    }
+
+    pub fn expire_at(&self, field: &[u8]) -> Option<u128> {
+        self.expires
+            .get(field)
+            .copied()
+            .filter(|_| !self.is_hidden(field))
+    }
+
+    // one HSCAN step, see `ScanOrder::step`
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<(&Bytes, &Bytes)>) {
+        let (next, fields) = self.order.step(cursor, count);
+        let fields = fields
+            .into_iter()
+            .filter_map(|field| self.get(field).map(|v| (field, v)))
+            .collect();
+        (next, fields)
+    }
+
+    // start or stop hiding the fields expired at `now`, see `hidden_at`
+    pub fn hide_expired(&mut self, now: Option<u128>) {
+        self.hidden_at = now;
+    }
+
+    fn is_hidden(&self, field: &[u8]) -> bool {
+        self.hidden_at
+            .is_some_and(|now| self.expires.get(field).is_some_and(|t| now > *t))
+    }
+
+    // the field must exist
+    pub fn set_expire(&mut self, field: &[u8], expire_at: u128) {
+        if let Some((field, _)) = self.fields.get_key_value(field) {
+            self.expires.insert(field.clone(), expire_at);
+        }
+    }
+
+    // remove a field's TTL, false if it had none
+    pub fn persist(&mut self, field: &[u8]) -> bool {
+        self.expires.remove(field).is_some()
+    }
+
+    // the earliest field expiration, None if no field has a TTL
+    pub fn min_expire(&self) -> Option<u128> {
+        self.expires.values().min().copied()
+    }
+
+    // drop the fields whose expiration has passed and return them
+    pub fn remove_expired(&mut self, now: u128) -> Vec<Bytes> {
+        if self.expires.is_empty() {
+            return Vec::new();
+        }
+        let expired = self
+            .expires
+            .iter()
+            .filter(|(_, expire_at)| now > **expire_at)
+            .map(|(field, _)| field.clone())
+            .collect::<Vec<_>>();
+        for field in &expired {
+            self.remove(field);
+        }
+        expired
+    }
+}
+
+impl FromIterator<(Bytes, Bytes)> for Hash {
+    fn from_iter<I: IntoIterator<Item = (Bytes, Bytes)>>(iter: I) -> Self {
+        let fields: HashMap<Bytes, Bytes> = iter.into_iter().collect();
+        Hash {
+            order: fields.keys().collect(),
+            fields,
+            expires: HashMap::new(),
+            hidden_at: None,
+        }
+    }
+}
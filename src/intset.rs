@@ -0,0 +1,24 @@
+// intset, the sorted integer array used for small sets of integers:
+// <encoding u32><length u32><contents>, where encoding is the size in bytes (2, 4 or 8) of each
+// little-endian integer in contents
+
+use crate::error::DBError;
+
+pub fn decode(buf: &[u8]) -> Result<Vec<i64>, DBError> {
+    let corrupt = || DBError("invalid intset".to_string());
+    let header = buf.get(..8).ok_or_else(corrupt)?;
+    let width = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+    let len = u32::from_le_bytes(header[4..].try_into().unwrap()) as usize;
+    if !matches!(width, 2 | 4 | 8) {
+        return Err(corrupt());
+    }
+    let contents = buf.get(8..8 + width * len).ok_or_else(corrupt)?;
+    Ok(contents
+        .chunks_exact(width)
+        .map(|c| match width {
+            2 => i16::from_le_bytes(c.try_into().unwrap()) as i64,
+            4 => i32::from_le_bytes(c.try_into().unwrap()) as i64,
+            _ => i64::from_le_bytes(c.try_into().unwrap()),
+        })
+        .collect())
+}
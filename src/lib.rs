@@ -1,8 +1,23 @@
+mod aof;
+mod blocking;
 mod cmd;
+mod codec;
+mod crc64;
 pub mod error;
+mod glob;
+mod hash;
+mod intset;
+mod listpack;
+mod lzf;
 pub mod options;
 mod protocol;
 mod rdb;
 mod replication_client;
+mod scan;
 pub mod server;
+mod set;
 mod storage;
+mod stream;
+mod ziplist;
+mod zipmap;
+mod zset;
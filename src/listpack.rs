@@ -0,0 +1,154 @@
+// listpack, the compact encoding that replaced ziplist in Redis 7:
+// <total bytes u32><num elements u16><entry>...<0xFF>
+//
+// Each entry is <encoding><data><backlen>, where backlen is the size of encoding + data stored
+// in 1 to 5 bytes so the list can be walked backwards. The encoding byte is one of:
+// - 0xxxxxxx: a 7 bit unsigned integer
+// - 10xxxxxx: a string of up to 63 bytes
+// - 110xxxxx yyyyyyyy: a 13 bit signed integer
+// - 1110xxxx yyyyyyyy: a string of up to 4095 bytes
+// - 11110000 + u32 little-endian: a longer string
+// - 11110001 / 11110010 / 11110011 / 11110100: an i16 / i24 / i32 / i64
+
+use bytes::Bytes;
+
+use crate::error::DBError;
+
+const HEADER_SIZE: usize = 6;
+const END: u8 = 0xFF;
+
+pub fn decode(buf: &[u8]) -> Result<Vec<Bytes>, DBError> {
+    let corrupt = || DBError("invalid listpack".to_string());
+    let mut entries = Vec::new();
+    let mut p = HEADER_SIZE;
+    loop {
+        let enc = *buf.get(p).ok_or_else(corrupt)?;
+        if enc == END {
+            break;
+        }
+        let start = p;
+        p += 1;
+        let mut take = |n: usize| -> Result<&[u8], DBError> {
+            let s = buf.get(p..p + n).ok_or_else(corrupt)?;
+            p += n;
+            Ok(s)
+        };
+        let entry = if enc & 0x80 == 0 {
+            Bytes::from((enc as i64).to_string())
+        } else if enc & 0xC0 == 0x80 {
+            let len = (enc & 0x3F) as usize;
+            Bytes::copy_from_slice(take(len)?)
+        } else if enc & 0xE0 == 0xC0 {
+            let v = ((enc & 0x1F) as i64) << 8 | take(1)?[0] as i64;
+            // sign extend from 13 bits
+            let v = if v >= 1 << 12 { v - (1 << 13) } else { v };
+            Bytes::from(v.to_string())
+        } else if enc & 0xF0 == 0xE0 {
+            let len = ((enc & 0x0F) as usize) << 8 | take(1)?[0] as usize;
+            Bytes::copy_from_slice(take(len)?)
+        } else {
+            let int = |v: i64| Bytes::from(v.to_string());
+            match enc {
+                0xF0 => {
+                    let len = u32::from_le_bytes(take(4)?.try_into().unwrap()) as usize;
+                    Bytes::copy_from_slice(take(len)?)
+                }
+                0xF1 => int(i16::from_le_bytes(take(2)?.try_into().unwrap()) as i64),
+                0xF2 => {
+                    let b = take(3)?;
+                    int((i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8) as i64)
+                }
+                0xF3 => int(i32::from_le_bytes(take(4)?.try_into().unwrap()) as i64),
+                0xF4 => int(i64::from_le_bytes(take(8)?.try_into().unwrap())),
+                _ => return Err(corrupt()),
+            }
+        };
+        entries.push(entry);
+        p += backlen_size(p - start);
+    }
+    Ok(entries)
+}
+
+/// Builds a listpack, storing entries that are canonical decimal integers in the integer
+/// encodings like Redis does.
+pub fn encode<'a>(entries: impl IntoIterator<Item = &'a [u8]>) -> Vec<u8> {
+    let mut buf = vec![0; HEADER_SIZE];
+    let mut count = 0usize;
+    for entry in entries {
+        let start = buf.len();
+        match string_to_int(entry) {
+            Some(v) => encode_int(&mut buf, v),
+            None => {
+                let len = entry.len();
+                if len < 64 {
+                    buf.push(0x80 | len as u8);
+                } else if len < 4096 {
+                    buf.push(0xE0 | (len >> 8) as u8);
+                    buf.push(len as u8);
+                } else {
+                    buf.push(0xF0);
+                    buf.extend_from_slice(&(len as u32).to_le_bytes());
+                }
+                buf.extend_from_slice(entry);
+            }
+        }
+        let len = buf.len() - start;
+        encode_backlen(&mut buf, len);
+        count += 1;
+    }
+    buf.push(END);
+    let total = buf.len() as u32;
+    buf[..4].copy_from_slice(&total.to_le_bytes());
+    // the element count saturates, readers then have to walk the entries
+    buf[4..6].copy_from_slice(&(count.min(u16::MAX as usize) as u16).to_le_bytes());
+    buf
+}
+
+fn encode_int(buf: &mut Vec<u8>, v: i64) {
+    if (0..=127).contains(&v) {
+        buf.push(v as u8);
+    } else if (-4096..=4095).contains(&v) {
+        let v = v as u16 & 0x1FFF;
+        buf.push(0xC0 | (v >> 8) as u8);
+        buf.push(v as u8);
+    } else if i16::try_from(v).is_ok() {
+        buf.push(0xF1);
+        buf.extend_from_slice(&(v as i16).to_le_bytes());
+    } else if (-(1 << 23)..1 << 23).contains(&v) {
+        buf.push(0xF2);
+        buf.extend_from_slice(&(v as i32).to_le_bytes()[..3]);
+    } else if i32::try_from(v).is_ok() {
+        buf.push(0xF3);
+        buf.extend_from_slice(&(v as i32).to_le_bytes());
+    } else {
+        buf.push(0xF4);
+        buf.extend_from_slice(&v.to_le_bytes());
+    }
+}
+
+// only strings that read back identically, so "007" or "+1" stay strings
+pub fn string_to_int(s: &[u8]) -> Option<i64> {
+    let v = std::str::from_utf8(s).ok()?.parse::<i64>().ok()?;
+    (v.to_string().as_bytes() == s).then_some(v)
+}
+
+// the entry length, most significant 7 bit group first, every byte but the first flagged with
+// the high bit so it can be decoded from its last byte backwards
+fn encode_backlen(buf: &mut Vec<u8>, len: usize) {
+    let size = backlen_size(len);
+    for i in (0..size).rev() {
+        let group = ((len >> (7 * i)) & 0x7F) as u8;
+        buf.push(if i == size - 1 { group } else { group | 0x80 });
+    }
+}
+
+// number of bytes used to store the length of an entry of `len` bytes at its end
+fn backlen_size(len: usize) -> usize {
+    match len {
+        0..=127 => 1,
+        128..=16382 => 2,
+        16383..=2097150 => 3,
+        2097151..=268435454 => 4,
+        _ => 5,
+    }
+}
@@ -0,0 +1,145 @@
+// LZF compression as used for RDB strings: http://oldhome.schmorp.de/marc/liblzf.html
+//
+// The stream is a sequence of chunks, each starting with a control byte:
+// - 000LLLLL: a literal run of L + 1 bytes follows
+// - LLLOOOOO OOOOOOOO: a back reference of L + 2 bytes at offset O + 1
+// - 111OOOOO LLLLLLLL OOOOOOOO: a back reference of L + 9 bytes at offset O + 1
+
+use crate::error::DBError;
+
+const HASH_LOG: usize = 14;
+const MAX_LITERAL: usize = 1 << 5;
+const MAX_OFFSET: usize = 1 << 13;
+const MAX_REF: usize = (1 << 8) + (1 << 3);
+
+pub fn decompress(input: &[u8], out_len: usize) -> Result<Vec<u8>, DBError> {
+    let corrupt = || DBError("invalid LZF compressed data".to_string());
+    let mut out = Vec::with_capacity(out_len);
+    let mut ip = 0;
+    while ip < input.len() {
+        let ctrl = input[ip] as usize;
+        ip += 1;
+        if ctrl < MAX_LITERAL {
+            let len = ctrl + 1;
+            let literal = input.get(ip..ip + len).ok_or_else(corrupt)?;
+            out.extend_from_slice(literal);
+            ip += len;
+        } else {
+            let mut len = ctrl >> 5;
+            if len == 7 {
+                len += *input.get(ip).ok_or_else(corrupt)? as usize;
+                ip += 1;
+            }
+            len += 2;
+            let offset = ((ctrl & 0x1f) << 8) + *input.get(ip).ok_or_else(corrupt)? as usize + 1;
+            ip += 1;
+            if offset > out.len() {
+                return Err(corrupt());
+            }
+            // the reference may overlap the bytes being written, so copy one at a time
+            let start = out.len() - offset;
+            for i in 0..len {
+                out.push(out[start + i]);
+            }
+        }
+    }
+    if out.len() != out_len {
+        return Err(DBError(format!(
+            "LZF decompressed length {} does not match expected length {}",
+            out.len(),
+            out_len
+        )));
+    }
+    Ok(out)
+}
+
+/// Compresses `input`, returning `None` when the result would not be smaller than the input.
+pub fn compress(input: &[u8]) -> Option<Vec<u8>> {
+    let mut table = vec![usize::MAX; 1 << HASH_LOG];
+    let mut out = Vec::with_capacity(input.len());
+    // index of the control byte of the literal run being built
+    let mut lit_start = 0;
+    let mut lit = 0;
+    out.push(0);
+
+    let mut ip = 0;
+    while ip + 2 < input.len() {
+        let h = hash(&input[ip..ip + 3]);
+        let candidate = table[h];
+        table[h] = ip;
+
+        if candidate < ip
+            && ip - candidate - 1 < MAX_OFFSET
+            && input[candidate..candidate + 3] == input[ip..ip + 3]
+        {
+            let max_len = MAX_REF.min(input.len() - ip);
+            let mut len = 3;
+            while len < max_len && input[candidate + len] == input[ip + len] {
+                len += 1;
+            }
+
+            // close the pending literal run, or drop its unused control byte
+            if lit > 0 {
+                out[lit_start] = (lit - 1) as u8;
+            } else {
+                out.pop();
+            }
+
+            let offset = ip - candidate - 1;
+            let len_code = len - 2;
+            if len_code < 7 {
+                out.push(((len_code << 5) + (offset >> 8)) as u8);
+            } else {
+                out.push(((7 << 5) + (offset >> 8)) as u8);
+                out.push((len_code - 7) as u8);
+            }
+            out.push(offset as u8);
+
+            ip += len;
+            lit_start = out.len();
+            lit = 0;
+            out.push(0);
+        } else {
+            out.push(input[ip]);
+            ip += 1;
+            lit += 1;
+            if lit == MAX_LITERAL {
+                out[lit_start] = (lit - 1) as u8;
+                lit_start = out.len();
+                lit = 0;
+                out.push(0);
+            }
+        }
+
+        if out.len() >= input.len() {
+            return None;
+        }
+    }
+
+    for b in &input[ip..] {
+        out.push(*b);
+        lit += 1;
+        if lit == MAX_LITERAL {
+            out[lit_start] = (lit - 1) as u8;
+            lit_start = out.len();
+            lit = 0;
+            out.push(0);
+        }
+    }
+    if lit > 0 {
+        out[lit_start] = (lit - 1) as u8;
+    } else {
+        out.pop();
+    }
+
+    if out.len() >= input.len() {
+        None
+    } else {
+        Some(out)
+    }
+}
+
+fn hash(b: &[u8]) -> usize {
+    let v = ((b[0] as usize) << 16) | ((b[1] as usize) << 8) | b[2] as usize;
+    (v.wrapping_mul(2654435761) >> (32 - HASH_LOG)) & ((1 << HASH_LOG) - 1)
+}
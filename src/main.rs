@@ -2,7 +2,12 @@
 
 use tokio::net::TcpListener;
 
-use redis_rs::{options::ReplicationOption, server};
+use redis_rs::{
+    options::{
+        parse_memory, parse_save_params, AofOption, AppendFsync, MaxmemoryPolicy, ReplicationOption,
+    },
+    server,
+};
 
 use clap::Parser;
 
@@ -25,6 +30,46 @@ struct Args {
     /// The address of the master Redis server, if the server is a replica. None if the server is a master.
     #[arg(long)]
     replicaof: Option<String>,
+
+    /// Snapshot rules as "<seconds> <changes>" pairs, an empty string disables automatic snapshots
+    #[arg(long, default_value = "900 1 300 10 60 10000")]
+    save: String,
+
+    /// Log every write to an append only file and replay it at startup
+    #[arg(long)]
+    appendonly: bool,
+
+    /// The name of the append only file
+    #[arg(long, default_value = "appendonly.aof")]
+    appendfilename: String,
+
+    /// When to fsync the append only file: always, everysec or no
+    #[arg(long, default_value = "everysec")]
+    appendfsync: String,
+
+    /// Refuse to start instead of dropping a truncated last command of the append only file
+    #[arg(long)]
+    aof_strict_load: bool,
+
+    /// Load RDB files without verifying their checksum
+    #[arg(long)]
+    skip_rdb_checksum: bool,
+
+    /// The number of logical databases
+    #[arg(long, default_value_t = 16)]
+    databases: usize,
+
+    /// The memory limit, like 100mb or 1gb, 0 for none
+    #[arg(long, default_value = "0")]
+    maxmemory: String,
+
+    /// How to make room once maxmemory is reached: noeviction, allkeys-lru, allkeys-lfu, allkeys-random, volatile-lru, volatile-lfu, volatile-random or volatile-ttl
+    #[arg(long, default_value = "noeviction")]
+    maxmemory_policy: String,
+
+    /// The number of keys sampled per database to pick the one to evict
+    #[arg(long, default_value_t = 5)]
+    maxmemory_samples: usize,
 }
 
 #[tokio::main]
@@ -39,13 +84,63 @@ async fn main() {
         .await
         .unwrap();
 
+    let save_params = match parse_save_params(&args.save) {
+        Ok(params) => params,
+        Err(e) => {
+            println!("error: {:?}", e);
+            return;
+        }
+    };
+    if args.databases == 0 {
+        println!("error: databases must be at least 1");
+        return;
+    }
+    let fsync = match AppendFsync::parse(&args.appendfsync) {
+        Ok(fsync) => fsync,
+        Err(e) => {
+            println!("error: {:?}", e);
+            return;
+        }
+    };
+
+    let maxmemory = match parse_memory(&args.maxmemory) {
+        Ok(maxmemory) => maxmemory,
+        Err(e) => {
+            println!("error: {:?}", e);
+            return;
+        }
+    };
+    let maxmemory_policy = match MaxmemoryPolicy::parse(&args.maxmemory_policy) {
+        Ok(policy) => policy,
+        Err(e) => {
+            println!("error: {:?}", e);
+            return;
+        }
+    };
+    if args.maxmemory_samples == 0 {
+        println!("error: maxmemory-samples must be at least 1");
+        return;
+    }
+
     // new DB option
     let option = redis_rs::options::DBOption {
         dir: args.dir,
         db_file_name: args.dbfilename,
         port,
+        save_params,
+        skip_rdb_checksum: args.skip_rdb_checksum,
+        databases: args.databases,
+        maxmemory,
+        maxmemory_policy,
+        maxmemory_samples: args.maxmemory_samples,
+        aof: AofOption {
+            enabled: args.appendonly,
+            file_name: args.appendfilename,
+            fsync,
+            load_truncated: !args.aof_strict_load,
+        },
         replication: ReplicationOption {
-            role: if let Some(_) = args.replicaof {
+            role: if args.replicaof.is_some() {
                 "slave".to_string()
             } else {
                 "master".to_string()
@@ -59,6 +154,9 @@ async fn main() {
     // new server
     let mut server = server::Server::new(option).await;
 
+    // periodic background jobs
+    tokio::spawn(server.clone().cron());
+
     //start receive replication cmds for slave
     if server.is_slave() {
         let mut sc = server.clone();
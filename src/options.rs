@@ -1,9 +1,124 @@
+use crate::error::DBError;
+
 #[derive(Clone)]
 pub struct DBOption {
     pub dir: String,
     pub db_file_name: String,
     pub replication: ReplicationOption,
     pub port: u16,
+    // (seconds, changes) pairs, a snapshot is taken once any pair is satisfied
+    pub save_params: Vec<(u64, u64)>,
+    pub aof: AofOption,
+    // load RDB files without verifying their CRC64 checksum
+    pub skip_rdb_checksum: bool,
+    // number of logical databases, selected with SELECT
+    pub databases: usize,
+    // the memory limit in bytes, 0 for none
+    pub maxmemory: u64,
+    pub maxmemory_policy: MaxmemoryPolicy,
+    // keys sampled per database to pick one to evict
+    pub maxmemory_samples: usize,
+}
+
+#[derive(Clone)]
+pub struct AofOption {
+    pub enabled: bool,
+    pub file_name: String,
+    pub fsync: AppendFsync,
+    // cut off a truncated last command instead of refusing to start
+    pub load_truncated: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AppendFsync {
+    Always,
+    EverySec,
+    No,
+}
+
+impl AppendFsync {
+    pub fn parse(s: &str) -> Result<Self, DBError> {
+        match s.to_ascii_lowercase().as_str() {
+            "always" => Ok(AppendFsync::Always),
+            "everysec" => Ok(AppendFsync::EverySec),
+            "no" => Ok(AppendFsync::No),
+            _ => Err(DBError(format!("invalid appendfsync policy: {:?}", s))),
+        }
+    }
+}
+
+// what to do when a write needs memory beyond maxmemory
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MaxmemoryPolicy {
+    NoEviction,
+    AllkeysLru,
+    AllkeysLfu,
+    AllkeysRandom,
+    VolatileLru,
+    VolatileLfu,
+    VolatileRandom,
+    VolatileTtl,
+}
+
+impl MaxmemoryPolicy {
+    pub fn parse(s: &str) -> Result<Self, DBError> {
+        match s.to_ascii_lowercase().as_str() {
+            "noeviction" => Ok(MaxmemoryPolicy::NoEviction),
+            "allkeys-lru" => Ok(MaxmemoryPolicy::AllkeysLru),
+            "allkeys-lfu" => Ok(MaxmemoryPolicy::AllkeysLfu),
+            "allkeys-random" => Ok(MaxmemoryPolicy::AllkeysRandom),
+            "volatile-lru" => Ok(MaxmemoryPolicy::VolatileLru),
+            "volatile-lfu" => Ok(MaxmemoryPolicy::VolatileLfu),
+            "volatile-random" => Ok(MaxmemoryPolicy::VolatileRandom),
+            "volatile-ttl" => Ok(MaxmemoryPolicy::VolatileTtl),
+            _ => Err(DBError(format!("invalid maxmemory policy: {:?}", s))),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            MaxmemoryPolicy::NoEviction => "noeviction",
+            MaxmemoryPolicy::AllkeysLru => "allkeys-lru",
+            MaxmemoryPolicy::AllkeysLfu => "allkeys-lfu",
+            MaxmemoryPolicy::AllkeysRandom => "allkeys-random",
+            MaxmemoryPolicy::VolatileLru => "volatile-lru",
+            MaxmemoryPolicy::VolatileLfu => "volatile-lfu",
+            MaxmemoryPolicy::VolatileRandom => "volatile-random",
+            MaxmemoryPolicy::VolatileTtl => "volatile-ttl",
+        }
+    }
+
+    // only keys with a TTL may be evicted
+    pub fn is_volatile(&self) -> bool {
+        matches!(
+            self,
+            MaxmemoryPolicy::VolatileLru
+                | MaxmemoryPolicy::VolatileLfu
+                | MaxmemoryPolicy::VolatileRandom
+                | MaxmemoryPolicy::VolatileTtl
+        )
+    }
+}
+
+// parse a memory size like "100mb": k, m and g count in thousands, kb, mb and gb in 1024s
+pub fn parse_memory(s: &str) -> Result<u64, DBError> {
+    let lower = s.to_ascii_lowercase();
+    let digits = lower.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let unit = match &lower[digits.len()..] {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return Err(DBError(format!("invalid memory size: {:?}", s))),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(unit))
+        .ok_or_else(|| DBError(format!("invalid memory size: {:?}", s)))
 }
 
 #[derive(Clone)]
@@ -13,3 +128,23 @@ pub struct ReplicationOption {
     pub master_repl_offset: u64,
     pub replica_of: Option<String>,
 }
+
+// parse "<seconds> <changes> [<seconds> <changes> ...]", an empty string disables snapshotting
+pub fn parse_save_params(s: &str) -> Result<Vec<(u64, u64)>, DBError> {
+    let parts = s.split_whitespace().collect::<Vec<_>>();
+    if parts.len() % 2 != 0 {
+        return Err(DBError(format!("invalid save parameters: {:?}", s)));
+    }
+    parts
+        .chunks(2)
+        .map(|p| Ok((p[0].parse()?, p[1].parse()?)))
+        .collect()
+}
+
+pub fn format_save_params(params: &[(u64, u64)]) -> String {
+    params
+        .iter()
+        .map(|(secs, changes)| format!("{} {}", secs, changes))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
@@ -1,13 +1,41 @@
 use core::fmt;
 
+use bytes::Bytes;
+
 use crate::error::DBError;
 
+pub const RESP2: u8 = 2;
+pub const RESP3: u8 = 3;
+
+// the longest bulk string accepted, Redis' proto-max-bulk-len default
+const PROTO_MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+// the longest inline command or length line, Redis' PROTO_INLINE_MAX_SIZE
+const PROTO_INLINE_MAX_SIZE: usize = 64 * 1024;
+// the most elements an aggregate may declare
+const PROTO_MAX_MULTIBULK_LEN: usize = 1024 * 1024 * 1024;
+
+// a parsed aggregate or blob and its consumed length, `None` inside stands for the null value
+type ParsedAggregate = Option<(Option<Vec<Protocol>>, usize)>;
+type ParsedBlob = Option<(Option<Bytes>, usize)>;
+
 #[derive(Debug, Clone)]
 pub enum Protocol {
     SimpleString(String),
-    BulkString(String),
+    BulkString(Bytes),
+    Integer(i64),
+    Error(String),
     Null,
     Array(Vec<Protocol>),
+    NullArray,
+    // RESP3 only types, downgraded to their closest RESP2 form when encoded for a RESP2 client
+    Map(Vec<(Protocol, Protocol)>),
+    Set(Vec<Protocol>),
+    Double(f64),
+    Boolean(bool),
+    BigNumber(String),
+    VerbatimString(String, Bytes),
+    Attribute(Vec<(Protocol, Protocol)>, Box<Protocol>),
+    Push(Vec<Protocol>),
 }
 
 impl fmt::Display for Protocol {
@@ -17,48 +45,206 @@ impl fmt::Display for Protocol {
 }
 
 impl Protocol {
-    pub fn from(protocol: &str) -> Result<(Self, usize), DBError> {
-        let ret = match protocol.chars().nth(0) {
-            Some('+') => Self::parse_simple_string_sfx(&protocol[1..]),
-            Some('$') => Self::parse_bulk_string_sfx(&protocol[1..]),
-            Some('*') => Self::parse_array_sfx(&protocol[1..]),
+    /// Parses one frame from the head of `protocol`, returning the frame and the number of
+    /// bytes it occupies, or `None` if the buffer does not yet hold a complete frame.
+    pub fn from(protocol: &[u8]) -> Result<Option<(Self, usize)>, DBError> {
+        let ret = match protocol.first() {
+            Some(b'+') => Self::parse_simple_string_sfx(&protocol[1..]),
+            Some(b'-') => Self::parse_error_sfx(&protocol[1..]),
+            Some(b':') => Self::parse_integer_sfx(&protocol[1..]),
+            Some(b'$') => Self::parse_bulk_string_sfx(&protocol[1..]),
+            Some(b'*') => Self::parse_array_sfx(&protocol[1..]),
+            Some(b'_') => Self::parse_null_sfx(&protocol[1..]),
+            Some(b'%') => Self::parse_map_sfx(&protocol[1..]),
+            Some(b'~') => Self::parse_set_sfx(&protocol[1..]),
+            Some(b',') => Self::parse_double_sfx(&protocol[1..]),
+            Some(b'#') => Self::parse_boolean_sfx(&protocol[1..]),
+            Some(b'(') => Self::parse_big_number_sfx(&protocol[1..]),
+            Some(b'=') => Self::parse_verbatim_string_sfx(&protocol[1..]),
+            Some(b'|') => Self::parse_attribute_sfx(&protocol[1..]),
+            Some(b'>') => Self::parse_push_sfx(&protocol[1..]),
+            None => Ok(None),
             _ => Err(DBError(format!(
                 "[from] unsupported protocol: {:?}",
-                protocol
+                String::from_utf8_lossy(protocol)
             ))),
         };
-        match ret {
-            Ok((p, s)) => Ok((p, s + 1)),
-            Err(e) => Err(e),
+        Ok(ret?.map(|(p, s)| (p, s + 1)))
+    }
+
+    /// Parses a client request, which is either a RESP array or an inline command: a single
+    /// line of space separated, optionally quoted arguments as typed into telnet or netcat.
+    /// An inline command is returned as the equivalent array of bulk strings.
+    pub fn from_request(protocol: &[u8]) -> Result<Option<(Self, usize)>, DBError> {
+        match protocol.first() {
+            Some(b'*') | None => Self::from(protocol),
+            _ => Self::parse_inline(protocol),
+        }
+    }
+
+    fn parse_inline(protocol: &[u8]) -> Result<Option<(Self, usize)>, DBError> {
+        let len = match protocol.iter().position(|b| *b == b'\n') {
+            Some(x) => x,
+            None if protocol.len() > PROTO_INLINE_MAX_SIZE => {
+                return Err(DBError(
+                    "Protocol error: too big inline request".to_string(),
+                ))
+            }
+            None => return Ok(None),
+        };
+        let line = protocol[..len]
+            .strip_suffix(b"\r")
+            .unwrap_or(&protocol[..len]);
+        let args = Self::split_inline_args(line)?
+            .into_iter()
+            .map(|arg| Protocol::BulkString(Bytes::from(arg)))
+            .collect();
+        Ok(Some((Protocol::Array(args), len + 1)))
+    }
+
+    // follows the quoting rules of sdssplitargs in Redis
+    fn split_inline_args(line: &[u8]) -> Result<Vec<Vec<u8>>, DBError> {
+        let unbalanced = || DBError("Protocol error: unbalanced quotes in request".to_string());
+        let mut args = Vec::new();
+        let mut i = 0;
+        loop {
+            while i < line.len() && line[i].is_ascii_whitespace() {
+                i += 1;
+            }
+            if i == line.len() {
+                return Ok(args);
+            }
+            let mut arg = Vec::new();
+            match line[i] {
+                b'"' => {
+                    i += 1;
+                    loop {
+                        match line.get(i) {
+                            None => return Err(unbalanced()),
+                            Some(b'\\') if i + 1 < line.len() => {
+                                let hex = line
+                                    .get(i + 2..i + 4)
+                                    .filter(|_| line[i + 1] == b'x')
+                                    .and_then(|h| std::str::from_utf8(h).ok())
+                                    .and_then(|h| u8::from_str_radix(h, 16).ok());
+                                if let Some(b) = hex {
+                                    arg.push(b);
+                                    i += 4;
+                                    continue;
+                                }
+                                arg.push(match line[i + 1] {
+                                    b'n' => b'\n',
+                                    b'r' => b'\r',
+                                    b't' => b'\t',
+                                    b'b' => 0x08,
+                                    b'a' => 0x07,
+                                    c => c,
+                                });
+                                i += 2;
+                            }
+                            Some(b'"') => {
+                                // the closing quote must be followed by a space or nothing
+                                if line.get(i + 1).is_some_and(|c| !c.is_ascii_whitespace()) {
+                                    return Err(unbalanced());
+                                }
+                                i += 1;
+                                break;
+                            }
+                            Some(c) => {
+                                arg.push(*c);
+                                i += 1;
+                            }
+                        }
+                    }
+                }
+                b'\'' => {
+                    i += 1;
+                    loop {
+                        match line.get(i) {
+                            None => return Err(unbalanced()),
+                            Some(b'\\') if line.get(i + 1) == Some(&b'\'') => {
+                                arg.push(b'\'');
+                                i += 2;
+                            }
+                            Some(b'\'') => {
+                                if line.get(i + 1).is_some_and(|c| !c.is_ascii_whitespace()) {
+                                    return Err(unbalanced());
+                                }
+                                i += 1;
+                                break;
+                            }
+                            Some(c) => {
+                                arg.push(*c);
+                                i += 1;
+                            }
+                        }
+                    }
+                }
+                _ => {
+                    while i < line.len() && !line[i].is_ascii_whitespace() {
+                        arg.push(line[i]);
+                        i += 1;
+                    }
+                }
+            }
+            args.push(arg);
         }
     }
 
     pub fn from_vec(array: Vec<&str>) -> Self {
         let array = array
             .into_iter()
-            .map(|x| Protocol::BulkString(x.to_string()))
+            .map(|x| Protocol::BulkString(Bytes::copy_from_slice(x.as_bytes())))
             .collect();
         Protocol::Array(array)
     }
 
+    #[inline]
+    pub fn bulk(s: impl Into<Bytes>) -> Self {
+        Protocol::BulkString(s.into())
+    }
+
+    // the SELECT command written ahead of replicated and logged writes
+    pub fn select(db: usize) -> Self {
+        Protocol::Array(vec![
+            Protocol::bulk("SELECT"),
+            Protocol::bulk(db.to_string()),
+        ])
+    }
+
     #[inline]
     pub fn ok() -> Self {
-        Protocol::SimpleString("ok".to_string())
+        Protocol::SimpleString("OK".to_string())
     }
 
     #[inline]
     pub fn err(msg: &str) -> Self {
-        Protocol::SimpleString(msg.to_string())
+        Protocol::Error(msg.to_string())
+    }
+
+    #[inline]
+    pub fn unknown_cmd_err() -> Self {
+        Self::err("ERR unknown command")
     }
 
     #[inline]
     pub fn write_on_slave_err() -> Self {
-        Self::err("DISALLOW WRITE ON SLAVE")
+        Self::err("READONLY You can't write against a read only replica.")
+    }
+
+    #[inline]
+    pub fn wrong_type_err() -> Self {
+        Self::err("WRONGTYPE Operation against a key holding the wrong kind of value")
+    }
+
+    #[inline]
+    pub fn oom_err() -> Self {
+        Self::err("OOM command not allowed when used memory > 'maxmemory'.")
     }
 
     #[inline]
     pub fn psync_on_slave_err() -> Self {
-        Self::err("PSYNC ON SLAVE IS NOT ALLOWED")
+        Self::err("ERR PSYNC ON SLAVE IS NOT ALLOWED")
     }
 
     #[inline]
@@ -69,108 +255,364 @@ impl Protocol {
     pub fn decode(&self) -> String {
         match self {
             Protocol::SimpleString(s) => s.to_string(),
-            Protocol::BulkString(s) => s.to_string(),
-            Protocol::Null => "".to_string(),
-            Protocol::Array(s) => s.iter().map(|x| x.decode()).collect::<Vec<_>>().join(" "),
+            Protocol::BulkString(s) => String::from_utf8_lossy(s).to_string(),
+            Protocol::Integer(i) => i.to_string(),
+            Protocol::Error(s) => s.to_string(),
+            Protocol::Null | Protocol::NullArray => "".to_string(),
+            Protocol::Array(s) | Protocol::Set(s) | Protocol::Push(s) => {
+                s.iter().map(|x| x.decode()).collect::<Vec<_>>().join(" ")
+            }
+            Protocol::Map(m) => m
+                .iter()
+                .map(|(k, v)| format!("{} {}", k.decode(), v.decode()))
+                .collect::<Vec<_>>()
+                .join(" "),
+            Protocol::Double(d) => Self::format_double(*d),
+            Protocol::Boolean(b) => b.to_string(),
+            Protocol::BigNumber(n) => n.to_string(),
+            Protocol::VerbatimString(_, s) => String::from_utf8_lossy(s).to_string(),
+            Protocol::Attribute(_, p) => p.decode(),
         }
     }
 
-    pub fn encode(&self) -> String {
+    /// Encodes the value as RESP2, replacing RESP3 only types with their RESP2 counterparts.
+    pub fn encode(&self) -> Vec<u8> {
+        self.encode_with(RESP2)
+    }
+
+    pub fn encode_with(&self, version: u8) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode_to(&mut buf, version >= RESP3);
+        buf
+    }
+
+    fn encode_to(&self, buf: &mut Vec<u8>, resp3: bool) {
         match self {
-            Protocol::SimpleString(s) => format!("+{}\r\n", s),
-            Protocol::BulkString(s) => format!("${}\r\n{}\r\n", s.len(), s),
-            Protocol::Array(ss) => {
-                format!("*{}\r\n", ss.len())
-                    + ss.iter()
-                        .map(|x| x.encode())
-                        .collect::<Vec<_>>()
-                        .join("")
-                        .as_str()
+            Protocol::SimpleString(s) => Self::encode_line(buf, b'+', s.as_bytes()),
+            Protocol::Error(s) => Self::encode_line(buf, b'-', s.as_bytes()),
+            Protocol::Integer(i) => Self::encode_line(buf, b':', i.to_string().as_bytes()),
+            Protocol::BulkString(s) => Self::encode_blob(buf, b'$', s),
+            Protocol::Array(ss) => Self::encode_aggregate(buf, b'*', ss, resp3),
+            Protocol::Null if resp3 => buf.extend_from_slice(b"_\r\n"),
+            Protocol::Null => buf.extend_from_slice(b"$-1\r\n"),
+            Protocol::NullArray if resp3 => buf.extend_from_slice(b"_\r\n"),
+            Protocol::NullArray => buf.extend_from_slice(b"*-1\r\n"),
+            Protocol::Map(m) => {
+                let (prefix, len) = if resp3 {
+                    (b'%', m.len())
+                } else {
+                    (b'*', m.len() * 2)
+                };
+                Self::encode_line(buf, prefix, len.to_string().as_bytes());
+                for (k, v) in m {
+                    k.encode_to(buf, resp3);
+                    v.encode_to(buf, resp3);
+                }
             }
-            Protocol::Null => "$-1\r\n".to_string(),
+            Protocol::Set(ss) if resp3 => Self::encode_aggregate(buf, b'~', ss, resp3),
+            Protocol::Set(ss) => Self::encode_aggregate(buf, b'*', ss, resp3),
+            Protocol::Double(d) if resp3 => {
+                Self::encode_line(buf, b',', Self::format_double(*d).as_bytes())
+            }
+            Protocol::Double(d) => Self::encode_blob(buf, b'$', Self::format_double(*d).as_bytes()),
+            Protocol::Boolean(b) if resp3 => {
+                Self::encode_line(buf, b'#', if *b { b"t" } else { b"f" })
+            }
+            Protocol::Boolean(b) => Self::encode_line(buf, b':', if *b { b"1" } else { b"0" }),
+            Protocol::BigNumber(n) if resp3 => Self::encode_line(buf, b'(', n.as_bytes()),
+            Protocol::BigNumber(n) => Self::encode_blob(buf, b'$', n.as_bytes()),
+            Protocol::VerbatimString(format, s) if resp3 => {
+                let mut data = Vec::with_capacity(s.len() + 4);
+                data.extend_from_slice(format.as_bytes());
+                data.push(b':');
+                data.extend_from_slice(s);
+                Self::encode_blob(buf, b'=', &data)
+            }
+            Protocol::VerbatimString(_, s) => Self::encode_blob(buf, b'$', s),
+            Protocol::Attribute(attrs, p) => {
+                // RESP2 has no way to carry attributes, so only the reply itself is sent
+                if resp3 {
+                    Self::encode_line(buf, b'|', attrs.len().to_string().as_bytes());
+                    for (k, v) in attrs {
+                        k.encode_to(buf, resp3);
+                        v.encode_to(buf, resp3);
+                    }
+                }
+                p.encode_to(buf, resp3);
+            }
+            Protocol::Push(ss) if resp3 => Self::encode_aggregate(buf, b'>', ss, resp3),
+            Protocol::Push(ss) => Self::encode_aggregate(buf, b'*', ss, resp3),
         }
     }
 
-    fn parse_simple_string_sfx(protocol: &str) -> Result<(Self, usize), DBError> {
-        match protocol.find("\r\n") {
-            Some(x) => Ok((Self::SimpleString(protocol[..x].to_string()), x + 2)),
-            _ => Err(DBError(format!(
-                "[new simple string] unsupported protocol: {:?}",
-                protocol
+    fn encode_line(buf: &mut Vec<u8>, prefix: u8, s: &[u8]) {
+        buf.push(prefix);
+        buf.extend_from_slice(s);
+        buf.extend_from_slice(b"\r\n");
+    }
+
+    fn encode_blob(buf: &mut Vec<u8>, prefix: u8, s: &[u8]) {
+        Self::encode_line(buf, prefix, s.len().to_string().as_bytes());
+        buf.extend_from_slice(s);
+        buf.extend_from_slice(b"\r\n");
+    }
+
+    fn encode_aggregate(buf: &mut Vec<u8>, prefix: u8, ss: &[Protocol], resp3: bool) {
+        Self::encode_line(buf, prefix, ss.len().to_string().as_bytes());
+        for s in ss {
+            s.encode_to(buf, resp3);
+        }
+    }
+
+    fn format_double(d: f64) -> String {
+        if d.is_nan() {
+            "nan".to_string()
+        } else if d.is_infinite() {
+            if d > 0.0 { "inf" } else { "-inf" }.to_string()
+        } else {
+            d.to_string()
+        }
+    }
+
+    fn parse_line(protocol: &[u8]) -> Option<(String, usize)> {
+        Self::find_crlf(protocol)
+            .map(|x| (String::from_utf8_lossy(&protocol[..x]).to_string(), x + 2))
+    }
+
+    fn parse_simple_string_sfx(protocol: &[u8]) -> Result<Option<(Self, usize)>, DBError> {
+        Ok(Self::parse_line(protocol).map(|(s, len)| (Self::SimpleString(s), len)))
+    }
+
+    fn parse_error_sfx(protocol: &[u8]) -> Result<Option<(Self, usize)>, DBError> {
+        Ok(Self::parse_line(protocol).map(|(s, len)| (Self::Error(s), len)))
+    }
+
+    fn parse_big_number_sfx(protocol: &[u8]) -> Result<Option<(Self, usize)>, DBError> {
+        Ok(Self::parse_line(protocol).map(|(s, len)| (Self::BigNumber(s), len)))
+    }
+
+    fn parse_integer_sfx(protocol: &[u8]) -> Result<Option<(Self, usize)>, DBError> {
+        match Self::find_crlf(protocol) {
+            Some(x) => Ok(Some((
+                Self::Integer(Self::parse_i64(&protocol[..x])?),
+                x + 2,
             ))),
+            None => Ok(None),
         }
     }
 
-    fn parse_bulk_string_sfx(protocol: &str) -> Result<(Self, usize), DBError> {
-        if let Some(len) = protocol.find("\r\n") {
-            let size = Self::parse_usize(&protocol[..len])?;
-            if let Some(data_len) = protocol[len + 2..].find("\r\n") {
-                let s = Self::parse_string(&protocol[len + 2..len + 2 + data_len])?;
-                if size != s.len() {
-                    Err(DBError(format!(
-                        "[new bulk string] unmatched string length in prototocl {:?}",
-                        protocol,
-                    )))
-                } else {
-                    Ok((
-                        Protocol::BulkString(s.to_lowercase()),
-                        len + 2 + data_len + 2,
-                    ))
-                }
-            } else {
-                Err(DBError(format!(
-                    "[new bulk string] unsupported protocol: {:?}",
-                    protocol
-                )))
+    fn parse_null_sfx(protocol: &[u8]) -> Result<Option<(Self, usize)>, DBError> {
+        match Self::find_crlf(protocol) {
+            Some(0) => Ok(Some((Self::Null, 2))),
+            Some(_) => Err(DBError(format!(
+                "[new null] unsupported protocol: {:?}",
+                String::from_utf8_lossy(protocol)
+            ))),
+            None => Ok(None),
+        }
+    }
+
+    fn parse_double_sfx(protocol: &[u8]) -> Result<Option<(Self, usize)>, DBError> {
+        match Self::parse_line(protocol) {
+            Some((s, len)) => {
+                let d = match s.as_str() {
+                    "inf" => f64::INFINITY,
+                    "-inf" => f64::NEG_INFINITY,
+                    _ => s
+                        .parse::<f64>()
+                        .map_err(|_| DBError(format!("parse double error: {}", s)))?,
+                };
+                Ok(Some((Self::Double(d), len)))
             }
-        } else {
-            Err(DBError(format!(
-                "[new bulk string] unsupported protocol: {:?}",
-                protocol
-            )))
-        }
-    }
-
-    fn parse_array_sfx(s: &str) -> Result<(Self, usize), DBError> {
-        let mut offset = 0;
-        match s.find("\r\n") {
-            Some(x) => {
-                let array_len = s[..x].parse::<usize>()?;
-                offset += x + 2;
-                let mut vec = vec![];
-                for _ in 0..array_len {
-                    match Protocol::from(&s[offset..]) {
-                        Ok((p, len)) => {
-                            offset += len;
-                            vec.push(p);
-                        }
-                        Err(e) => {
-                            return Err(e);
-                        }
-                    }
+            None => Ok(None),
+        }
+    }
+
+    fn parse_boolean_sfx(protocol: &[u8]) -> Result<Option<(Self, usize)>, DBError> {
+        match Self::parse_line(protocol) {
+            Some((s, len)) => match s.as_str() {
+                "t" => Ok(Some((Self::Boolean(true), len))),
+                "f" => Ok(Some((Self::Boolean(false), len))),
+                _ => Err(DBError(format!("parse boolean error: {}", s))),
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn parse_bulk_string_sfx(protocol: &[u8]) -> Result<Option<(Self, usize)>, DBError> {
+        Ok(Self::parse_blob(protocol)?.map(|(s, len)| match s {
+            Some(s) => (Protocol::BulkString(s), len),
+            None => (Protocol::Null, len),
+        }))
+    }
+
+    fn parse_verbatim_string_sfx(protocol: &[u8]) -> Result<Option<(Self, usize)>, DBError> {
+        match Self::parse_blob(protocol)? {
+            Some((Some(s), len)) if s.len() >= 4 && s[3] == b':' => Ok(Some((
+                Protocol::VerbatimString(
+                    String::from_utf8_lossy(&s[..3]).to_string(),
+                    s.slice(4..),
+                ),
+                len,
+            ))),
+            Some(_) => Err(DBError(format!(
+                "[new verbatim string] unsupported protocol: {:?}",
+                String::from_utf8_lossy(protocol)
+            ))),
+            None => Ok(None),
+        }
+    }
+
+    /// Parses `<len>\r\n<bytes>\r\n`, where a length of -1 yields `Some((None, _))`.
+    fn parse_blob(protocol: &[u8]) -> Result<ParsedBlob, DBError> {
+        let len = match Self::find_length_line(protocol)? {
+            Some(len) => len,
+            None => return Ok(None),
+        };
+        // a negative length denotes the null bulk string
+        if &protocol[..len] == b"-1" {
+            return Ok(Some((None, len + 2)));
+        }
+        let size = Self::parse_usize(&protocol[..len])?;
+        let start = len + 2;
+        let end = match start.checked_add(size) {
+            Some(end) if size <= PROTO_MAX_BULK_LEN => end,
+            _ => return Err(DBError("Protocol error: invalid bulk length".to_string())),
+        };
+        if protocol.len() < end + 2 {
+            return Ok(None);
+        }
+        if &protocol[end..end + 2] != b"\r\n" {
+            return Err(DBError(format!(
+                "[new bulk string] unmatched string length in protocol {:?}",
+                String::from_utf8_lossy(protocol),
+            )));
+        }
+        Ok(Some((
+            Some(Bytes::copy_from_slice(&protocol[start..end])),
+            end + 2,
+        )))
+    }
+
+    fn parse_array_sfx(s: &[u8]) -> Result<Option<(Self, usize)>, DBError> {
+        Ok(Self::parse_aggregate(s, 1)?.map(|(vec, len)| match vec {
+            Some(vec) => (Protocol::Array(vec), len),
+            None => (Protocol::NullArray, len),
+        }))
+    }
+
+    fn parse_set_sfx(s: &[u8]) -> Result<Option<(Self, usize)>, DBError> {
+        Ok(Self::parse_aggregate(s, 1)?
+            .map(|(vec, len)| (Protocol::Set(vec.unwrap_or_default()), len)))
+    }
+
+    fn parse_push_sfx(s: &[u8]) -> Result<Option<(Self, usize)>, DBError> {
+        Ok(Self::parse_aggregate(s, 1)?
+            .map(|(vec, len)| (Protocol::Push(vec.unwrap_or_default()), len)))
+    }
+
+    fn parse_map_sfx(s: &[u8]) -> Result<Option<(Self, usize)>, DBError> {
+        Ok(Self::parse_aggregate(s, 2)?
+            .map(|(vec, len)| (Protocol::Map(Self::pairs(vec.unwrap_or_default())), len)))
+    }
+
+    fn parse_attribute_sfx(s: &[u8]) -> Result<Option<(Self, usize)>, DBError> {
+        let (attrs, offset) = match Self::parse_aggregate(s, 2)? {
+            Some((attrs, offset)) => (Self::pairs(attrs.unwrap_or_default()), offset),
+            None => return Ok(None),
+        };
+        // an attribute is always followed by the reply it describes
+        Ok(Protocol::from(&s[offset..])?
+            .map(|(p, len)| (Protocol::Attribute(attrs, Box::new(p)), offset + len)))
+    }
+
+    /// Parses the `<count>\r\n` that opens an aggregate, returning the count, `None` for a
+    /// count of -1, and the number of bytes the line occupies.
+    pub fn parse_aggregate_len(s: &[u8]) -> Result<Option<(Option<usize>, usize)>, DBError> {
+        let x = match Self::find_length_line(s)? {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        // a negative length denotes the null array
+        if &s[..x] == b"-1" {
+            return Ok(Some((None, x + 2)));
+        }
+        match Self::parse_usize(&s[..x])? {
+            n if n <= PROTO_MAX_MULTIBULK_LEN => Ok(Some((Some(n), x + 2))),
+            _ => Err(DBError(
+                "Protocol error: invalid multibulk length".to_string(),
+            )),
+        }
+    }
+
+    /// Parses `<count>\r\n` followed by `count * per_entry` frames, where a count of -1
+    /// yields `Some((None, _))`.
+    fn parse_aggregate(s: &[u8], per_entry: usize) -> Result<ParsedAggregate, DBError> {
+        let (array_len, mut offset) = match Self::parse_aggregate_len(s)? {
+            Some((Some(n), offset)) => (n * per_entry, offset),
+            Some((None, offset)) => return Ok(Some((None, offset))),
+            None => return Ok(None),
+        };
+        let mut vec = vec![];
+        for _ in 0..array_len {
+            match Protocol::from(&s[offset..])? {
+                Some((p, len)) => {
+                    offset += len;
+                    vec.push(p);
                 }
-                Ok((Protocol::Array(vec), offset))
+                None => return Ok(None),
             }
-            _ => Err(DBError(format!(
-                "[new array] unsupported protocol: {:?}",
-                s
-            ))),
         }
+        Ok(Some((Some(vec), offset)))
     }
 
-    fn parse_usize(protocol: &str) -> Result<usize, DBError> {
-        match protocol.len() {
-            0 => Err(DBError(format!("parse usize error: {:?}", protocol))),
-            _ => Ok(protocol
-                .parse::<usize>()
-                .map_err(|_| DBError(format!("parse usize error: {}", protocol)))?),
+    fn pairs(vec: Vec<Protocol>) -> Vec<(Protocol, Protocol)> {
+        let mut pairs = Vec::with_capacity(vec.len() / 2);
+        let mut iter = vec.into_iter();
+        while let (Some(k), Some(v)) = (iter.next(), iter.next()) {
+            pairs.push((k, v));
+        }
+        pairs
+    }
+
+    fn find_crlf(protocol: &[u8]) -> Option<usize> {
+        protocol.windows(2).position(|w| w == b"\r\n")
+    }
+
+    // the end of a length line, which may not grow past the inline limit while it is incomplete
+    fn find_length_line(protocol: &[u8]) -> Result<Option<usize>, DBError> {
+        match Self::find_crlf(protocol) {
+            None if protocol.len() > PROTO_INLINE_MAX_SIZE => {
+                Err(DBError("Protocol error: too big length line".to_string()))
+            }
+            found => Ok(found),
         }
     }
 
-    fn parse_string(protocol: &str) -> Result<String, DBError> {
+    fn parse_usize(protocol: &[u8]) -> Result<usize, DBError> {
         match protocol.len() {
             0 => Err(DBError(format!("parse usize error: {:?}", protocol))),
-            _ => Ok(protocol.to_string()),
+            _ => std::str::from_utf8(protocol)
+                .ok()
+                .and_then(|s| s.parse::<usize>().ok())
+                .ok_or_else(|| {
+                    DBError(format!(
+                        "parse usize error: {}",
+                        String::from_utf8_lossy(protocol)
+                    ))
+                }),
         }
     }
+
+    fn parse_i64(protocol: &[u8]) -> Result<i64, DBError> {
+        std::str::from_utf8(protocol)
+            .ok()
+            .and_then(|s| s.parse::<i64>().ok())
+            .ok_or_else(|| {
+                DBError(format!(
+                    "parse integer error: {}",
+                    String::from_utf8_lossy(protocol)
+                ))
+            })
+    }
 }
@@ -1,11 +1,29 @@
-// parse Redis RDB file format: https://rdb.fnordig.de/file_format.html
+// parse and write Redis RDB file format: https://rdb.fnordig.de/file_format.html
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    path::{Path, PathBuf},
+    sync::atomic::Ordering,
+};
 
 use tokio::{
     fs,
     io::{AsyncRead, AsyncReadExt, BufReader},
 };
 
-use crate::{error::DBError, server::Server};
+use crate::{
+    crc64::{self, Crc64Reader},
+    error::DBError,
+    hash::Hash,
+    intset, listpack, lzf,
+    options::ReplicationOption,
+    server::Server,
+    storage::{now_in_millis, Value, ValueType},
+    stream::{Consumer, ConsumerGroup, PendingEntry, Stream, StreamId},
+    ziplist, zipmap,
+    zset::ZSet,
+};
+use bytes::Bytes;
 
 use futures::pin_mut;
 
@@ -14,7 +32,7 @@ enum StringEncoding {
     I8,
     I16,
     I32,
-    LZF,
+    Lzf,
 }
 
 // RDB file format.
@@ -22,48 +40,163 @@ const MAGIC: &[u8; 5] = b"REDIS";
 const META: u8 = 0xFA;
 const DB_SELECT: u8 = 0xFE;
 const TABLE_SIZE_INFO: u8 = 0xFB;
+const EXPIRE_TIME_MS: u8 = 0xFC;
+const EXPIRE_TIME: u8 = 0xFD;
+const IDLE: u8 = 0xF8;
+const FREQ: u8 = 0xF9;
 pub const EOF: u8 = 0xFF;
+const RDB_VERSION: &[u8; 4] = b"0011";
+// the first version with hash field expiration, only written when a field has a TTL
+const RDB_VERSION_HASH_FIELD_TTL: &[u8; 4] = b"0012";
+
+// value types
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_ZSET: u8 = 3;
+const TYPE_HASH: u8 = 4;
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_HASH_ZIPMAP: u8 = 9;
+const TYPE_LIST_ZIPLIST: u8 = 10;
+const TYPE_SET_INTSET: u8 = 11;
+const TYPE_ZSET_ZIPLIST: u8 = 12;
+const TYPE_HASH_ZIPLIST: u8 = 13;
+const TYPE_LIST_QUICKLIST: u8 = 14;
+const TYPE_HASH_LISTPACK: u8 = 16;
+const TYPE_ZSET_LISTPACK: u8 = 17;
+const TYPE_LIST_QUICKLIST_2: u8 = 18;
+const TYPE_STREAM_LISTPACKS: u8 = 15;
+const TYPE_STREAM_LISTPACKS_2: u8 = 19;
+const TYPE_SET_LISTPACK: u8 = 20;
+const TYPE_STREAM_LISTPACKS_3: u8 = 21;
+const TYPE_HASH_METADATA_PRE_GA: u8 = 22;
+const TYPE_HASH_LISTPACK_EX_PRE_GA: u8 = 23;
+const TYPE_HASH_METADATA: u8 = 24;
+const TYPE_HASH_LISTPACK_EX: u8 = 25;
+
+// stream listpack entry flags
+const STREAM_ITEM_FLAG_DELETED: i64 = 1;
+const STREAM_ITEM_FLAG_SAMEFIELDS: i64 = 2;
+// entries per stream listpack node, Redis' stream-node-max-entries default
+const STREAM_NODE_MAX_ENTRIES: usize = 100;
+
+// quicklist 2 node containers
+const QUICKLIST_NODE_PLAIN: u64 = 1;
+const QUICKLIST_NODE_PACKED: u64 = 2;
+
+/// Aux fields and key counts of the last RDB file loaded.
+#[derive(Debug, Clone, Default)]
+pub struct RdbLoadInfo {
+    pub redis_ver: Option<String>,
+    pub ctime: Option<u64>,
+    pub used_mem: Option<u64>,
+    pub repl_id: Option<String>,
+    pub repl_offset: Option<u64>,
+    pub keys_loaded: u64,
+    // keys already past their expiry, a master drops them while loading
+    pub keys_expired: u64,
+}
+
+impl RdbLoadInfo {
+    fn record_aux(&mut self, k: &str, v: String) {
+        match k {
+            "redis-ver" => self.redis_ver = Some(v),
+            "ctime" => self.ctime = v.parse().ok(),
+            "used-mem" => self.used_mem = v.parse().ok(),
+            "repl-id" => self.repl_id = Some(v),
+            "repl-offset" => self.repl_offset = v.parse().ok(),
+            // anything else is informational only, like redis-bits or aof-base
+            _ => {}
+        }
+    }
+}
+
+// (database index, live keys) for every database that has keys
+pub type DbSnapshot = Vec<(usize, Vec<(Bytes, ValueType)>)>;
 
 pub async fn parse_rdb<R: AsyncRead + Unpin>(
     reader: &mut R,
     server: &mut Server,
 ) -> Result<(), DBError> {
-    let mut storage = server.storage.lock().await;
-    parse_magic(reader).await?;
-    let _version = parse_version(reader).await?;
+    // checksum everything up to the EOF opcode
+    let mut reader = Crc64Reader::new(reader);
+    parse_magic(&mut reader).await?;
+    let _version = parse_version(&mut reader).await?;
     pin_mut!(reader);
+
+    let mut info = RdbLoadInfo::default();
+    let mut db = 0;
+    // set by an expire opcode, applies to the key that follows it
+    let mut expire_at = None;
+    let now = now_in_millis();
     loop {
         let op = reader.read_u8().await?;
         match op {
             META => {
-                let _ = parse_aux(&mut *reader).await?;
-                let _ = parse_aux(&mut *reader).await?;
-                // just ignore the aux info for now
+                let k = parse_aux(&mut *reader).await?;
+                let v = parse_aux(&mut *reader).await?;
+                info.record_aux(&k, v);
             }
             DB_SELECT => {
-                let (_, _) = parse_len(&mut *reader).await?;
-                // just ignore the db index for now
+                let index = parse_len(&mut *reader).await?.0 as usize;
+                if index >= server.dbs.len() {
+                    return Err(DBError(format!(
+                        "RDB selects DB {} but only {} databases are configured",
+                        index,
+                        server.dbs.len()
+                    )));
+                }
+                db = index;
             }
             TABLE_SIZE_INFO => {
-                let size_no_expire = parse_len(&mut *reader).await?.0;
-                let size_expire = parse_len(&mut *reader).await?.0;
-                for _ in 0..size_no_expire {
-                    let (k, v) = parse_no_expire_entry(&mut *reader).await?;
-                    storage.set(k, v);
-                }
-                for _ in 0..size_expire {
-                    let (k, v, expire_timestamp) = parse_expire_entry(&mut *reader).await?;
-                    storage.setx(k, v, expire_timestamp);
-                }
+                // only a hint for presizing the keyspace and expires tables
+                let _db_size = parse_len(&mut *reader).await?;
+                let _expires_size = parse_len(&mut *reader).await?;
+            }
+            EXPIRE_TIME_MS => {
+                expire_at = Some(reader.read_u64_le().await? as u128);
+            }
+            EXPIRE_TIME => {
+                expire_at = Some(reader.read_u32_le().await? as u128 * 1000);
+            }
+            IDLE => {
+                let _idle_secs = parse_len(&mut *reader).await?;
+            }
+            FREQ => {
+                let _lfu_freq = reader.read_u8().await?;
             }
             EOF => {
-                // not verify crc for now
-                let _crc = reader.read_u64().await?;
+                let computed = reader.crc();
+                let expected = reader.read_u64_le().await?;
+                // a zero checksum means the file was written with checksums disabled
+                if expected != 0 && !server.option.skip_rdb_checksum && expected != computed {
+                    return Err(DBError(format!(
+                        "wrong RDB checksum, expected: {:016x}, got: {:016x}",
+                        expected, computed
+                    )));
+                }
                 break;
             }
+            value_type @ TYPE_STRING..=TYPE_HASH_LISTPACK_EX => {
+                let k = parse_value(&mut *reader).await?;
+                let v = parse_object(&mut *reader, value_type).await?;
+                let expire_at = expire_at.take();
+                // replicas keep expired keys and wait for the master's DEL
+                if server.is_master() && expire_at.is_some_and(|t| t < now) {
+                    info.keys_expired += 1;
+                    continue;
+                }
+                // an empty collection could not be popped from, Redis skips it too
+                if v.is_empty_collection() {
+                    continue;
+                }
+                server.dbs[db].lock().await.insert(k, v, expire_at);
+                info.keys_loaded += 1;
+            }
             _ => return Err(DBError(format!("unexpected op: {}", op))),
         }
     }
+    *server.rdb_load_info.lock().await = info;
     Ok(())
 }
 
@@ -72,37 +205,344 @@ pub async fn parse_rdb_file(f: &mut fs::File, server: &mut Server) -> Result<(),
     parse_rdb(&mut reader, server).await
 }
 
-async fn parse_no_expire_entry<R: AsyncRead + Unpin>(
+async fn parse_object<R: AsyncRead + Unpin>(
     input: &mut R,
-) -> Result<(String, String), DBError> {
-    let b = input.read_u8().await?;
-    if b != 0 {
-        return Err(DBError(format!("unexpected key type: {}", b)));
-    }
-    let k = parse_aux(input).await?;
-    let v = parse_aux(input).await?;
-    Ok((k, v))
+    value_type: u8,
+) -> Result<Value, DBError> {
+    let v = match value_type {
+        TYPE_STRING => Value::String(parse_value(input).await?),
+        TYPE_LIST => Value::List(parse_values(input).await?.into()),
+        TYPE_SET => Value::Set(parse_values(input).await?.into_iter().collect()),
+        TYPE_ZSET | TYPE_ZSET_2 => {
+            let len = parse_len(input).await?.0;
+            let mut zset = ZSet::default();
+            for _ in 0..len {
+                let member = parse_value(input).await?;
+                let score = if value_type == TYPE_ZSET_2 {
+                    f64::from_le_bytes(input.read_u64_le().await?.to_le_bytes())
+                } else {
+                    parse_double(input).await?
+                };
+                zset.insert(member, score);
+            }
+            Value::ZSet(zset)
+        }
+        TYPE_HASH => {
+            let len = parse_len(input).await?.0;
+            let mut hash = Hash::default();
+            for _ in 0..len {
+                let field = parse_value(input).await?;
+                let value = parse_value(input).await?;
+                hash.insert(field, value);
+            }
+            Value::Hash(hash)
+        }
+        TYPE_HASH_ZIPMAP => Value::Hash(
+            zipmap::decode(&parse_value(input).await?)?
+                .into_iter()
+                .collect(),
+        ),
+        TYPE_LIST_ZIPLIST => Value::List(ziplist::decode(&parse_value(input).await?)?.into()),
+        TYPE_SET_INTSET => Value::Set(
+            intset::decode(&parse_value(input).await?)?
+                .into_iter()
+                .map(|i| Bytes::from(i.to_string()))
+                .collect(),
+        ),
+        TYPE_ZSET_ZIPLIST => zset_from_entries(ziplist::decode(&parse_value(input).await?)?)?,
+        TYPE_HASH_ZIPLIST => hash_from_entries(ziplist::decode(&parse_value(input).await?)?)?,
+        TYPE_LIST_QUICKLIST => {
+            let nodes = parse_len(input).await?.0;
+            let mut list = VecDeque::new();
+            for _ in 0..nodes {
+                list.extend(ziplist::decode(&parse_value(input).await?)?);
+            }
+            Value::List(list)
+        }
+        TYPE_HASH_LISTPACK => hash_from_entries(listpack::decode(&parse_value(input).await?)?)?,
+        TYPE_ZSET_LISTPACK => zset_from_entries(listpack::decode(&parse_value(input).await?)?)?,
+        TYPE_LIST_QUICKLIST_2 => {
+            let nodes = parse_len(input).await?.0;
+            let mut list = VecDeque::new();
+            for _ in 0..nodes {
+                let container = parse_len(input).await?.0;
+                let node = parse_value(input).await?;
+                match container {
+                    // a single element too big to share a listpack
+                    QUICKLIST_NODE_PLAIN => list.push_back(node),
+                    QUICKLIST_NODE_PACKED => list.extend(listpack::decode(&node)?),
+                    _ => {
+                        return Err(DBError(format!(
+                            "unexpected quicklist container: {}",
+                            container
+                        )))
+                    }
+                }
+            }
+            Value::List(list)
+        }
+        TYPE_SET_LISTPACK => Value::Set(
+            listpack::decode(&parse_value(input).await?)?
+                .into_iter()
+                .collect(),
+        ),
+        TYPE_STREAM_LISTPACKS | TYPE_STREAM_LISTPACKS_2 | TYPE_STREAM_LISTPACKS_3 => {
+            Value::Stream(parse_stream(input, value_type).await?)
+        }
+        TYPE_HASH_METADATA | TYPE_HASH_METADATA_PRE_GA => {
+            // the GA format stores each TTL relative to the smallest one, plus one so that zero
+            // still means no TTL
+            let min_expire = if value_type == TYPE_HASH_METADATA {
+                Some(input.read_u64_le().await? as u128)
+            } else {
+                None
+            };
+            let len = parse_len(input).await?.0;
+            let mut hash = Hash::default();
+            for _ in 0..len {
+                let ttl = parse_len(input).await?.0 as u128;
+                let field = parse_value(input).await?;
+                let value = parse_value(input).await?;
+                hash.insert(field.clone(), value);
+                if ttl != 0 {
+                    hash.set_expire(&field, min_expire.map_or(ttl, |min| ttl + min - 1));
+                }
+            }
+            Value::Hash(hash)
+        }
+        TYPE_HASH_LISTPACK_EX | TYPE_HASH_LISTPACK_EX_PRE_GA => {
+            if value_type == TYPE_HASH_LISTPACK_EX {
+                let _min_expire = input.read_u64_le().await?;
+            }
+            // field, value, expire time triplets, with a zero expire time for no TTL
+            let entries = listpack::decode(&parse_value(input).await?)?;
+            if !entries.len().is_multiple_of(3) {
+                return Err(DBError(
+                    "hash listpack entries are not field, value, TTL triplets".to_string(),
+                ));
+            }
+            let mut hash = Hash::default();
+            for triplet in entries.chunks(3) {
+                let expire_at = std::str::from_utf8(&triplet[2])
+                    .ok()
+                    .and_then(|t| t.parse::<u128>().ok())
+                    .ok_or_else(|| DBError(format!("invalid hash field TTL: {:?}", triplet[2])))?;
+                hash.insert(triplet[0].clone(), triplet[1].clone());
+                if expire_at != 0 {
+                    hash.set_expire(&triplet[0], expire_at);
+                }
+            }
+            Value::Hash(hash)
+        }
+        _ => return Err(DBError(format!("unexpected value type: {}", value_type))),
+    };
+    Ok(v)
 }
 
-async fn parse_expire_entry<R: AsyncRead + Unpin>(
+// the radix tree of listpack nodes, then the stream metadata and its consumer groups
+async fn parse_stream<R: AsyncRead + Unpin>(
     input: &mut R,
-) -> Result<(String, String, u128), DBError> {
-    let b = input.read_u8().await?;
-    match b {
-        0xFC => {
-            // expire in milliseconds
-            let expire_stamp = input.read_u64_le().await?;
-            let (k, v) = parse_no_expire_entry(input).await?;
-            Ok((k, v, expire_stamp as u128))
+    value_type: u8,
+) -> Result<Stream, DBError> {
+    let mut stream = Stream::default();
+    let nodes = parse_len(input).await?.0;
+    for _ in 0..nodes {
+        let master_id = StreamId::from_be_bytes(&parse_value(input).await?)
+            .ok_or_else(|| DBError("invalid stream node key".to_string()))?;
+        let node = listpack::decode(&parse_value(input).await?)?;
+        parse_stream_node(master_id, &node, &mut stream)?;
+    }
+
+    let length = parse_len(input).await?.0;
+    stream.last_id = parse_stream_id(input).await?;
+    if value_type >= TYPE_STREAM_LISTPACKS_2 {
+        let _first_id = parse_stream_id(input).await?;
+        stream.max_deleted_id = parse_stream_id(input).await?;
+        stream.entries_added = parse_len(input).await?.0;
+    } else {
+        stream.entries_added = length;
+    }
+
+    let groups = parse_len(input).await?.0;
+    for _ in 0..groups {
+        let name = parse_value(input).await?;
+        let last_id = parse_stream_id(input).await?;
+        let entries_read = if value_type >= TYPE_STREAM_LISTPACKS_2 {
+            // saved as an unsigned length, so -1 comes back as u64::MAX
+            parse_len(input).await?.0 as i64
+        } else {
+            -1
+        };
+
+        let mut pending = BTreeMap::new();
+        for _ in 0..parse_len(input).await?.0 {
+            let id = parse_raw_stream_id(input).await?;
+            let delivery_time = input.read_u64_le().await?;
+            let delivery_count = parse_len(input).await?.0;
+            pending.insert(
+                id,
+                PendingEntry {
+                    consumer: Bytes::new(),
+                    delivery_time,
+                    delivery_count,
+                },
+            );
         }
-        0xFD => {
-            // expire in seconds
-            let expire_timestamp = input.read_u32_le().await?;
-            let (k, v) = parse_no_expire_entry(input).await?;
-            Ok((k, v, (expire_timestamp * 1000) as u128))
+
+        let mut consumers = Vec::new();
+        for _ in 0..parse_len(input).await?.0 {
+            let consumer = parse_value(input).await?;
+            let seen_time = input.read_u64_le().await?;
+            let active_time = if value_type >= TYPE_STREAM_LISTPACKS_3 {
+                input.read_u64_le().await?
+            } else {
+                seen_time
+            };
+            // the consumer's pending list only names entries of the group's list
+            for _ in 0..parse_len(input).await?.0 {
+                let id = parse_raw_stream_id(input).await?;
+                let entry = pending.get_mut(&id).ok_or_else(|| {
+                    DBError(format!("consumer pending entry {} not in the group", id))
+                })?;
+                entry.consumer = consumer.clone();
+            }
+            consumers.push(Consumer {
+                name: consumer,
+                seen_time,
+                active_time,
+            });
         }
-        _ => return Err(DBError(format!("unexpected expire type: {}", b))),
+
+        stream.groups.push(ConsumerGroup {
+            name,
+            last_id,
+            entries_read,
+            pending,
+            consumers,
+        });
     }
+    Ok(stream)
+}
+
+// A node starts with a master entry holding the live and deleted entry counts and the field
+// names of the first entry, then the entries with IDs relative to the node key. Entries with the
+// master fields only store their values.
+fn parse_stream_node(
+    master_id: StreamId,
+    node: &[Bytes],
+    stream: &mut Stream,
+) -> Result<(), DBError> {
+    let corrupt = || DBError("invalid stream listpack".to_string());
+    let mut it = node.iter();
+    let mut next = || it.next().ok_or_else(corrupt);
+    let int = |b: &Bytes| -> Result<i64, DBError> {
+        std::str::from_utf8(b)
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(corrupt)
+    };
+
+    let count = int(next()?)? + int(next()?)?;
+    let master_fields = (0..int(next()?)?)
+        .map(|_| next().cloned())
+        .collect::<Result<Vec<_>, _>>()?;
+    next()?; // master entry terminator
+
+    for _ in 0..count {
+        let flags = int(next()?)?;
+        let id = StreamId::new(
+            master_id.ms.wrapping_add(int(next()?)? as u64),
+            master_id.seq.wrapping_add(int(next()?)? as u64),
+        );
+        let fields = if flags & STREAM_ITEM_FLAG_SAMEFIELDS != 0 {
+            master_fields
+                .iter()
+                .map(|f| Ok((f.clone(), next()?.clone())))
+                .collect::<Result<Vec<_>, DBError>>()?
+        } else {
+            (0..int(next()?)?)
+                .map(|_| Ok((next()?.clone(), next()?.clone())))
+                .collect::<Result<Vec<_>, DBError>>()?
+        };
+        next()?; // number of listpack entries making up this entry
+        if flags & STREAM_ITEM_FLAG_DELETED == 0 {
+            stream.entries.insert(id, fields);
+        }
+    }
+    Ok(())
+}
+
+// an ID as two lengths
+async fn parse_stream_id<R: AsyncRead + Unpin>(input: &mut R) -> Result<StreamId, DBError> {
+    let ms = parse_len(input).await?.0;
+    let seq = parse_len(input).await?.0;
+    Ok(StreamId::new(ms, seq))
+}
+
+// an ID as 16 raw big-endian bytes, used in pending entry lists
+async fn parse_raw_stream_id<R: AsyncRead + Unpin>(input: &mut R) -> Result<StreamId, DBError> {
+    let ms = input.read_u64().await?;
+    let seq = input.read_u64().await?;
+    Ok(StreamId::new(ms, seq))
+}
+
+// a length prefixed sequence of strings
+async fn parse_values<R: AsyncRead + Unpin>(input: &mut R) -> Result<Vec<Bytes>, DBError> {
+    let len = parse_len(input).await?.0;
+    let mut values = Vec::with_capacity(len as usize);
+    for _ in 0..len {
+        values.push(parse_value(input).await?);
+    }
+    Ok(values)
+}
+
+// the score encoding of the original zset type: a length byte followed by the score in ASCII,
+// with the lengths 253, 254 and 255 standing for nan, +inf and -inf
+async fn parse_double<R: AsyncRead + Unpin>(input: &mut R) -> Result<f64, DBError> {
+    match input.read_u8().await? {
+        253 => Ok(f64::NAN),
+        254 => Ok(f64::INFINITY),
+        255 => Ok(f64::NEG_INFINITY),
+        len => {
+            let mut s = vec![0; len as usize];
+            input.read_exact(&mut s).await?;
+            parse_score(&s)
+        }
+    }
+}
+
+fn parse_score(s: &[u8]) -> Result<f64, DBError> {
+    std::str::from_utf8(s)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| {
+            DBError(format!(
+                "invalid zset score: {:?}",
+                Bytes::copy_from_slice(s)
+            ))
+        })
+}
+
+// compact hashes and zsets are flat lists of alternating field and value
+fn pairs(entries: Vec<Bytes>) -> Result<impl Iterator<Item = (Bytes, Bytes)>, DBError> {
+    if !entries.len().is_multiple_of(2) {
+        return Err(DBError(
+            "odd number of entries in compact encoding".to_string(),
+        ));
+    }
+    let mut it = entries.into_iter();
+    Ok(std::iter::from_fn(move || Some((it.next()?, it.next()?))))
+}
+
+fn hash_from_entries(entries: Vec<Bytes>) -> Result<Value, DBError> {
+    Ok(Value::Hash(pairs(entries)?.collect()))
+}
+
+fn zset_from_entries(entries: Vec<Bytes>) -> Result<Value, DBError> {
+    let zset = pairs(entries)?
+        .map(|(member, score)| Ok((member, parse_score(&score)?)))
+        .collect::<Result<_, DBError>>()?;
+    Ok(Value::ZSet(zset))
 }
 
 async fn parse_magic<R: AsyncRead + Unpin>(input: &mut R) -> Result<(), DBError> {
@@ -130,30 +570,43 @@ async fn parse_version<R: AsyncRead + Unpin>(input: &mut R) -> Result<[u8; 4], D
     }
 }
 
+// aux fields are informational, so a value that is not UTF-8 is kept lossily instead of failing
+// the load
 async fn parse_aux<R: AsyncRead + Unpin>(input: &mut R) -> Result<String, DBError> {
     let (len, encoding) = parse_len(input).await?;
     let s = parse_string(input, len, encoding).await?;
-    Ok(s)
+    Ok(String::from_utf8_lossy(&s).into_owned())
 }
 
-async fn parse_len<R: AsyncRead + Unpin>(input: &mut R) -> Result<(u32, StringEncoding), DBError> {
+async fn parse_value<R: AsyncRead + Unpin>(input: &mut R) -> Result<Bytes, DBError> {
+    let (len, encoding) = parse_len(input).await?;
+    let s = parse_string(input, len, encoding).await?;
+    Ok(Bytes::from(s))
+}
+
+async fn parse_len<R: AsyncRead + Unpin>(input: &mut R) -> Result<(u64, StringEncoding), DBError> {
     let first = input.read_u8().await?;
     match first & 0xC0 {
         0x00 => {
             // The size is the remaining 6 bits of the byte.
-            Ok((first as u32, StringEncoding::Raw))
+            Ok((first as u64, StringEncoding::Raw))
         }
-        0x04 => {
+        0x40 => {
             // The size is the next 14 bits of the byte.
             let second = input.read_u8().await?;
             Ok((
-                (((first & 0x3F) as u32) << 8 | second as u32) as u32,
+                ((first & 0x3F) as u64) << 8 | second as u64,
                 StringEncoding::Raw,
             ))
         }
-        0x80 => {
+        0x80 if first == 0x80 => {
             //Ignore the remaining 6 bits of the first byte.  The size is the next 4 bytes, in big-endian
             let second = input.read_u32().await?;
+            Ok((second as u64, StringEncoding::Raw))
+        }
+        0x80 if first == 0x81 => {
+            // The size is the next 8 bytes, in big-endian
+            let second = input.read_u64().await?;
             Ok((second, StringEncoding::Raw))
         }
         0xC0 => {
@@ -162,7 +615,7 @@ async fn parse_len<R: AsyncRead + Unpin>(input: &mut R) -> Result<(u32, StringEn
                 0xC0 => Ok((1, StringEncoding::I8)),
                 0xC1 => Ok((2, StringEncoding::I16)),
                 0xC2 => Ok((4, StringEncoding::I32)),
-                0xC3 => Ok((0, StringEncoding::LZF)), // not supported yet
+                0xC3 => Ok((0, StringEncoding::Lzf)), // lengths follow the encoding byte
                 _ => Err(DBError(format!("unexpected string encoding: {}", first))),
             }
         }
@@ -172,30 +625,360 @@ async fn parse_len<R: AsyncRead + Unpin>(input: &mut R) -> Result<(u32, StringEn
 
 async fn parse_string<R: AsyncRead + Unpin>(
     input: &mut R,
-    len: u32,
+    len: u64,
     encoding: StringEncoding,
-) -> Result<String, DBError> {
+) -> Result<Vec<u8>, DBError> {
     match encoding {
         StringEncoding::Raw => {
             let mut s = vec![0; len as usize];
             input.read_exact(&mut s).await?;
-            Ok(String::from_utf8(s).unwrap())
+            Ok(s)
         }
         StringEncoding::I8 => {
-            let b = input.read_u8().await?;
-            Ok(b.to_string())
+            let b = input.read_i8().await?;
+            Ok(b.to_string().into_bytes())
         }
         StringEncoding::I16 => {
-            let b = input.read_u16_le().await?;
-            Ok(b.to_string())
+            let b = input.read_i16_le().await?;
+            Ok(b.to_string().into_bytes())
         }
         StringEncoding::I32 => {
-            let b = input.read_u32_le().await?;
-            Ok(b.to_string())
+            let b = input.read_i32_le().await?;
+            Ok(b.to_string().into_bytes())
+        }
+        StringEncoding::Lzf => {
+            let (compressed_len, _) = parse_len(input).await?;
+            let (len, _) = parse_len(input).await?;
+            let mut compressed = vec![0; compressed_len as usize];
+            input.read_exact(&mut compressed).await?;
+            lzf::decompress(&compressed, len as usize)
+        }
+    }
+}
+
+/// Serializes a keyspace snapshot into an RDB image.
+pub fn dump_rdb(dbs: &DbSnapshot, repl: &ReplicationOption) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(MAGIC);
+    let field_ttls = dbs
+        .iter()
+        .flat_map(|(_, entries)| entries)
+        .any(|(_, (v, _))| matches!(v, Value::Hash(hash) if hash.min_expire().is_some()));
+    buf.extend_from_slice(if field_ttls {
+        RDB_VERSION_HASH_FIELD_TTL
+    } else {
+        RDB_VERSION
+    });
+
+    let ctime = (now_in_millis() / 1000).to_string();
+    let repl_offset = repl.master_repl_offset.to_string();
+    for (k, v) in [
+        ("redis-ver", "7.2.0"),
+        ("redis-bits", "64"),
+        ("ctime", ctime.as_str()),
+        ("used-mem", "0"),
+        ("repl-id", repl.master_replid.as_str()),
+        ("repl-offset", repl_offset.as_str()),
+        ("aof-base", "0"),
+    ] {
+        buf.push(META);
+        write_string(&mut buf, k.as_bytes());
+        write_string(&mut buf, v.as_bytes());
+    }
+
+    for (index, entries) in dbs {
+        buf.push(DB_SELECT);
+        write_len(&mut buf, *index as u64);
+        buf.push(TABLE_SIZE_INFO);
+        write_len(&mut buf, entries.len() as u64);
+        write_len(
+            &mut buf,
+            entries.iter().filter(|(_, (_, e))| e.is_some()).count() as u64,
+        );
+        for (k, (v, expire_at)) in entries {
+            if let Some(expire_at) = expire_at {
+                buf.push(EXPIRE_TIME_MS);
+                buf.extend_from_slice(&(*expire_at as u64).to_le_bytes());
+            }
+            write_object(&mut buf, k, v);
+        }
+    }
+
+    buf.push(EOF);
+    let checksum = crc64::crc64(&buf);
+    buf.extend_from_slice(&checksum.to_le_bytes());
+    buf
+}
+
+// every type is written in its plain encoding, which any Redis version can load
+fn write_object(buf: &mut Vec<u8>, k: &[u8], v: &Value) {
+    match v {
+        Value::String(s) => {
+            buf.push(TYPE_STRING);
+            write_string(buf, k);
+            write_string(buf, s);
+        }
+        Value::List(list) => {
+            buf.push(TYPE_LIST);
+            write_string(buf, k);
+            write_len(buf, list.len() as u64);
+            for item in list {
+                write_string(buf, item);
+            }
+        }
+        Value::Set(set) => {
+            buf.push(TYPE_SET);
+            write_string(buf, k);
+            write_len(buf, set.len() as u64);
+            for member in set.iter() {
+                write_string(buf, &member);
+            }
+        }
+        Value::ZSet(zset) => {
+            buf.push(TYPE_ZSET_2);
+            write_string(buf, k);
+            write_len(buf, zset.len() as u64);
+            for (member, score) in zset.iter() {
+                write_string(buf, member);
+                buf.extend_from_slice(&score.to_le_bytes());
+            }
+        }
+        Value::Hash(hash) => match hash.min_expire() {
+            // hashes with field TTLs need the metadata encoding, each TTL is written relative
+            // to the smallest one
+            Some(min_expire) => {
+                buf.push(TYPE_HASH_METADATA);
+                write_string(buf, k);
+                buf.extend_from_slice(&(min_expire as u64).to_le_bytes());
+                write_len(buf, hash.len() as u64);
+                for (field, value) in hash.iter() {
+                    let ttl = hash
+                        .expire_at(field)
+                        .map_or(0, |expire_at| expire_at - min_expire + 1);
+                    write_len(buf, ttl as u64);
+                    write_string(buf, field);
+                    write_string(buf, value);
+                }
+            }
+            None => {
+                buf.push(TYPE_HASH);
+                write_string(buf, k);
+                write_len(buf, hash.len() as u64);
+                for (field, value) in hash.iter() {
+                    write_string(buf, field);
+                    write_string(buf, value);
+                }
+            }
+        },
+        Value::Stream(stream) => {
+            buf.push(TYPE_STREAM_LISTPACKS_3);
+            write_string(buf, k);
+            write_stream(buf, stream);
+        }
+    }
+}
+
+// streams have no plain encoding, so this mirrors what Redis 7.2 writes
+fn write_stream(buf: &mut Vec<u8>, stream: &Stream) {
+    let entries = stream.entries.iter().collect::<Vec<_>>();
+    let nodes = entries.chunks(STREAM_NODE_MAX_ENTRIES).collect::<Vec<_>>();
+    write_len(buf, nodes.len() as u64);
+    for node in nodes {
+        let (master_id, master_fields) = (node[0].0, &node[0].1);
+        let mut items = vec![
+            Bytes::from(node.len().to_string()),
+            Bytes::from("0"),
+            Bytes::from(master_fields.len().to_string()),
+        ];
+        items.extend(master_fields.iter().map(|(f, _)| f.clone()));
+        items.push(Bytes::from("0"));
+        for (id, fields) in node {
+            let same_fields = fields.len() == master_fields.len()
+                && fields
+                    .iter()
+                    .zip(master_fields.iter())
+                    .all(|((a, _), (b, _))| a == b);
+            let flags = if same_fields {
+                STREAM_ITEM_FLAG_SAMEFIELDS
+            } else {
+                0
+            };
+            items.push(Bytes::from(flags.to_string()));
+            items.push(Bytes::from(
+                (id.ms.wrapping_sub(master_id.ms) as i64).to_string(),
+            ));
+            items.push(Bytes::from(
+                (id.seq.wrapping_sub(master_id.seq) as i64).to_string(),
+            ));
+            if same_fields {
+                items.extend(fields.iter().map(|(_, v)| v.clone()));
+                items.push(Bytes::from((fields.len() + 3).to_string()));
+            } else {
+                items.push(Bytes::from(fields.len().to_string()));
+                for (f, v) in fields.iter() {
+                    items.push(f.clone());
+                    items.push(v.clone());
+                }
+                items.push(Bytes::from((fields.len() * 2 + 4).to_string()));
+            }
+        }
+        write_string(buf, &master_id.to_be_bytes());
+        write_string(buf, &listpack::encode(items.iter().map(|b| b.as_ref())));
+    }
+
+    write_len(buf, stream.entries.len() as u64);
+    write_stream_id(buf, stream.last_id);
+    write_stream_id(buf, stream.first_id());
+    write_stream_id(buf, stream.max_deleted_id);
+    write_len(buf, stream.entries_added);
+
+    write_len(buf, stream.groups.len() as u64);
+    for group in &stream.groups {
+        write_string(buf, &group.name);
+        write_stream_id(buf, group.last_id);
+        write_len(buf, group.entries_read as u64);
+        write_len(buf, group.pending.len() as u64);
+        for (id, entry) in &group.pending {
+            buf.extend_from_slice(&id.to_be_bytes());
+            buf.extend_from_slice(&entry.delivery_time.to_le_bytes());
+            write_len(buf, entry.delivery_count);
         }
-        StringEncoding::LZF => {
-            // not supported yet
-            Err(DBError("LZF encoding not supported yet".to_string()))
+        write_len(buf, group.consumers.len() as u64);
+        for consumer in &group.consumers {
+            write_string(buf, &consumer.name);
+            buf.extend_from_slice(&consumer.seen_time.to_le_bytes());
+            buf.extend_from_slice(&consumer.active_time.to_le_bytes());
+            let pending = group
+                .pending
+                .iter()
+                .filter(|(_, e)| e.consumer == consumer.name)
+                .collect::<Vec<_>>();
+            write_len(buf, pending.len() as u64);
+            for (id, _) in pending {
+                buf.extend_from_slice(&id.to_be_bytes());
+            }
+        }
+    }
+}
+
+fn write_stream_id(buf: &mut Vec<u8>, id: StreamId) {
+    write_len(buf, id.ms);
+    write_len(buf, id.seq);
+}
+
+fn write_len(buf: &mut Vec<u8>, len: u64) {
+    if len < 1 << 6 {
+        buf.push(len as u8);
+    } else if len < 1 << 14 {
+        buf.push(0x40 | (len >> 8) as u8);
+        buf.push(len as u8);
+    } else if len <= u32::MAX as u64 {
+        buf.push(0x80);
+        buf.extend_from_slice(&(len as u32).to_be_bytes());
+    } else {
+        buf.push(0x81);
+        buf.extend_from_slice(&len.to_be_bytes());
+    }
+}
+
+fn write_string(buf: &mut Vec<u8>, s: &[u8]) {
+    // like Redis, only bother compressing strings longer than 20 bytes
+    if s.len() > 20 {
+        if let Some(compressed) = lzf::compress(s) {
+            buf.push(0xC3);
+            write_len(buf, compressed.len() as u64);
+            write_len(buf, s.len() as u64);
+            buf.extend_from_slice(&compressed);
+            return;
         }
     }
+    write_len(buf, s.len() as u64);
+    buf.extend_from_slice(s);
+}
+
+/// Writes the image to a temp file in the same directory and renames it over the target, so a
+/// crash mid-write never leaves a truncated dump behind.
+fn write_rdb_file(path: &Path, data: &[u8]) -> Result<(), DBError> {
+    let tmp_path = path.with_file_name(format!("temp-{}.rdb", std::process::id()));
+    {
+        use std::io::Write;
+        let mut f = std::fs::File::create(&tmp_path)?;
+        f.write_all(data)?;
+        f.sync_all()?;
+    }
+    std::fs::rename(&tmp_path, path).inspect_err(|_| {
+        let _ = std::fs::remove_file(&tmp_path);
+    })?;
+    Ok(())
+}
+
+fn rdb_file_path(server: &Server) -> PathBuf {
+    PathBuf::from(server.option.dir.clone()).join(server.option.db_file_name.clone())
+}
+
+fn unix_secs() -> u64 {
+    (now_in_millis() / 1000) as u64
+}
+
+/// SAVE: snapshot and write the dump, blocking the caller until it is on disk.
+pub async fn save_rdb_file(server: &Server) -> Result<(), DBError> {
+    let (entries, dirty) = snapshot(server).await;
+    let path = rdb_file_path(server);
+    let repl = server.option.replication.clone();
+    let ret =
+        tokio::task::spawn_blocking(move || write_rdb_file(&path, &dump_rdb(&entries, &repl)))
+            .await
+            .map_err(|e| DBError(e.to_string()))
+            .and_then(|r| r);
+    finish_save(server, &ret, dirty);
+    ret
+}
+
+/// BGSAVE: snapshot under the lock, then serialize and write it in the background.
+/// Returns false if another background save is still running. The snapshot is a deep copy
+/// taken in the foreground, so clients block for a time that grows with the dataset; only
+/// the serialization and the disk write run in the background.
+pub async fn bgsave_rdb_file(server: &Server) -> bool {
+    if server.bgsave_in_progress.swap(true, Ordering::AcqRel) {
+        return false;
+    }
+    server.last_bgsave_try.store(unix_secs(), Ordering::Relaxed);
+    let (entries, dirty) = snapshot(server).await;
+    let path = rdb_file_path(server);
+    let server = server.clone();
+    tokio::task::spawn_blocking(move || {
+        let ret = write_rdb_file(&path, &dump_rdb(&entries, &server.option.replication));
+        if let Err(e) = &ret {
+            println!("background saving error: {:?}", e);
+        }
+        server.last_bgsave_ok.store(ret.is_ok(), Ordering::Relaxed);
+        finish_save(&server, &ret, dirty);
+        server.bgsave_in_progress.store(false, Ordering::Release);
+    });
+    true
+}
+
+// every non-empty database together with the dirty counter at the time it was copied. The
+// write lock keeps both in step: no write can change a database or bump the counter until the
+// copy is done, and the counter is read before it starts, so a write counted later is one the
+// dump does not hold and it stays dirty
+async fn snapshot(server: &Server) -> (DbSnapshot, u64) {
+    let _write_order = server.write_lock.lock().await;
+    let dirty = server.dirty.load(Ordering::Relaxed);
+    let mut dbs = Vec::new();
+    for (index, db) in server.dbs.iter().enumerate() {
+        let entries = db.lock().await.snapshot();
+        if !entries.is_empty() {
+            dbs.push((index, entries));
+        }
+    }
+    (dbs, dirty)
+}
+
+fn finish_save(server: &Server, ret: &Result<(), DBError>, dirty_before_save: u64) {
+    if ret.is_ok() {
+        // writes that raced with the save stay dirty
+        server.dirty.fetch_sub(dirty_before_save, Ordering::Relaxed);
+        server.last_save.store(unix_secs(), Ordering::Relaxed);
+    }
 }
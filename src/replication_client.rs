@@ -21,38 +21,38 @@ impl FollowerReplicationClient {
         }
     }
 
-    pub async fn ping_master(self: &mut Self) -> Result<(), DBError> {
-        let protocol = Protocol::Array(vec![Protocol::BulkString("PING".to_string())]);
-        self.stream.write_all(protocol.encode().as_bytes()).await?;
+    pub async fn ping_master(&mut self) -> Result<(), DBError> {
+        let protocol = Protocol::Array(vec![Protocol::bulk("PING")]);
+        self.stream.write_all(&protocol.encode()).await?;
 
         self.check_resp("PONG").await
     }
 
-    pub async fn report_port(self: &mut Self, port: u16) -> Result<(), DBError> {
+    pub async fn report_port(&mut self, port: u16) -> Result<(), DBError> {
         let protocol = Protocol::from_vec(vec![
             "REPLCONF",
             "listening-port",
             port.to_string().as_str(),
         ]);
-        self.stream.write_all(protocol.encode().as_bytes()).await?;
+        self.stream.write_all(&protocol.encode()).await?;
 
         self.check_resp("OK").await
     }
 
-    pub async fn report_sync_protocol(self: &mut Self) -> Result<(), DBError> {
+    pub async fn report_sync_protocol(&mut self) -> Result<(), DBError> {
         let p = Protocol::from_vec(vec!["REPLCONF", "capa", "psync2"]);
-        self.stream.write_all(p.encode().as_bytes()).await?;
+        self.stream.write_all(&p.encode()).await?;
         self.check_resp("OK").await
     }
 
-    pub async fn start_psync(self: &mut Self, server: &mut Server) -> Result<(), DBError> {
+    pub async fn start_psync(&mut self, server: &mut Server) -> Result<(), DBError> {
         let p = Protocol::from_vec(vec!["PSYNC", "?", "-1"]);
-        self.stream.write_all(p.encode().as_bytes()).await?;
+        self.stream.write_all(&p.encode()).await?;
         self.recv_rdb_file(server).await?;
         Ok(())
     }
 
-    pub async fn recv_rdb_file(self: &mut Self, server: &mut Server) -> Result<(), DBError> {
+    pub async fn recv_rdb_file(&mut self, server: &mut Server) -> Result<(), DBError> {
         let mut reader = BufReader::new(&mut self.stream);
 
         let mut buf = Vec::new();
@@ -100,7 +100,7 @@ impl FollowerReplicationClient {
             String::from_utf8(buf[..n_bytes].to_vec()).unwrap()
         );
         let expect = Protocol::SimpleString(expected.to_string()).encode();
-        if expect.as_bytes() != &buf[..n_bytes] {
+        if expect != buf[..n_bytes] {
             return Err(DBError(format!(
                 "expect response {:?} but found {:?}",
                 expect,
@@ -114,12 +114,15 @@ impl FollowerReplicationClient {
 #[derive(Clone)]
 pub struct MasterReplicationClient {
     pub streams: Arc<Mutex<Vec<TcpStream>>>,
+    // database the replicas were last told to SELECT
+    selected_db: Option<usize>,
 }
 
 impl MasterReplicationClient {
     pub fn new() -> MasterReplicationClient {
         MasterReplicationClient {
             streams: Arc::new(Mutex::new(Vec::new())),
+            selected_db: None,
         }
     }
 
@@ -134,21 +137,30 @@ impl MasterReplicationClient {
         _ = stream
             .write(empty_rdb_file_bytes.len().to_string().as_bytes())
             .await?;
-        _ = stream.write_all("\r\n".as_bytes()).await?;
-        _ = stream.write_all(&empty_rdb_file_bytes).await?;
+        stream.write_all("\r\n".as_bytes()).await?;
+        stream.write_all(&empty_rdb_file_bytes).await?;
         Ok(())
     }
 
     pub async fn add_stream(&mut self, stream: TcpStream) -> Result<(), DBError> {
         let mut streams = self.streams.lock().await;
         streams.push(stream);
+        // the new replica starts out on database 0, so SELECT again before the next command
+        self.selected_db = None;
         Ok(())
     }
 
-    pub async fn send_command(&mut self, protocol: Protocol) -> Result<(), DBError> {
+    // send a write made in database `db`, preceded by a SELECT when the database changed
+    pub async fn send_command(&mut self, db: usize, protocol: Protocol) -> Result<(), DBError> {
+        let mut data = Vec::new();
+        if self.selected_db != Some(db) {
+            data.extend_from_slice(&Protocol::select(db).encode());
+            self.selected_db = Some(db);
+        }
+        data.extend_from_slice(&protocol.encode());
         let mut streams = self.streams.lock().await;
         for stream in streams.iter_mut() {
-            stream.write_all(protocol.encode().as_bytes()).await?;
+            stream.write_all(&data).await?;
         }
         Ok(())
     }
@@ -0,0 +1,66 @@
+// the order HSCAN and SSCAN walk a hash or set in. Members are kept sorted by a fixed hash of
+// their name and a cursor is the hash to go on from, so members present for the whole scan are
+// returned exactly once however the collection changes in between, and each call only touches
+// the members it returns.
+
+use std::{
+    collections::BTreeMap,
+    hash::{BuildHasher, BuildHasherDefault, DefaultHasher},
+};
+
+use bytes::Bytes;
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ScanOrder {
+    // hash -> the members with that hash, almost always one
+    buckets: BTreeMap<u64, Vec<Bytes>>,
+}
+
+// the same on every run, so a cursor stays valid across a restart or on a replica
+fn scan_hash(member: &[u8]) -> u64 {
+    BuildHasherDefault::<DefaultHasher>::default().hash_one(member)
+}
+
+impl ScanOrder {
+    pub fn insert(&mut self, member: &Bytes) {
+        let bucket = self.buckets.entry(scan_hash(member)).or_default();
+        if !bucket.contains(member) {
+            bucket.push(member.clone());
+        }
+    }
+
+    pub fn remove(&mut self, member: &[u8]) {
+        let h = scan_hash(member);
+        if let Some(bucket) = self.buckets.get_mut(&h) {
+            bucket.retain(|m| m != member);
+            if bucket.is_empty() {
+                self.buckets.remove(&h);
+            }
+        }
+    }
+
+    // at least `count` members from `cursor` on, unless the scan ends first, and the next
+    // cursor, 0 when done. Members sharing a hash go out together, as the cursor cannot point
+    // between them
+    pub fn step(&self, cursor: u64, count: usize) -> (u64, Vec<&Bytes>) {
+        let mut members = Vec::new();
+        let mut buckets = self.buckets.range(cursor..);
+        for (_, bucket) in buckets.by_ref() {
+            members.extend(bucket);
+            if members.len() >= count {
+                break;
+            }
+        }
+        (buckets.next().map_or(0, |(h, _)| *h), members)
+    }
+}
+
+impl<'a> FromIterator<&'a Bytes> for ScanOrder {
+    fn from_iter<I: IntoIterator<Item = &'a Bytes>>(iter: I) -> Self {
+        let mut order = ScanOrder::default();
+        for member in iter {
+            order.insert(member);
+        }
+        order
+    }
+}
@@ -1,34 +1,85 @@
-use core::str;
-use std::collections::BTreeMap;
-use std::collections::HashMap;
+use bytes::Bytes;
+use bytes::BytesMut;
 use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
 use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
 use tokio::fs::OpenOptions;
 use tokio::io::AsyncReadExt;
 use tokio::io::AsyncWriteExt;
 use tokio::sync::mpsc::Sender;
 use tokio::sync::Mutex;
+use tokio::sync::MutexGuard;
+use tokio::sync::Notify;
+use tokio_util::codec::Decoder;
+use tokio_util::codec::Encoder;
 
+use crate::aof;
+use crate::aof::Aof;
+use crate::blocking::BlockingKeys;
 use crate::cmd::Cmd;
+use crate::codec::RespCodec;
 use crate::error::DBError;
 use crate::options;
+use crate::options::MaxmemoryPolicy;
 use crate::protocol::Protocol;
+use crate::protocol::RESP2;
 use crate::rdb;
+use crate::rdb::RdbLoadInfo;
 use crate::replication_client::FollowerReplicationClient;
 use crate::replication_client::MasterReplicationClient;
+use crate::storage::now_in_millis;
+use crate::storage::ExpiredKeys;
 use crate::storage::Storage;
 
-type Stream = BTreeMap<String, Vec<(String, String)>>;
+const BGSAVE_RETRY_DELAY_SECS: u64 = 5;
+// keys with a TTL looked at per round of the active expire cycle
+const ACTIVE_EXPIRE_LOOKUPS_PER_LOOP: usize = 20;
+// a quarter of the 100ms cron period, like ACTIVE_EXPIRE_CYCLE_SLOW_TIME_PERC in Redis
+const ACTIVE_EXPIRE_TIME_BUDGET: Duration = Duration::from_millis(25);
 
 #[derive(Clone)]
 pub struct Server {
+    pub dbs: Arc<Vec<Arc<Mutex<Storage>>>>,
+    // the database selected by this connection, `storage` is `dbs[db_index]`
+    pub db_index: usize,
     pub storage: Arc<Mutex<Storage>>,
-    pub streams: Arc<Mutex<HashMap<String, Stream>>>,
     pub option: options::DBOption,
     pub offset: Arc<AtomicU64>,
     pub master_repl_clients: Arc<Mutex<Option<MasterReplicationClient>>>,
     pub stream_reader_blocker: Arc<Mutex<Vec<Sender<()>>>>,
+    pub blocking_keys: Arc<Mutex<BlockingKeys>>,
+    // unix time in seconds of the last successful SAVE/BGSAVE
+    pub last_save: Arc<AtomicU64>,
+    pub bgsave_in_progress: Arc<AtomicBool>,
+    pub last_bgsave_ok: Arc<AtomicBool>,
+    pub last_bgsave_try: Arc<AtomicU64>,
+    // number of writes since the last successful snapshot
+    pub dirty: Arc<AtomicU64>,
+    pub save_params: Arc<Mutex<Vec<(u64, u64)>>>,
+    pub aof: Option<Arc<Mutex<Aof>>>,
+    // held by a write from its storage change until it is logged and replicated, and by the
+    // snapshots, so the AOF, the replicas and a snapshot see every write whole and in the order
+    // the writes were applied
+    pub write_lock: Arc<Mutex<()>>,
+    pub rdb_load_info: Arc<Mutex<RdbLoadInfo>>,
+    // RESP version negotiated by HELLO, the server is cloned per connection so this is per client
+    pub resp_version: u8,
+    // set while EXEC runs the queued commands, blocking commands do not block then
+    pub in_exec: bool,
+    // keys the databases expired or evicted that still have to be propagated as DEL
+    pub expired_keys: ExpiredKeys,
+    // maxmemory and its policy, both can be changed with CONFIG SET
+    pub maxmemory: Arc<AtomicU64>,
+    pub maxmemory_policy: Arc<Mutex<MaxmemoryPolicy>>,
+    // notified when the client of a blocking command goes away while it waits, `handle` gives
+    // each connection its own
+    pub client_gone: Arc<Notify>,
+    // whether this connection is a replica's link to its master
+    master_link: bool,
     master_addr: Option<String>,
 }
 
@@ -47,10 +98,23 @@ impl Server {
         };
 
         let is_master = option.replication.role == "master";
+        let save_params = option.save_params.clone();
+        let maxmemory = option.maxmemory;
+        let maxmemory_policy = option.maxmemory_policy;
+
+        // only a master deletes expired keys, a replica waits for the DEL
+        let expired_keys = ExpiredKeys::default();
+        let dbs = (0..option.databases)
+            .map(|db| {
+                let expired_keys = is_master.then(|| expired_keys.clone());
+                Arc::new(Mutex::new(Storage::new(db, expired_keys)))
+            })
+            .collect::<Vec<_>>();
 
         let mut server = Server {
-            storage: Arc::new(Mutex::new(Storage::new())),
-            streams: Arc::new(Mutex::new(HashMap::new())),
+            db_index: 0,
+            storage: dbs[0].clone(),
+            dbs: Arc::new(dbs),
             option,
             master_repl_clients: if is_master {
                 Arc::new(Mutex::new(Some(MasterReplicationClient::new())))
@@ -59,16 +123,44 @@ impl Server {
             },
             offset: Arc::new(AtomicU64::new(0)),
             stream_reader_blocker: Arc::new(Mutex::new(Vec::new())),
+            blocking_keys: Arc::new(Mutex::new(BlockingKeys::default())),
+            last_save: Arc::new(AtomicU64::new(now_in_millis() as u64 / 1000)),
+            bgsave_in_progress: Arc::new(AtomicBool::new(false)),
+            last_bgsave_ok: Arc::new(AtomicBool::new(true)),
+            last_bgsave_try: Arc::new(AtomicU64::new(0)),
+            dirty: Arc::new(AtomicU64::new(0)),
+            save_params: Arc::new(Mutex::new(save_params)),
+            aof: None,
+            write_lock: Arc::new(Mutex::new(())),
+            rdb_load_info: Arc::new(Mutex::new(RdbLoadInfo::default())),
+            resp_version: RESP2,
+            in_exec: false,
+            expired_keys,
+            maxmemory: Arc::new(AtomicU64::new(maxmemory)),
+            maxmemory_policy: Arc::new(Mutex::new(maxmemory_policy)),
+            client_gone: Arc::new(Notify::new()),
+            master_link: false,
             master_addr,
         };
 
-        server.init().await.unwrap();
+        // a dataset that cannot be loaded is fatal, like in Redis
+        if let Err(e) = server.init().await {
+            println!("failed to load the dataset: {}", e.0);
+            std::process::exit(1);
+        }
         server
     }
 
     pub async fn init(&mut self) -> Result<(), DBError> {
+        let aof_path =
+            PathBuf::from(self.option.dir.clone()).join(self.option.aof.file_name.clone());
+        let aof_exists = self.option.aof.enabled && aof_path.exists();
+
         // master initialization
-        if self.is_master() {
+        if self.is_master() && aof_exists {
+            println!("Start as master, loading AOF {}\n", aof_path.display());
+            aof::load_aof(self, &self.option.aof).await?;
+        } else if self.is_master() {
             println!("Start as master\n");
             let db_file_path =
                 PathBuf::from(self.option.dir.clone()).join(self.option.db_file_name.clone());
@@ -85,6 +177,23 @@ impl Server {
 
             if file.metadata().await?.len() != 0 {
                 rdb::parse_rdb_file(&mut file, self).await?;
+                // pick the replication history back up so replicas can continue from it
+                let info = self.rdb_load_info.lock().await.clone();
+                if let (Some(id), Some(offset)) = (info.repl_id, info.repl_offset) {
+                    self.option.replication.master_replid = id;
+                    self.option.replication.master_repl_offset = offset;
+                }
+            }
+        }
+
+        if self.option.aof.enabled {
+            self.aof = Some(Arc::new(Mutex::new(Aof::open(
+                &self.option.dir,
+                &self.option.aof,
+            )?)));
+            // seed a new AOF with whatever was loaded from the RDB file
+            if !aof_exists {
+                aof::create_aof(self).await?;
             }
         }
         Ok(())
@@ -103,41 +212,88 @@ impl Server {
         mut stream: tokio::net::TcpStream,
         is_rep_conn: bool,
     ) -> Result<(), DBError> {
-        let mut buf = [0; 512];
+        let mut codec = RespCodec::default();
+        let mut read_buf = BytesMut::with_capacity(4096);
         let mut queued_cmd: Option<Vec<(Cmd, Protocol)>> = None;
+        self.master_link = is_rep_conn;
+        self.client_gone = Arc::new(Notify::new());
+        let client_gone = self.client_gone.clone();
         loop {
-            if let Ok(len) = stream.read(&mut buf).await {
+            if let Ok(len) = stream.read_buf(&mut read_buf).await {
                 if len == 0 {
                     println!("[handle] connection closed");
                     return Ok(());
                 }
-                let s = str::from_utf8(&buf[..len])?;
-                let (cmd, protocol) =
-                    Cmd::from(s).unwrap_or((Cmd::Unknow, Protocol::err("unknow cmd")));
-                println!("got command: {:?}, protocol: {:?}", cmd, protocol);
 
-                let res = cmd
-                    .run(self, protocol, is_rep_conn, &mut queued_cmd)
-                    .await
-                    .unwrap_or(Protocol::err("unknow cmd"));
-                print!("queued 2 cmd {:?}", queued_cmd);
+                // run every complete command in the buffer and batch the replies into one write
+                let mut write_buf = BytesMut::new();
+                loop {
+                    let frame = match codec.decode(&mut read_buf) {
+                        Ok(Some(frame)) => frame,
+                        Ok(None) => break,
+                        Err(e) => {
+                            // reply with the protocol error before dropping the connection
+                            codec.encode(Protocol::err(&format!("ERR {}", e.0)), &mut write_buf)?;
+                            stream.write_all(&write_buf).await?;
+                            return Err(e);
+                        }
+                    };
+                    let (cmd, protocol) =
+                        Cmd::from(frame).unwrap_or((Cmd::Unknow, Protocol::unknown_cmd_err()));
+                    println!("got command: {:?}, protocol: {:?}", cmd, protocol);
 
-                // only send response to normal client, do not send response to replication client
-                if !is_rep_conn {
-                    println!("going to send response {}", res.encode());
-                    _ = stream.write(res.encode().as_bytes()).await?;
-                }
+                    // the replies batched so far must not wait behind one that may block
+                    if cmd.may_block() && !write_buf.is_empty() {
+                        stream.write_all(&write_buf).await?;
+                        write_buf.clear();
+                    }
+
+                    let run = cmd.run(self, protocol, is_rep_conn, &mut queued_cmd);
+                    let (res, closed) = if cmd.may_block() {
+                        // a blocked client that goes away stops waiting, so no push is served to it
+                        tokio::pin!(run);
+                        tokio::select! {
+                            res = &mut run => (res, false),
+                            _ = wait_closed(&mut stream, &mut read_buf) => {
+                                client_gone.notify_one();
+                                (run.await, true)
+                            }
+                        }
+                    } else {
+                        (run.await, false)
+                    };
+                    let res = res.unwrap_or_else(|e| Protocol::err(&format!("ERR {}", e.0)));
+                    // keys a read expired on the way
+                    if let Err(e) = self.flush_expired().await {
+                        println!("failed to propagate expired keys: {:?}", e);
+                    }
+                    if closed {
+                        println!("[handle] connection closed");
+                        return Ok(());
+                    }
 
-                // send a full RDB file to slave
-                if self.is_master() {
-                    if let Cmd::Psync = cmd {
-                        let mut master_rep_client = self.master_repl_clients.lock().await;
-                        let master_rep_client = master_rep_client.as_mut().unwrap();
-                        master_rep_client.send_rdb_file(&mut stream).await?;
-                        master_rep_client.add_stream(stream).await?;
-                        break;
+                    // only send response to normal client, do not send response to replication client
+                    if !is_rep_conn {
+                        println!("going to send response {:?}", res);
+                        codec.version = self.resp_version;
+                        codec.encode(res, &mut write_buf)?;
+                    }
+
+                    // send a full RDB file to slave
+                    if self.is_master() {
+                        if let Cmd::Psync = cmd {
+                            stream.write_all(&write_buf).await?;
+                            let mut master_rep_client = self.master_repl_clients.lock().await;
+                            let master_rep_client = master_rep_client.as_mut().unwrap();
+                            master_rep_client.send_rdb_file(&mut stream).await?;
+                            master_rep_client.add_stream(stream).await?;
+                            return Ok(());
+                        }
                     }
                 }
+                if !write_buf.is_empty() {
+                    stream.write_all(&write_buf).await?;
+                }
             } else {
                 println!("[handle] going to break");
                 break;
@@ -146,6 +302,153 @@ impl Server {
         Ok(())
     }
 
+    /// Runs the periodic background jobs, like serverCron in Redis.
+    pub async fn cron(self) {
+        let mut interval = tokio::time::interval(Duration::from_millis(100));
+        // the database the next active expire cycle starts from
+        let mut expire_db = 0;
+        loop {
+            interval.tick().await;
+            if self.is_master() {
+                self.active_expire_cycle(&mut expire_db).await;
+                if let Err(e) = self.flush_expired().await {
+                    println!("failed to propagate expired keys: {:?}", e);
+                }
+            }
+            self.save_if_needed().await;
+            if let Some(aof) = &self.aof {
+                if let Err(e) = aof.lock().await.fsync_if_due() {
+                    println!("AOF fsync error: {:?}", e);
+                }
+            }
+        }
+    }
+
+    // reclaim expired keys that are never read again, like activeExpireCycle in Redis. Each database
+    // samples keys with a TTL and goes on while more than a quarter of the sample had expired, until
+    // the time budget runs out; the next cycle picks up at the database this one stopped before
+    async fn active_expire_cycle(&self, next_db: &mut usize) {
+        let start = Instant::now();
+        for _ in 0..self.dbs.len() {
+            let db = *next_db;
+            *next_db = (db + 1) % self.dbs.len();
+            let mut storage = self.dbs[db].lock().await;
+            loop {
+                let (sampled, expired) = storage.expire_sample(ACTIVE_EXPIRE_LOOKUPS_PER_LOOP);
+                if expired * 4 <= sampled || start.elapsed() >= ACTIVE_EXPIRE_TIME_BUDGET {
+                    break;
+                }
+            }
+            drop(storage);
+            if start.elapsed() >= ACTIVE_EXPIRE_TIME_BUDGET {
+                break;
+            }
+        }
+    }
+
+    // the approximate memory taken by all the databases
+    pub async fn used_memory(&self) -> u64 {
+        let mut used = 0;
+        for db in self.dbs.iter() {
+            used += db.lock().await.used_memory() as u64;
+        }
+        used
+    }
+
+    // make room under maxmemory before a command that may take more of it, like performEvictions
+    // in Redis: every database offers its best pick from a sample and the best of those goes,
+    // until the memory fits. False if it still does not fit, under noeviction or with nothing
+    // left the policy may evict. The memory is summed once and then counted down by what each
+    // eviction frees
+    pub async fn evict_if_needed(&self) -> bool {
+        let maxmemory = self.maxmemory.load(Ordering::Relaxed);
+        if maxmemory == 0 {
+            return true;
+        }
+        let policy = *self.maxmemory_policy.lock().await;
+        let mut used = self.used_memory().await;
+        while used > maxmemory {
+            let mut best: Option<(usize, Bytes, u64)> = None;
+            for (db, storage) in self.dbs.iter().enumerate() {
+                let candidate = storage
+                    .lock()
+                    .await
+                    .eviction_candidate(policy, self.option.maxmemory_samples);
+                if let Some((k, score)) = candidate {
+                    if best.as_ref().is_none_or(|(_, _, s)| score > *s) {
+                        best = Some((db, k, score));
+                    }
+                }
+            }
+            match best {
+                Some((db, k, _)) => match self.dbs[db].lock().await.evict(&k) {
+                    // deleted by another client meanwhile, which may have freed anything
+                    0 => used = self.used_memory().await,
+                    freed => used = used.saturating_sub(freed as u64),
+                },
+                None => return false,
+            }
+        }
+        true
+    }
+
+    // propagate the keys expired since the last write, see `propagate_expired`
+    pub async fn flush_expired(&self) -> Result<(), DBError> {
+        if self.expired_keys.lock().unwrap().is_empty() {
+            return Ok(());
+        }
+        let mut master_repl_clients = self.master_repl_clients.lock().await;
+        match master_repl_clients.as_mut() {
+            Some(client) => self.propagate_expired(client).await,
+            None => Ok(()),
+        }
+    }
+
+    // a master logs a DEL for every key and an HDEL for the hash fields it expired and sends them
+    // to the replicas, so they drop them at the same point of the replication stream. The caller
+    // holds the replication clients, which keeps any other write from going out ahead of the DELs
+    pub async fn propagate_expired(
+        &self,
+        client: &mut MasterReplicationClient,
+    ) -> Result<(), DBError> {
+        let dels = std::mem::take(&mut *self.expired_keys.lock().unwrap());
+        for (db, del) in dels {
+            if let Some(aof) = &self.aof {
+                aof.lock().await.append(db, &del)?;
+            }
+            client.send_command(db, del).await?;
+        }
+        Ok(())
+    }
+
+    // trigger a background save once any `save <seconds> <changes>` rule is satisfied
+    async fn save_if_needed(&self) {
+        if self.bgsave_in_progress.load(Ordering::Acquire) {
+            return;
+        }
+        let now = (now_in_millis() / 1000) as u64;
+        let dirty = self.dirty.load(Ordering::Relaxed);
+        let since_last_save = now.saturating_sub(self.last_save.load(Ordering::Relaxed));
+        // after a failed background save, wait a bit before trying again
+        if !self.last_bgsave_ok.load(Ordering::Relaxed)
+            && now.saturating_sub(self.last_bgsave_try.load(Ordering::Relaxed))
+                < BGSAVE_RETRY_DELAY_SECS
+        {
+            return;
+        }
+        let rule = self
+            .save_params
+            .lock()
+            .await
+            .iter()
+            .find(|(secs, changes)| dirty >= *changes && since_last_save >= *secs)
+            .copied();
+        if let Some((secs, changes)) = rule {
+            println!("{} changes in {} seconds. Saving...", changes, secs);
+            rdb::bgsave_rdb_file(self).await;
+        }
+    }
+
     pub fn is_slave(&self) -> bool {
         self.option.replication.role == "slave"
     }
@@ -153,4 +456,32 @@ impl Server {
     pub fn is_master(&self) -> bool {
         !self.is_slave()
     }
+
+    // lock the database selected by this connection
+    pub async fn lock_storage(&self) -> MutexGuard<'_, Storage> {
+        self.lock_db(self.db_index).await
+    }
+
+    // lock a database, telling it whether the lock is held for the master's stream
+    pub async fn lock_db(&self, index: usize) -> MutexGuard<'_, Storage> {
+        let mut storage = self.dbs[index].lock().await;
+        storage.from_master = self.master_link;
+        storage
+    }
+
+    // point this connection at another database, the index must be in range
+    pub fn select(&mut self, index: usize) {
+        self.db_index = index;
+        self.storage = self.dbs[index].clone();
+    }
+}
+
+// returns once the client closes the connection, what it sends until then is kept in `read_buf`
+// for after the running command
+async fn wait_closed(stream: &mut tokio::net::TcpStream, read_buf: &mut BytesMut) {
+    while let Ok(len) = stream.read_buf(read_buf).await {
+        if len == 0 {
+            return;
+        }
+    }
 }
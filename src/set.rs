@@ -0,0 +1,128 @@
+// the set value type. Small sets of integers are kept as a sorted array of i64, like the intset
+// encoding of Redis, and turn into a hash set of members once they outgrow it.
+
+use std::collections::HashSet;
+
+use bytes::Bytes;
+
+use crate::{listpack::string_to_int, scan::ScanOrder};
+
+// Redis' set-max-intset-entries default
+const MAX_INTSET_ENTRIES: usize = 512;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Set {
+    Ints(Vec<i64>),
+    // the members and their SSCAN order
+    Members(HashSet<Bytes>, ScanOrder),
+}
+
+impl Default for Set {
+    fn default() -> Self {
+        Set::Ints(Vec::new())
+    }
+}
+
+impl Set {
+    pub fn contains(&self, member: &[u8]) -> bool {
+        match self {
+            Set::Ints(ints) => {
+                string_to_int(member).is_some_and(|i| ints.binary_search(&i).is_ok())
+            }
+            Set::Members(members, _) => members.contains(member),
+        }
+    }
+
+    // true if the member is new
+    pub fn insert(&mut self, member: Bytes) -> bool {
+        if let Set::Ints(ints) = self {
+            if let Some(i) = string_to_int(&member) {
+                match ints.binary_search(&i) {
+                    Ok(_) => return false,
+                    Err(pos) if ints.len() < MAX_INTSET_ENTRIES => {
+                        ints.insert(pos, i);
+                        return true;
+                    }
+                    Err(_) => {}
+                }
+            }
+            self.convert();
+        }
+        match self {
+            Set::Members(members, order) => {
+                order.insert(&member);
+                members.insert(member)
+            }
+            Set::Ints(_) => unreachable!(),
+        }
+    }
+
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        match self {
+            Set::Ints(ints) => match string_to_int(member).map(|i| ints.binary_search(&i)) {
+                Some(Ok(pos)) => {
+                    ints.remove(pos);
+                    true
+                }
+                _ => false,
+            },
+            Set::Members(members, order) => {
+                order.remove(member);
+                members.remove(member)
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Set::Ints(ints) => ints.len(),
+            Set::Members(members, _) => members.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn iter(&self) -> Box<dyn Iterator<Item = Bytes> + '_> {
+        match self {
+            Set::Ints(ints) => Box::new(ints.iter().map(|i| Bytes::from(i.to_string()))),
+            Set::Members(members, _) => Box::new(members.iter().cloned()),
+        }
+    }
+
+    // one SSCAN step, see `ScanOrder::step`; a set of integers is small enough to be ordered
+    // on the fly
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<Bytes>) {
+        let ints;
+        let order = match self {
+            Set::Ints(_) => {
+                ints = self.iter().collect::<Vec<_>>();
+                &ints.iter().collect()
+            }
+            Set::Members(_, order) => order,
+        };
+        let (next, members) = order.step(cursor, count);
+        (next, members.into_iter().cloned().collect())
+    }
+
+    // switch to the hash set representation
+    fn convert(&mut self) {
+        if let Set::Ints(ints) = self {
+            let members: HashSet<Bytes> = ints.iter().map(|i| Bytes::from(i.to_string())).collect();
+            let order = members.iter().collect();
+            *self = Set::Members(members, order);
+        }
+    }
+}
+
+// starts out compact and converts once a member does not fit
+impl FromIterator<Bytes> for Set {
+    fn from_iter<I: IntoIterator<Item = Bytes>>(iter: I) -> Self {
+        let mut set = Set::default();
+        for member in iter {
+            set.insert(member);
+        }
+        set
+    }
+}
@@ -1,13 +1,195 @@
 use std::{
-    collections::HashMap,
+    collections::{hash_map::RandomState, HashMap, VecDeque},
+    hash::{BuildHasher, Hasher},
+    sync::{Arc, Mutex},
     time::{SystemTime, UNIX_EPOCH},
 };
 
-pub type ValueType = (String, Option<u128>);
+use bytes::Bytes;
+
+use crate::{
+    hash::Hash, options::MaxmemoryPolicy, protocol::Protocol, set::Set, stream::Stream, zset::ZSet,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(Bytes),
+    List(VecDeque<Bytes>),
+    Set(Set),
+    ZSet(ZSet),
+    Hash(Hash),
+    Stream(Stream),
+}
+
+impl Value {
+    // the name reported by TYPE
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::List(_) => "list",
+            Value::Set(_) => "set",
+            Value::ZSet(_) => "zset",
+            Value::Hash(_) => "hash",
+            Value::Stream(_) => "stream",
+        }
+    }
+
+    // a list, set, sorted set or hash without elements, which no command leaves behind; an
+    // empty stream is valid
+    pub fn is_empty_collection(&self) -> bool {
+        match self {
+            Value::List(list) => list.is_empty(),
+            Value::Set(set) => set.is_empty(),
+            Value::ZSet(zset) => zset.is_empty(),
+            Value::Hash(hash) => hash.is_empty(),
+            Value::String(_) | Value::Stream(_) => false,
+        }
+    }
+}
+
+pub type ValueType = (Value, Option<u128>);
+
+// the DEL for every key and the HDEL for the hash fields a master has expired or evicted, as
+// (db, command), until they are propagated
+pub type ExpiredKeys = Arc<Mutex<Vec<(usize, Protocol)>>>;
+
+// the LFU counter of a new key, so it is not evicted before it had a chance to be read
+const LFU_INIT_VAL: u8 = 5;
+// how fast the LFU counter saturates, 10 takes about a million accesses to reach 255
+const LFU_LOG_FACTOR: u64 = 10;
+// the LFU counter loses one per minute without access
+const LFU_DECAY_MILLIS: u64 = 60_000;
+// elements of a collection looked at for its memory estimate, like MEMORY USAGE samples
+const SIZE_SAMPLES: usize = 5;
+// a Bytes handle and the heap block behind it
+const BYTES_OVERHEAD: usize = 48;
+
+struct Entry {
+    value: Value,
+    // absolute expire time in milli seconds
+    expire_at: Option<u128>,
+    // approximate bytes taken by the key and value, see `estimate`
+    size: usize,
+    // unix time in milliseconds of the last access, for LRU and the LFU decay
+    accessed: u64,
+    // logarithmic access counter for LFU
+    lfu: u8,
+}
+
+impl Entry {
+    fn new(k: &[u8], value: Value, expire_at: Option<u128>) -> Self {
+        Entry {
+            size: estimate(k, &value),
+            value,
+            expire_at,
+            accessed: now_in_millis() as u64,
+            lfu: LFU_INIT_VAL,
+        }
+    }
+
+    fn is_expired(&self, now: u128) -> bool {
+        self.expire_at.is_some_and(|t| now > t)
+    }
+
+    // the LFU counter after the decay for the time since the last access
+    fn lfu_decayed(&self, now: u64) -> u8 {
+        let periods = now.saturating_sub(self.accessed) / LFU_DECAY_MILLIS;
+        self.lfu.saturating_sub(periods.min(u8::MAX as u64) as u8)
+    }
+}
+
+// approximate bytes a key takes. The key is held by the keyspace and the sampling indexes, a
+// collection is sized from the average of its first few elements
+fn estimate(k: &[u8], v: &Value) -> usize {
+    fn scaled(len: usize, sizes: impl Iterator<Item = usize>) -> usize {
+        let (n, total) = sizes
+            .take(SIZE_SAMPLES)
+            .fold((0, 0), |(n, total), size| (n + 1, total + size));
+        (total * len).checked_div(n).unwrap_or(0)
+    }
+    let value = match v {
+        Value::String(s) => s.len() + BYTES_OVERHEAD,
+        Value::List(list) => scaled(list.len(), list.iter().map(|e| e.len() + BYTES_OVERHEAD)),
+        Value::Set(Set::Ints(ints)) => ints.len() * 8,
+        Value::Set(set) => scaled(set.len(), set.iter().map(|m| m.len() + BYTES_OVERHEAD + 8)),
+        // the score index entry plus the skiplist node
+        Value::ZSet(zset) => scaled(
+            zset.len(),
+            zset.iter().map(|(m, _)| m.len() + BYTES_OVERHEAD + 96),
+        ),
+        Value::Hash(hash) => scaled(
+            hash.len(),
+            hash.iter()
+                .map(|(f, v)| f.len() + v.len() + 2 * BYTES_OVERHEAD),
+        ),
+        Value::Stream(stream) => scaled(
+            stream.entries.len(),
+            stream.entries.values().map(|fields| {
+                fields
+                    .iter()
+                    .map(|(f, v)| f.len() + v.len() + 2 * BYTES_OVERHEAD)
+                    .sum::<usize>()
+                    + 48
+            }),
+        ),
+    };
+    std::mem::size_of::<Entry>() + 3 * (k.len() + 24) + value
+}
 
 pub struct Storage {
-    // key -> (value, (insert/update time, expire milli seconds))
-    set: HashMap<String, ValueType>,
+    // key -> value, expiration and access stats
+    set: HashMap<Bytes, Entry>,
+    // every key and the keys with a TTL, so the active expire cycle and eviction can sample them
+    all: KeySample,
+    volatile: KeySample,
+    // the sum of the entry sizes
+    used: usize,
+    // the key last handed out by `get_value_mut`, its size is estimated again on the next call
+    resized: Option<Bytes>,
+    // xorshift state for sampling
+    rng: u64,
+    // the index of this database
+    db: usize,
+    // where a master queues the keys it expires or evicts. None on a replica, which never
+    // deletes an expired key itself but hides it from clients until the DEL from its master arrives
+    expired: Option<ExpiredKeys>,
+    // set while the lock is held for the master's stream, which still sees keys past their TTL
+    pub from_master: bool,
+}
+
+// a set of keys that can hand out a random member in O(1), the keys live in a vec and
+// `pos` maps each one to its slot
+#[derive(Default)]
+struct KeySample {
+    keys: Vec<Bytes>,
+    pos: HashMap<Bytes, usize>,
+}
+
+impl KeySample {
+    fn insert(&mut self, k: &Bytes) {
+        if !self.pos.contains_key(k) {
+            self.pos.insert(k.clone(), self.keys.len());
+            self.keys.push(k.clone());
+        }
+    }
+
+    fn remove(&mut self, k: &[u8]) {
+        if let Some(i) = self.pos.remove(k) {
+            self.keys.swap_remove(i);
+            if let Some(moved) = self.keys.get(i) {
+                self.pos.insert(moved.clone(), i);
+            }
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    fn clear(&mut self) {
+        self.keys.clear();
+        self.pos.clear();
+    }
 }
 
 #[inline]
@@ -18,42 +200,338 @@ pub fn now_in_millis() -> u128 {
 }
 
 impl Storage {
-    pub fn new() -> Self {
+    pub fn new(db: usize, expired: Option<ExpiredKeys>) -> Self {
         Storage {
             set: HashMap::new(),
+            all: KeySample::default(),
+            volatile: KeySample::default(),
+            used: 0,
+            resized: None,
+            rng: RandomState::new().build_hasher().finish() | 1,
+            db,
+            expired,
+            from_master: false,
         }
     }
 
-    pub fn get(self: &mut Self, k: &str) -> Option<String> {
-        match self.set.get(k) {
-            Some((ss, expire_timestamp)) => match expire_timestamp {
-                Some(expire_time_stamp) => {
-                    if now_in_millis() > *expire_time_stamp {
-                        self.set.remove(k);
-                        None
-                    } else {
-                        Some(ss.clone())
-                    }
+    fn random(&mut self) -> u64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+
+    // bring the size of the value last handed out for writing up to date
+    fn refresh_size(&mut self) {
+        if let Some(k) = self.resized.take() {
+            if let Some(entry) = self.set.get_mut(&k) {
+                let size = estimate(&k, &entry.value);
+                self.used = self.used - entry.size + size;
+                entry.size = size;
+            }
+        }
+    }
+
+    // drop a key together with its entries in the sampling indexes
+    fn remove(&mut self, k: &[u8]) -> Option<ValueType> {
+        self.refresh_size();
+        self.all.remove(k);
+        self.volatile.remove(k);
+        let entry = self.set.remove(k)?;
+        self.used -= entry.size;
+        Some((entry.value, entry.expire_at))
+    }
+
+    // delete a key the server decided to drop, expired or evicted, and queue the DEL for the replicas
+    fn drop_key(&mut self, k: &[u8]) {
+        let Some((k, _)) = self.set.get_key_value(k) else {
+            return;
+        };
+        let k = k.clone();
+        self.remove(&k);
+        self.queue_expired(Protocol::Array(vec![
+            Protocol::bulk("DEL"),
+            Protocol::bulk(k),
+        ]));
+    }
+
+    fn queue_expired(&self, cmd: Protocol) {
+        if let Some(expired) = &self.expired {
+            expired.lock().unwrap().push((self.db, cmd));
+        }
+    }
+
+    // true if the key is live, expiring it otherwise, and count the access for LRU and LFU
+    fn lookup(&mut self, k: &[u8]) -> bool {
+        self.refresh_size();
+        let now = now_in_millis();
+        let Some(entry) = self.set.get_mut(k) else {
+            return false;
+        };
+        if entry.is_expired(now) {
+            match self.expired {
+                Some(_) => {
+                    self.drop_key(k);
+                    return false;
+                }
+                None if !self.from_master => return false,
+                None => {}
+            }
+        }
+        // a master expires hash fields lazily too, and the hash with them once none is left. A
+        // replica hides them from its clients until the master's HDEL arrives
+        if let Some(Entry {
+            value: Value::Hash(hash),
+            ..
+        }) = self.set.get_mut(k)
+        {
+            if self.expired.is_none() {
+                hash.hide_expired((!self.from_master).then_some(now));
+            } else {
+                let fields = hash.remove_expired(now);
+                if !fields.is_empty() && hash.is_empty() {
+                    self.drop_key(k);
+                    return false;
+                }
+                if !fields.is_empty() {
+                    let mut hdel = vec![Protocol::bulk("HDEL"), Protocol::bulk(k.to_vec())];
+                    hdel.extend(fields.into_iter().map(Protocol::BulkString));
+                    self.queue_expired(Protocol::Array(hdel));
+                    self.resized = self.set.get_key_value(k).map(|(k, _)| k.clone());
                 }
-                _ => Some(ss.clone()),
-            },
+            }
+        }
+        let r = self.random();
+        if let Some(entry) = self.set.get_mut(k) {
+            let now = now as u64;
+            // the counter grows less likely the higher it is already
+            let counter = entry.lfu_decayed(now);
+            let base = counter.saturating_sub(LFU_INIT_VAL) as u64;
+            entry.lfu = if counter < u8::MAX && r.is_multiple_of(base * LFU_LOG_FACTOR + 1) {
+                counter + 1
+            } else {
+                counter
+            };
+            entry.accessed = now;
+        }
+        true
+    }
+
+    // any value type, dropping the key first if it has expired; a replica only hides it
+    pub fn get_value(&mut self, k: &[u8]) -> Option<&Value> {
+        if !self.lookup(k) {
+            return None;
+        }
+        self.set.get(k).map(|entry| &entry.value)
+    }
+
+    pub fn get_value_mut(&mut self, k: &[u8]) -> Option<&mut Value> {
+        if !self.lookup(k) {
+            return None;
+        }
+        self.resized = self.set.get_key_value(k).map(|(k, _)| k.clone());
+        self.set.get_mut(k).map(|entry| &mut entry.value)
+    }
+
+    // string values only, other types read as missing
+    pub fn get(&mut self, k: &[u8]) -> Option<Bytes> {
+        match self.get_value(k) {
+            Some(Value::String(s)) => Some(s.clone()),
             _ => None,
         }
     }
 
-    pub fn set(self: &mut Self, k: String, v: String) {
-        self.set.insert(k, (v, None));
+    pub fn set(&mut self, k: Bytes, v: Bytes) {
+        self.insert(k, Value::String(v), None);
+    }
+
+    // insert a value of any type with an optional absolute unix timestamp in milliseconds as the expiration
+    pub fn insert(&mut self, k: Bytes, v: Value, expire_at: Option<u128>) {
+        self.refresh_size();
+        self.all.insert(&k);
+        match expire_at {
+            Some(_) => self.volatile.insert(&k),
+            None => self.volatile.remove(&k),
+        }
+        let entry = Entry::new(&k, v, expire_at);
+        self.used += entry.size;
+        if let Some(old) = self.set.insert(k, entry) {
+            self.used -= old.size;
+        }
+    }
+
+    // the expiration of a live key, None if the key is missing and Some(None) if it has no TTL
+    pub fn expire_at(&mut self, k: &[u8]) -> Option<Option<u128>> {
+        self.get_value(k)?;
+        self.set.get(k).map(|entry| entry.expire_at)
+    }
+
+    // set or clear the expiration of a live key, false if the key is missing
+    pub fn set_expire(&mut self, k: &[u8], expire_at: Option<u128>) -> bool {
+        if self.get_value(k).is_none() {
+            return false;
+        }
+        let Some((k, _)) = self.set.get_key_value(k) else {
+            return false;
+        };
+        let k = k.clone();
+        if let Some(entry) = self.set.get_mut(&k) {
+            entry.expire_at = expire_at;
+        }
+        match expire_at {
+            Some(_) => self.volatile.insert(&k),
+            None => self.volatile.remove(&k),
+        }
+        true
+    }
+
+    pub fn del(&mut self, k: &[u8]) -> bool {
+        self.remove(k).is_some()
+    }
+
+    // remove a live key and hand back its value and expiration
+    pub fn take(&mut self, k: &[u8]) -> Option<ValueType> {
+        self.get_value(k)?;
+        self.remove(k)
+    }
+
+    // one round of active expiry: look at up to `count` random keys with a TTL and expire the
+    // ones past it. Returns how many keys were looked at and how many expired
+    pub fn expire_sample(&mut self, count: usize) -> (usize, usize) {
+        let now = now_in_millis();
+        let sampled = count.min(self.volatile.len());
+        let mut expired = 0;
+        for _ in 0..sampled {
+            if self.volatile.keys.is_empty() {
+                break;
+            }
+            let i = (self.random() % self.volatile.len() as u64) as usize;
+            let k = &self.volatile.keys[i];
+            if self.set.get(k).is_some_and(|entry| entry.is_expired(now)) {
+                let k = k.clone();
+                self.drop_key(&k);
+                expired += 1;
+            }
+        }
+        (sampled, expired)
+    }
+
+    // approximate bytes taken by the keys of this database
+    pub fn used_memory(&mut self) -> usize {
+        self.refresh_size();
+        self.used
+    }
+
+    // look at up to `count` random keys the policy may evict and pick the best one to go, with a
+    // score to weigh it against the picks of other databases: the higher, the sooner it goes
+    pub fn eviction_candidate(
+        &mut self,
+        policy: MaxmemoryPolicy,
+        count: usize,
+    ) -> Option<(Bytes, u64)> {
+        let volatile = policy.is_volatile();
+        let len = if volatile {
+            self.volatile.len()
+        } else {
+            self.all.len()
+        };
+        if len == 0 || policy == MaxmemoryPolicy::NoEviction {
+            return None;
+        }
+        let now = now_in_millis() as u64;
+        let picks = (0..count).map(|_| self.random()).collect::<Vec<_>>();
+        let pool = if volatile { &self.volatile } else { &self.all };
+        let mut best: Option<(&Bytes, u64)> = None;
+        for r in picks {
+            let k = &pool.keys[(r % len as u64) as usize];
+            let entry = &self.set[k];
+            let score = match policy {
+                MaxmemoryPolicy::AllkeysLru | MaxmemoryPolicy::VolatileLru => {
+                    now.saturating_sub(entry.accessed)
+                }
+                MaxmemoryPolicy::AllkeysLfu | MaxmemoryPolicy::VolatileLfu => {
+                    (u8::MAX - entry.lfu_decayed(now)) as u64
+                }
+                // the closer the expiration, the higher
+                MaxmemoryPolicy::VolatileTtl => {
+                    u64::MAX - entry.expire_at.unwrap_or_default().min(u64::MAX as u128) as u64
+                }
+                MaxmemoryPolicy::AllkeysRandom
+                | MaxmemoryPolicy::VolatileRandom
+                | MaxmemoryPolicy::NoEviction => r,
+            };
+            if best.is_none_or(|(_, s)| score > s) {
+                best = Some((k, score));
+            }
+        }
+        best.map(|(k, score)| (k.clone(), score))
+    }
+
+    // remove a key to make room under maxmemory, queueing the DEL for the replicas, and return
+    // the memory it freed, 0 if the key is gone already
+    pub fn evict(&mut self, k: &[u8]) -> usize {
+        if !self.set.contains_key(k) {
+            return 0;
+        }
+        let before = self.used_memory();
+        self.drop_key(k);
+        before - self.used
+    }
+
+    // number of keys, including expired ones not reclaimed yet
+    pub fn len(&self) -> usize {
+        self.set.len()
     }
 
-    pub fn setx(self: &mut Self, k: String, v: String, expire_ms: u128) {
-        self.set.insert(k, (v, Some(expire_ms + now_in_millis())));
+    pub fn expires_len(&self) -> usize {
+        self.volatile.len()
     }
 
-    pub fn del(self: &mut Self, k: String) {
-        self.set.remove(&k);
+    // exchange the keys of two databases, as SWAPDB does
+    pub fn swap(&mut self, other: &mut Storage) {
+        self.refresh_size();
+        other.refresh_size();
+        std::mem::swap(&mut self.set, &mut other.set);
+        std::mem::swap(&mut self.all, &mut other.all);
+        std::mem::swap(&mut self.volatile, &mut other.volatile);
+        std::mem::swap(&mut self.used, &mut other.used);
     }
 
-    pub fn keys(self: &Self) -> Vec<String> {
-        self.set.keys().map(|x| x.clone()).collect()
+    pub fn clear(&mut self) {
+        self.set.clear();
+        self.all.clear();
+        self.volatile.clear();
+        self.used = 0;
+        self.resized = None;
+    }
+
+    // the live keys, leaving out the expired ones not reclaimed yet
+    pub fn keys(&self) -> Vec<Bytes> {
+        let now = now_in_millis();
+        self.set
+            .iter()
+            .filter(|(_, entry)| !entry.is_expired(now))
+            .map(|(k, _)| k.clone())
+            .collect()
+    }
+
+    // copy of every live key, taken under the lock so it can be persisted without holding it
+    pub fn snapshot(&self) -> Vec<(Bytes, ValueType)> {
+        let now = now_in_millis();
+        self.set
+            .iter()
+            .filter(|(_, entry)| !entry.is_expired(now))
+            .filter_map(|(k, entry)| {
+                let mut v = entry.value.clone();
+                if let Value::Hash(hash) = &mut v {
+                    hash.remove_expired(now);
+                    hash.hide_expired(None);
+                    if hash.is_empty() {
+                        return None;
+                    }
+                }
+                Some((k.clone(), (v, entry.expire_at)))
+            })
+            .collect()
     }
 }
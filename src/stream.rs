@@ -0,0 +1,98 @@
+// the stream value type: entries ordered by ID plus the consumer group state RDB carries along
+
+use std::{collections::BTreeMap, fmt};
+
+use bytes::Bytes;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Hash)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MAX: StreamId = StreamId {
+        ms: u64::MAX,
+        seq: u64::MAX,
+    };
+
+    pub fn new(ms: u64, seq: u64) -> Self {
+        StreamId { ms, seq }
+    }
+
+    // "<ms>-<seq>", or just "<ms>" in which case the sequence is `missing_seq`
+    pub fn parse(s: &str, missing_seq: u64) -> Option<Self> {
+        match s.split_once('-') {
+            Some((ms, seq)) => Some(StreamId::new(ms.parse().ok()?, seq.parse().ok()?)),
+            None => Some(StreamId::new(s.parse().ok()?, missing_seq)),
+        }
+    }
+
+    // big-endian so the byte order matches the ID order, the form used as RDB node keys
+    pub fn to_be_bytes(self) -> [u8; 16] {
+        let mut b = [0; 16];
+        b[..8].copy_from_slice(&self.ms.to_be_bytes());
+        b[8..].copy_from_slice(&self.seq.to_be_bytes());
+        b
+    }
+
+    pub fn from_be_bytes(b: &[u8]) -> Option<Self> {
+        if b.len() != 16 {
+            return None;
+        }
+        Some(StreamId::new(
+            u64::from_be_bytes(b[..8].try_into().unwrap()),
+            u64::from_be_bytes(b[8..].try_into().unwrap()),
+        ))
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Stream {
+    pub entries: BTreeMap<StreamId, Vec<(Bytes, Bytes)>>,
+    // the top ID ever added, which can be past the last entry once entries are deleted
+    pub last_id: StreamId,
+    pub max_deleted_id: StreamId,
+    // every entry ever added, including deleted ones
+    pub entries_added: u64,
+    pub groups: Vec<ConsumerGroup>,
+}
+
+impl Stream {
+    pub fn first_id(&self) -> StreamId {
+        self.entries.keys().next().copied().unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConsumerGroup {
+    pub name: Bytes,
+    pub last_id: StreamId,
+    // -1 when unknown
+    pub entries_read: i64,
+    // delivered but not yet acknowledged entries
+    pub pending: BTreeMap<StreamId, PendingEntry>,
+    pub consumers: Vec<Consumer>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingEntry {
+    pub consumer: Bytes,
+    // unix time in milliseconds of the last delivery
+    pub delivery_time: u64,
+    pub delivery_count: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Consumer {
+    pub name: Bytes,
+    // unix times in milliseconds of the last interaction and the last successful read
+    pub seen_time: u64,
+    pub active_time: u64,
+}
@@ -0,0 +1,72 @@
+// ziplist, the compact list encoding of RDB files written before Redis 7:
+// <zlbytes u32><zltail u32><zllen u16><entry>...<0xFF>
+//
+// Each entry is <prevlen><encoding><data>, prevlen is one byte, or 0xFE followed by a u32 when
+// the previous entry is 254 bytes or longer. The encoding byte is one of:
+// - 00pppppp: a string of up to 63 bytes
+// - 01pppppp qqqqqqqq: a string of up to 16383 bytes, length in big-endian
+// - 10000000 + u32 big-endian: a longer string
+// - 11000000 / 11010000 / 11100000: an i16 / i32 / i64
+// - 11110000: a 24 bit signed integer
+// - 11111110: an i8
+// - 1111xxxx: xxxx - 1 is the value itself, from 0 to 12
+
+use bytes::Bytes;
+
+use crate::error::DBError;
+
+const HEADER_SIZE: usize = 10;
+const END: u8 = 0xFF;
+
+pub fn decode(buf: &[u8]) -> Result<Vec<Bytes>, DBError> {
+    let corrupt = || DBError("invalid ziplist".to_string());
+    let mut entries = Vec::new();
+    let mut p = HEADER_SIZE;
+    loop {
+        let prevlen = *buf.get(p).ok_or_else(corrupt)?;
+        if prevlen == END {
+            break;
+        }
+        p += if prevlen == 0xFE { 5 } else { 1 };
+
+        let enc = *buf.get(p).ok_or_else(corrupt)?;
+        p += 1;
+        let mut take = |n: usize| -> Result<&[u8], DBError> {
+            let s = buf.get(p..p + n).ok_or_else(corrupt)?;
+            p += n;
+            Ok(s)
+        };
+        let entry = match enc >> 6 {
+            0b00 => {
+                let len = (enc & 0x3F) as usize;
+                Bytes::copy_from_slice(take(len)?)
+            }
+            0b01 => {
+                let len = ((enc & 0x3F) as usize) << 8 | take(1)?[0] as usize;
+                Bytes::copy_from_slice(take(len)?)
+            }
+            0b10 => {
+                let len = u32::from_be_bytes(take(4)?.try_into().unwrap()) as usize;
+                Bytes::copy_from_slice(take(len)?)
+            }
+            _ => {
+                let v = match enc {
+                    0xC0 => i16::from_le_bytes(take(2)?.try_into().unwrap()) as i64,
+                    0xD0 => i32::from_le_bytes(take(4)?.try_into().unwrap()) as i64,
+                    0xE0 => i64::from_le_bytes(take(8)?.try_into().unwrap()),
+                    0xF0 => {
+                        let b = take(3)?;
+                        // shift into the top of an i32 so the sign extends on the way back
+                        (i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8) as i64
+                    }
+                    0xFE => take(1)?[0] as i8 as i64,
+                    0xF1..=0xFD => (enc & 0x0F) as i64 - 1,
+                    _ => return Err(corrupt()),
+                };
+                Bytes::from(v.to_string())
+            }
+        };
+        entries.push(entry);
+    }
+    Ok(entries)
+}
@@ -0,0 +1,81 @@
+// zipmap, the small hash encoding of RDB files written before Redis 2.6:
+// <zmlen><len>key<len><free>value...<0xFF>
+//
+// A length is one byte when below 254, or 254 followed by a little-endian u32. `free` counts
+// unused bytes left after the value by an earlier update, they are skipped.
+
+use bytes::Bytes;
+
+use crate::error::DBError;
+
+const BIG_LEN: u8 = 254;
+const END: u8 = 0xFF;
+
+pub fn decode(buf: &[u8]) -> Result<Vec<(Bytes, Bytes)>, DBError> {
+    let corrupt = || DBError("invalid zipmap".to_string());
+    let mut pairs = Vec::new();
+    // the first byte is the number of pairs, but it saturates at 254 so walk to the end marker
+    let mut p = 1;
+    let read_len = |p: &mut usize| -> Result<Option<usize>, DBError> {
+        let b = *buf.get(*p).ok_or_else(corrupt)?;
+        *p += 1;
+        match b {
+            END => Ok(None),
+            BIG_LEN => {
+                let len = buf.get(*p..*p + 4).ok_or_else(corrupt)?;
+                *p += 4;
+                Ok(Some(u32::from_le_bytes(len.try_into().unwrap()) as usize))
+            }
+            len => Ok(Some(len as usize)),
+        }
+    };
+    while let Some(klen) = read_len(&mut p)? {
+        let key = buf.get(p..p + klen).ok_or_else(corrupt)?;
+        p += klen;
+        let vlen = read_len(&mut p)?.ok_or_else(corrupt)?;
+        let free = *buf.get(p).ok_or_else(corrupt)? as usize;
+        p += 1;
+        let value = buf.get(p..p + vlen).ok_or_else(corrupt)?;
+        p += vlen + free;
+        pairs.push((Bytes::copy_from_slice(key), Bytes::copy_from_slice(value)));
+    }
+    Ok(pairs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode_len(out: &mut Vec<u8>, len: usize) {
+        if len < BIG_LEN as usize {
+            out.push(len as u8);
+        } else {
+            out.push(BIG_LEN);
+            out.extend_from_slice(&(len as u32).to_le_bytes());
+        }
+    }
+
+    fn encode(pairs: &[(Bytes, Bytes)]) -> Vec<u8> {
+        let mut out = vec![pairs.len().min(254) as u8];
+        for (k, v) in pairs {
+            encode_len(&mut out, k.len());
+            out.extend_from_slice(k);
+            encode_len(&mut out, v.len());
+            out.push(0);
+            out.extend_from_slice(v);
+        }
+        out.push(END);
+        out
+    }
+
+    #[test]
+    fn round_trip_around_big_len() {
+        for len in [0, 1, 253, 254, 255, 1000] {
+            let pairs = vec![
+                (Bytes::from(vec![b'k'; len]), Bytes::from(vec![b'v'; len])),
+                (Bytes::from_static(b"f"), Bytes::from(vec![b'x'; len])),
+            ];
+            assert_eq!(decode(&encode(&pairs)).unwrap(), pairs, "len {}", len);
+        }
+    }
+}
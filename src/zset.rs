@@ -0,0 +1,364 @@
+// the sorted set value type: a member -> score index plus a skiplist ordered by (score, member).
+// Like Redis' zskiplist every link records how many elements it skips, so ranks are found in
+// O(log n) on the way down. Nodes live in an arena and link to each other by index.
+
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    hash::{BuildHasher, Hasher},
+};
+
+use bytes::Bytes;
+
+const MAX_LEVEL: usize = 32;
+// the head node, which holds no element
+const HEAD: usize = 0;
+const NIL: usize = usize::MAX;
+
+#[derive(Debug, Clone, Copy)]
+struct Link {
+    to: usize,
+    // the number of elements this link moves forward by
+    span: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    member: Bytes,
+    score: f64,
+    prev: usize,
+    links: Vec<Link>,
+}
+
+#[derive(Debug, Clone)]
+struct SkipList {
+    nodes: Vec<Node>,
+    // arena slots of removed nodes, reused by later inserts
+    free: Vec<usize>,
+    level: usize,
+    len: usize,
+    // xorshift state for the node levels
+    rng: u64,
+}
+
+// the order of the skiplist: by score, then by member
+fn before(score: f64, member: &[u8], other_score: f64, other_member: &[u8]) -> bool {
+    score < other_score || (score == other_score && member < other_member)
+}
+
+impl Default for SkipList {
+    fn default() -> Self {
+        let head = Node {
+            member: Bytes::new(),
+            score: 0.0,
+            prev: NIL,
+            links: vec![Link { to: NIL, span: 0 }; MAX_LEVEL],
+        };
+        SkipList {
+            nodes: vec![head],
+            free: Vec::new(),
+            level: 1,
+            len: 0,
+            rng: RandomState::new().build_hasher().finish() | 1,
+        }
+    }
+}
+
+impl SkipList {
+    // each level is kept with probability 1/4, as in Redis
+    fn random_level(&mut self) -> usize {
+        let mut level = 1;
+        while level < MAX_LEVEL {
+            self.rng ^= self.rng << 13;
+            self.rng ^= self.rng >> 7;
+            self.rng ^= self.rng << 17;
+            if self.rng & 3 != 0 {
+                break;
+            }
+            level += 1;
+        }
+        level
+    }
+
+    // the last node before (score, member) on every level, and the rank of each of those nodes
+    fn find_update(&self, score: f64, member: &[u8]) -> ([usize; MAX_LEVEL], [usize; MAX_LEVEL]) {
+        let mut update = [HEAD; MAX_LEVEL];
+        let mut rank = [0; MAX_LEVEL];
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            rank[i] = if i + 1 == self.level { 0 } else { rank[i + 1] };
+            loop {
+                let link = self.nodes[x].links[i];
+                if link.to == NIL {
+                    break;
+                }
+                let next = &self.nodes[link.to];
+                if !before(next.score, &next.member, score, member) {
+                    break;
+                }
+                rank[i] += link.span;
+                x = link.to;
+            }
+            update[i] = x;
+        }
+        (update, rank)
+    }
+
+    // the member must not be in the list yet
+    fn insert(&mut self, score: f64, member: Bytes) {
+        let (mut update, mut rank) = self.find_update(score, &member);
+        let level = self.random_level();
+        if level > self.level {
+            for i in self.level..level {
+                rank[i] = 0;
+                update[i] = HEAD;
+                self.nodes[HEAD].links[i].span = self.len;
+            }
+            self.level = level;
+        }
+        let node = Node {
+            member,
+            score,
+            prev: if update[0] == HEAD { NIL } else { update[0] },
+            links: vec![Link { to: NIL, span: 0 }; level],
+        };
+        let id = match self.free.pop() {
+            Some(id) => {
+                self.nodes[id] = node;
+                id
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        };
+        for i in 0..level {
+            let link = self.nodes[update[i]].links[i];
+            self.nodes[id].links[i] = Link {
+                to: link.to,
+                span: link.span - (rank[0] - rank[i]),
+            };
+            self.nodes[update[i]].links[i] = Link {
+                to: id,
+                span: rank[0] - rank[i] + 1,
+            };
+        }
+        for (i, u) in update.iter().enumerate().take(self.level).skip(level) {
+            self.nodes[*u].links[i].span += 1;
+        }
+        let next = self.nodes[id].links[0].to;
+        if next != NIL {
+            self.nodes[next].prev = id;
+        }
+        self.len += 1;
+    }
+
+    // the element must be in the list
+    fn remove(&mut self, score: f64, member: &[u8]) {
+        let (update, _) = self.find_update(score, member);
+        let id = self.nodes[update[0]].links[0].to;
+        for (i, u) in update.iter().enumerate().take(self.level) {
+            let link = self.nodes[*u].links[i];
+            if link.to == id {
+                let removed = self.nodes[id].links[i];
+                self.nodes[*u].links[i] = Link {
+                    to: removed.to,
+                    span: link.span + removed.span - 1,
+                };
+            } else {
+                self.nodes[*u].links[i].span -= 1;
+            }
+        }
+        let next = self.nodes[id].links[0].to;
+        if next != NIL {
+            self.nodes[next].prev = self.nodes[id].prev;
+        }
+        while self.level > 1 && self.nodes[HEAD].links[self.level - 1].to == NIL {
+            self.level -= 1;
+        }
+        self.nodes[id].member = Bytes::new();
+        self.free.push(id);
+        self.len -= 1;
+    }
+
+    // the number of elements `is_before` holds for, which must be the elements at the start
+    fn count_while(&self, is_before: impl Fn(f64, &[u8]) -> bool) -> usize {
+        let mut rank = 0;
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            loop {
+                let link = self.nodes[x].links[i];
+                if link.to == NIL {
+                    break;
+                }
+                let next = &self.nodes[link.to];
+                if !is_before(next.score, &next.member) {
+                    break;
+                }
+                rank += link.span;
+                x = link.to;
+            }
+        }
+        rank
+    }
+
+    // the node at the 0-based `rank`, which must be below the length
+    fn node_at(&self, rank: usize) -> usize {
+        let mut traversed = 0;
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            loop {
+                let link = self.nodes[x].links[i];
+                if link.to == NIL || traversed + link.span > rank + 1 {
+                    break;
+                }
+                traversed += link.span;
+                x = link.to;
+            }
+            if traversed == rank + 1 {
+                break;
+            }
+        }
+        x
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ZSet {
+    scores: HashMap<Bytes, f64>,
+    list: SkipList,
+}
+
+// equal when they hold the same members with the same scores, whatever the skiplist's shape
+impl PartialEq for ZSet {
+    fn eq(&self, other: &Self) -> bool {
+        self.scores == other.scores
+    }
+}
+
+impl ZSet {
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    // add a member or change its score, true if the member is new
+    pub fn insert(&mut self, member: Bytes, score: f64) -> bool {
+        match self.scores.insert(member.clone(), score) {
+            Some(old) if old == score => false,
+            Some(old) => {
+                self.list.remove(old, &member);
+                self.list.insert(score, member);
+                false
+            }
+            None => {
+                self.list.insert(score, member);
+                true
+            }
+        }
+    }
+
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        match self.scores.remove(member) {
+            Some(score) => {
+                self.list.remove(score, member);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // the 0-based position of a member in ascending order
+    pub fn rank(&self, member: &[u8]) -> Option<usize> {
+        let score = self.score(member)?;
+        Some(
+            self.list
+                .count_while(|s, m| before(s, m, score, member) || (s == score && m == member))
+                - 1,
+        )
+    }
+
+    // the number of elements, from the lowest, that `is_before` holds for. It must hold for a
+    // prefix of the set, like all the elements below a score
+    pub fn count_while(&self, is_before: impl Fn(f64, &[u8]) -> bool) -> usize {
+        self.list.count_while(is_before)
+    }
+
+    // the elements with 0-based ranks in `start..end`, lowest first; reverse it for highest first
+    pub fn range(&self, start: usize, end: usize) -> Iter<'_> {
+        let end = end.min(self.len());
+        if start >= end {
+            return Iter {
+                list: &self.list,
+                front: NIL,
+                back: NIL,
+                remaining: 0,
+            };
+        }
+        Iter {
+            list: &self.list,
+            front: self.list.node_at(start),
+            back: self.list.node_at(end - 1),
+            remaining: end - start,
+        }
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        self.range(0, self.len())
+    }
+}
+
+pub struct Iter<'a> {
+    list: &'a SkipList,
+    front: usize,
+    back: usize,
+    remaining: usize,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a Bytes, f64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let node = &self.list.nodes[self.front];
+        self.front = node.links[0].to;
+        Some((&node.member, node.score))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl DoubleEndedIterator for Iter<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let node = &self.list.nodes[self.back];
+        self.back = node.prev;
+        Some((&node.member, node.score))
+    }
+}
+
+impl ExactSizeIterator for Iter<'_> {}
+
+impl FromIterator<(Bytes, f64)> for ZSet {
+    fn from_iter<I: IntoIterator<Item = (Bytes, f64)>>(iter: I) -> Self {
+        let mut zset = ZSet::default();
+        for (member, score) in iter {
+            zset.insert(member, score);
+        }
+        zset
+    }
+}